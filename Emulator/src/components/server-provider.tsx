@@ -329,6 +329,11 @@ const uuidv4 = (): string => {
 
 const CLIENT_UUID = uuidv4();
 
+// The same tie-break as the phones: when both ends join at once, only the lower
+// id offers and the higher one answers, or each would ignore the other's offer.
+// Lowercase UUID strings sort the same way as their bytes
+const initiates = (peer: string): boolean => CLIENT_UUID < peer.toLowerCase();
+
 export class WebRTCAudioClient {
 	stream?: MediaStream;
 	peerConnections: { [key: string]: RTCPeerConnection } = {};
@@ -429,6 +434,30 @@ export class WebRTCAudioClient {
 		});
 	};
 
+	sendOffer = (target: string) => {
+		const newPeerConnection = new RTCPeerConnection(
+			WEBRTC_PEER_CONNECTION_CONFIGURATION
+		);
+
+		this.registerPeerConnection(newPeerConnection, target);
+
+		(async () => {
+			const offer = await newPeerConnection.createOffer({
+				offerToReceiveAudio: true,
+				offerToReceiveVideo: false,
+			});
+
+			await newPeerConnection.setLocalDescription(offer);
+
+			this.webRTCSignalingSocketTransmit({
+				type: "ICEOffer",
+				offer,
+				from: CLIENT_UUID,
+				to: target,
+			});
+		})();
+	};
+
 	webRTCSignalingSocketTransmit = (message: WebRTCSignalingMessage) => {
 		console.log(
 			`%c WebRTC Signaling Socket Transmit: ${message.type}`,
@@ -452,39 +481,26 @@ export class WebRTCAudioClient {
 						type: "JoinAck",
 						from: CLIENT_UUID,
 					});
+
+					if (
+						initiates(message.from) &&
+						!this.peerConnections[message.from]
+					) {
+						console.log(`New Join WebRTC Client: ${message.from}`);
+
+						this.sendOffer(message.from);
+					}
 				}
 				break;
 			case "JoinAck":
 				if (
 					message.from != CLIENT_UUID &&
+					initiates(message.from) &&
 					!this.peerConnections[message.from]
 				) {
 					console.log(`New JoinAck WebRTC Client: ${message.from}`);
 
-					const newPeerConnection = new RTCPeerConnection(
-						WEBRTC_PEER_CONNECTION_CONFIGURATION
-					);
-
-					this.registerPeerConnection(
-						newPeerConnection,
-						message.from
-					);
-
-					(async () => {
-						const offer = await newPeerConnection.createOffer({
-							offerToReceiveAudio: true,
-							offerToReceiveVideo: false,
-						});
-
-						await newPeerConnection.setLocalDescription(offer);
-
-						this.webRTCSignalingSocketTransmit({
-							type: "ICEOffer",
-							offer,
-							from: CLIENT_UUID,
-							to: message.from,
-						});
-					})();
+					this.sendOffer(message.from);
 				}
 
 				break;
@@ -0,0 +1,58 @@
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+#[cfg(test)]
+use std::sync::Mutex;
+
+// Where timer-driven logic (hook settling, ring limiting, dial pulses) gets the time from, so
+// it can be stepped by hand instead of by sleeping
+pub trait Clock {
+    fn now(&self) -> Instant;
+
+    fn elapsed_since(&self, earlier: Instant) -> Duration {
+        self.now().saturating_duration_since(earlier)
+    }
+}
+
+pub type SharedClock = Arc<dyn Clock + Send + Sync>;
+
+// Plain Instant::now, what everything runs on outside of tests
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+pub fn system() -> SharedClock {
+    Arc::new(SystemClock)
+}
+
+// Only moves when advanced
+#[cfg(test)]
+pub struct ManualClock {
+    now: Mutex<Instant>,
+}
+
+#[cfg(test)]
+impl ManualClock {
+    pub fn new() -> Arc<ManualClock> {
+        Arc::new(ManualClock {
+            now: Mutex::new(Instant::now()),
+        })
+    }
+
+    pub fn advance(&self, by: Duration) {
+        *self.now.lock().unwrap() += by;
+    }
+}
+
+#[cfg(test)]
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        *self.now.lock().unwrap()
+    }
+}
@@ -1,9 +1,905 @@
-pub const HOOK_SWITCH_PIN: u8 = 17;
+use std::{
+    collections::HashMap, env, fmt::Display, fs, process, str::FromStr, sync::OnceLock,
+    time::Duration,
+};
 
-pub const DIAL_LATCH_PIN: u8 = 22;
-pub const DIAL_PULSE_PIN: u8 = 27;
+use serde::{Deserialize, Deserializer};
 
-pub const BELL_SOLENOID_FORWARD_PIN: u8 = 24;
-pub const BELL_SOLENOID_REVERSE_PIN: u8 = 23;
+use crate::{
+    hardware::{
+        audio::FrameStrategy,
+        dial::{self, PulseMapping},
+    },
+    network, PhoneSide,
+};
 
+// Fixed, Opus and the frame length math depend on it
 pub const SAMPLE_RATE: u32 = 48000;
+
+const _: () = assert!(
+    matches!(SAMPLE_RATE, 8000 | 12000 | 16000 | 24000 | 48000),
+    "Opus doesn't support SAMPLE_RATE"
+);
+
+// Exact sample count of an Opus frame given in tenths of a millisecond (25 for 2.5ms). The
+// FRAME_LENGTH constants are derived with float math and checked against this at compile time,
+// so a different SAMPLE_RATE can't silently produce a length Opus rejects
+pub const fn opus_frame_length(tenths_of_millisecond: usize) -> usize {
+    assert!(
+        matches!(tenths_of_millisecond, 25 | 50 | 100 | 200 | 400 | 600),
+        "not an Opus frame duration"
+    );
+    assert!(
+        (SAMPLE_RATE as usize * tenths_of_millisecond).is_multiple_of(10000),
+        "frame isn't a whole number of samples at SAMPLE_RATE"
+    );
+
+    SAMPLE_RATE as usize * tenths_of_millisecond / 10000
+}
+
+const DEFAULT_CONFIG_PATH: &str = "phone-bell.toml";
+
+static CONFIG: OnceLock<Config> = OnceLock::new();
+
+#[derive(Deserialize, Debug, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum DialMapping {
+    Nanp,
+    Sweden,
+    NewZealand,
+}
+
+impl DialMapping {
+    pub fn pulse_mapping(self) -> PulseMapping {
+        match self {
+            DialMapping::Nanp => dial::nanp,
+            DialMapping::Sweden => dial::sweden,
+            DialMapping::NewZealand => dial::new_zealand,
+        }
+    }
+}
+
+// webrtc's default interceptors, each can be left out to save a little CPU on a quiet LAN. Loss
+// and stall detection don't depend on any of them, they count RTP themselves
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum WebRtcInterceptor {
+    // Retransmission requests. Only negotiated for video, so audio calls never use it
+    Nack,
+    // RTCP sender and receiver reports, which give the peer loss, jitter and round trip figures
+    RtcpReports,
+    // Transport-wide congestion control feedback, only for bandwidth estimation
+    Twcc,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum ShortCodeAction {
+    // Dial the server's door opener
+    DoorOpen,
+    // Dial intercom_number
+    Intercom,
+    // Send the code to the server as dialed
+    DialThrough,
+    Ignore,
+}
+
+// Call audio tuning for a room, e.g. turned down somewhere quiet or up with the rumble cut in a
+// hallway. Selected by the server with PhoneIncomingMessage::AudioProfile
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct AudioProfile {
+    // Linear, 1.0 leaves call audio as received
+    pub gain: f32,
+    // Overrides limiter_threshold while this profile is active
+    pub limiter_threshold: Option<f32>,
+    // Cuts low frequency rumble, which leaves more headroom for the voice before the limiter
+    pub high_pass: bool,
+}
+
+impl Default for AudioProfile {
+    fn default() -> Self {
+        AudioProfile {
+            gain: 1.0,
+            limiter_threshold: None,
+            high_pass: false,
+        }
+    }
+}
+
+// Durations are written in milliseconds in the config file
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    // Hardware bring-up without any networking, see test_mode_entry
+    pub test_mode: bool,
+    #[serde(deserialize_with = "millis")]
+    pub test_mode_ring_interval: Duration,
+
+    pub side: Option<PhoneSide>,
+    pub api_key: Option<String>,
+    // This phone's number, sent to the server on connect so it can tell more than two phones
+    // apart. Also the caller id when caller_id isn't set
+    pub extension: Option<String>,
+    pub caller_id: Option<String>,
+    // Intercom mode: picking up dials this number straight away, and dialing is disabled
+    pub intercom_number: Option<String>,
+    // Answer incoming calls without the handset being lifted, audio plays from the earpiece
+    pub intercom_auto_answer: bool,
+    pub id_path: String,
+
+    // Off to skip the GPIO and drive the phone from stdin, see hardware::console. It's also
+    // used when the GPIO can't be opened
+    pub gpio_enabled: bool,
+    pub hook_switch_pin: u8,
+    pub dial_latch_pin: u8,
+    pub dial_pulse_pin: u8,
+    pub dial_mapping: DialMapping,
+    // A latch held longer than this is treated as stuck and its pulses are thrown away. A full
+    // turn of the dial takes about a second
+    #[serde(deserialize_with = "millis")]
+    pub dial_latch_max_duration: Duration,
+    pub bell_solenoid_forward_pin: u8,
+    pub bell_solenoid_reverse_pin: u8,
+    // Shortest time between the bell being switched off and on again
+    #[serde(deserialize_with = "millis")]
+    pub ring_min_interval: Duration,
+    // Alternating on and off times the bell rings in, see hardware::cadence. Empty rings
+    // continuously and leaves the cadence to the server
+    #[serde(deserialize_with = "millis_list")]
+    pub ring_cadence: Vec<Duration>,
+    pub ptt_button_pin: u8,
+    // Driven high while the mic is going out to a peer, for an LED on public phones
+    pub privacy_light_pin: Option<u8>,
+    pub ptt_enabled: bool,
+
+    // Read the hook, dial and PTT from an input device instead, e.g. a USB telephony interface.
+    // Needs the evdev feature, see hardware::evdev. Key codes are from linux/input-event-codes.h
+    pub evdev_device: Option<String>,
+    // Held down means off-hook, unless inverted
+    pub evdev_hook_key: u16,
+    pub evdev_hook_inverted: bool,
+    pub evdev_ptt_key: Option<u16>,
+    // Each press is one dial pulse, the digit ends once they stop for evdev_pulse_digit_gap
+    pub evdev_pulse_key: Option<u16>,
+    #[serde(deserialize_with = "millis")]
+    pub evdev_pulse_digit_gap: Duration,
+    // Keys for 0-9, then * and #
+    pub evdev_digit_keys: Vec<u16>,
+
+    // cpal host by name, e.g. "alsa" or "jack", the platform default if unset or unavailable
+    pub audio_host: Option<String>,
+    // Output device that also plays the call, by name from --list-audio-devices. Skipped while
+    // it's missing
+    pub monitor_output_device: Option<String>,
+    pub frame_strategy: FrameStrategy,
+    pub output_underrun_callback_limit: u32,
+    // Output buffer depth the drift compensator aims for, which acts as the jitter buffer. Each
+    // call starts from the depth for its ICE path: direct (host candidates, usually the LAN),
+    // relayed through TURN, or this one for anything in between
+    #[serde(deserialize_with = "millis")]
+    pub output_target_depth: Duration,
+    #[serde(deserialize_with = "millis")]
+    pub direct_output_target_depth: Duration,
+    #[serde(deserialize_with = "millis")]
+    pub relayed_output_target_depth: Duration,
+    #[serde(deserialize_with = "millis")]
+    pub output_depth_tolerance: Duration,
+    pub drift_correction_interval: usize,
+    // How long the output depth is averaged over before the drift compensator acts on it.
+    // Longer rides out more jitter but is slower to catch up after a burst, 0 reacts right away
+    #[serde(deserialize_with = "millis")]
+    pub drift_averaging_time: Duration,
+    // Soft limiter on the final mix, after everything is summed. 1.0 only stops clipping, lower
+    // leaves headroom. Raise the release if loud-then-quiet speech pumps
+    pub limiter_threshold: f32,
+    #[serde(deserialize_with = "millis")]
+    pub limiter_attack: Duration,
+    #[serde(deserialize_with = "millis")]
+    pub limiter_release: Duration,
+    // Profile call audio starts on, the server can switch to any other in audio_profiles
+    pub audio_profile: String,
+    // A table here replaces the default quiet, default and loud profiles
+    pub audio_profiles: HashMap<String, AudioProfile>,
+    // Output device channels call audio and locally played tones go to. The default of channel
+    // 0 for both suits a single earpiece, split them for e.g. a handset plus a loudspeaker
+    pub voice_output_channels: Vec<u16>,
+    pub tone_output_channels: Vec<u16>,
+    // Dialtone, ringback and the other PlaySound sounds open their own output through rodio.
+    // Turn this off where that fights the call audio device or tones aren't wanted
+    pub local_sounds_enabled: bool,
+    // Let one-shot sounds finish and play in order, picking the loop up again after. Off, every
+    // PlaySound cuts off whatever was playing
+    pub queue_one_shot_sounds: bool,
+    // Mute the mic while a local tone plays, for handsets that let it leak into the mic
+    pub mute_during_tones: bool,
+    // Loudest hiss played while the far end sends DTX instead of audio, 0 for silence
+    pub comfort_noise_level: f32,
+    // Captured audio older than this is dropped after a stall instead of being sent late
+    #[serde(deserialize_with = "millis")]
+    pub mic_buffer_limit: Duration,
+    // Send the last partial frame of mic audio, padded with silence, when hanging up instead of
+    // dropping it
+    pub flush_mic_on_hangup: bool,
+    // Both streams run on silence this long at startup so the first call doesn't hit cold
+    // buffers, 0 to skip
+    #[serde(deserialize_with = "millis")]
+    pub audio_warm_up: Duration,
+    // Closes both audio devices once the phone has been on-hook with no call or peer this long,
+    // freeing them for other apps and saving power. Picking up reopens them, which takes tens of
+    // milliseconds, well before a call's audio arrives. 0 keeps them open
+    #[serde(deserialize_with = "optional_millis")]
+    pub audio_idle_release: Option<Duration>,
+
+    pub call_waiting_enabled: bool,
+    pub call_waiting_tone_frequency: f32,
+    #[serde(deserialize_with = "millis")]
+    pub call_waiting_tone_length: Duration,
+    #[serde(deserialize_with = "millis")]
+    pub call_waiting_tone_interval: Duration,
+    // A quiet beep while a peer is disconnected but might still come back, so a network blip
+    // isn't just dead air
+    pub reconnecting_tone_enabled: bool,
+    #[serde(deserialize_with = "millis")]
+    pub reconnecting_tone_interval: Duration,
+    // Two short beeps in the handset when the server sends a notice while it's off-hook
+    pub notice_tone_enabled: bool,
+
+    #[serde(deserialize_with = "millis")]
+    pub flash_hook_max_duration: Duration,
+    // During a call without hold or call waiting, the handset has to stay down this long to
+    // hang up, so a jostled hook switch doesn't drop the call. 0 hangs up right away
+    #[serde(deserialize_with = "millis")]
+    pub hangup_confirm_time: Duration,
+    // How long the hook switch has to hold a new state before it counts, on top of the
+    // debouncer, so a bouncy pickup doesn't look like a flash
+    #[serde(deserialize_with = "millis")]
+    pub hook_settle_time: Duration,
+
+    // A flash during a single call puts it on hold, another flash resumes it
+    pub hold_enabled: bool,
+    // Played to the far end while on hold, silence otherwise
+    pub hold_music_enabled: bool,
+    // A short beep on our side every hold_reminder_interval while the call is held
+    #[serde(deserialize_with = "millis")]
+    pub hold_reminder_interval: Duration,
+    pub hold_reminder_tone_frequency: f32,
+    #[serde(deserialize_with = "millis")]
+    pub hold_reminder_tone_length: Duration,
+
+    #[serde(deserialize_with = "millis")]
+    pub dial_setup_timeout: Duration,
+    // Left off-hook without a call: the server's dialtone, then reorder this long after pickup or
+    // the last digit, then the howler until it's hung up. 0 skips that stage
+    #[serde(deserialize_with = "optional_millis")]
+    pub off_hook_reorder_after: Option<Duration>,
+    #[serde(deserialize_with = "optional_millis")]
+    pub off_hook_howler_after: Option<Duration>,
+    // Soft beep while the server sets up a dialed call, silence otherwise
+    pub connecting_tone_enabled: bool,
+    // Digits dialed while off-hook in a connected call go to the far end as touch tones, off
+    // drops them. Either way they never reach the server mid-call. Local codes still work
+    pub in_call_dtmf: bool,
+    // Digits beyond this per pickup are dropped with a reorder tone, e.g. a stuck pulse contact
+    pub max_dialed_digits: usize,
+    #[serde(deserialize_with = "millis")]
+    pub ring_test_duration: Duration,
+
+    // Dialed right after picking up, beeps out the phone's status instead of placing a call.
+    // Digits matching the start of the code are held until they diverge from it, or go out as
+    // a normal number if short_code_timeout passes before the next one. Empty by default, and
+    // one starting with a short code makes that short code wait out short_code_timeout
+    pub diagnostic_dial_code: String,
+    // Dialed right after picking up, plays this phone's extension back as touch tones, so it
+    // can be read off with a DTMF decoder and dialed from the other phone. Empty by default,
+    // like diagnostic_dial_code
+    pub announce_dial_code: String,
+    // Dialed during a call, plays back the last replay_length of what the other end said. Empty
+    // by default, and a single digit only works with in_call_dtmf off so touch tones get through
+    pub replay_dial_code: String,
+    #[serde(deserialize_with = "millis")]
+    pub replay_length: Duration,
+    // Dialed during a call, reopens audio on the current default devices, e.g. after plugging
+    // in a headset. Empty by default so no in-call digit is taken over
+    pub switch_audio_devices_dial_code: String,
+    // Complete numbers dialed before a call that do something other than go to the server
+    // as-is. A code that starts a longer one waits short_code_timeout for more digits
+    pub short_codes: HashMap<String, ShortCodeAction>,
+    #[serde(deserialize_with = "millis")]
+    pub short_code_timeout: Duration,
+
+    pub metrics_enabled: bool,
+    pub metrics_port: u16,
+
+    // Watchdog device fed while the audio, network and UI loops are all running, e.g.
+    // /dev/watchdog. Off unless set, see watchdog.rs
+    pub watchdog_device: Option<String>,
+    // A loop that hasn't come around in this long counts as hung and feeding stops
+    #[serde(deserialize_with = "millis")]
+    pub watchdog_stall_timeout: Duration,
+
+    // The phone socket pings this often and reconnects after three intervals without hearing
+    // anything, so a connection that died while idle is replaced before the next call
+    #[serde(deserialize_with = "millis")]
+    pub keepalive_interval: Duration,
+    // Lines per second each socket prints for the messages it sends and receives, 0 hides them.
+    // Connects, disconnects and errors are always printed
+    pub message_log_rate: u32,
+
+    pub ice_servers: Vec<String>,
+    // Candidates gathered later than this aren't sent to the peer. Unset to send everything
+    #[serde(deserialize_with = "optional_millis")]
+    pub ice_gathering_timeout: Option<Duration>,
+    pub ice_allow_host_candidates: bool,
+    pub ice_allow_ipv6_candidates: bool,
+    pub ice_allow_mdns_candidates: bool,
+    // Which of webrtc's default interceptors to register, all of them unless set
+    pub webrtc_interceptors: Vec<WebRtcInterceptor>,
+    // Peers beyond this many are sent Busy, so a misbehaving server or a big conference can't
+    // run the Pi out of CPU
+    pub max_peers: usize,
+    // A Disconnected peer often comes back on its own, it's only dropped if it stays that way
+    // this long. Failed still drops it right away
+    #[serde(deserialize_with = "millis")]
+    pub peer_disconnect_grace_period: Duration,
+    // A connected peer that sends no RTP at all for this long gets an ICE restart. DTX still
+    // sends a packet every 400ms, so silence doesn't count. 0 to never restart
+    #[serde(deserialize_with = "optional_millis")]
+    pub ice_restart_after_stall: Option<Duration>,
+    // Shortest time between two restarts with the same peer
+    #[serde(deserialize_with = "millis")]
+    pub ice_restart_min_interval: Duration,
+    // A peer dropped while we're still off-hook is reconnected this many times before giving up
+    // with reorder, 0 to hang up right away
+    pub reconnect_attempts: u32,
+    // Wait before the first reconnect, doubled for each one after
+    #[serde(deserialize_with = "millis")]
+    pub reconnect_backoff: Duration,
+    // DSCP for call audio, 46 is expedited forwarding. ICE then runs on one marked IPv4 socket,
+    // which gets no STUN candidates, so only use it where the phones reach each other directly
+    // or through TURN. Off by default, 0 leaves packets unmarked too
+    pub audio_dscp: Option<u8>,
+
+    // How long to wait for the Leave to go out after SIGINT/SIGTERM before exiting anyway
+    #[serde(deserialize_with = "millis")]
+    pub shutdown_grace_period: Duration,
+
+    // Mute while no server instruction applies to the current call. A server `Mute` always wins
+    // until the call ends or the handset is hung up, after which this policy applies again.
+    // Auto-unmute only happens while off-hook with a connected peer, and while hung up the server
+    // can't unmute either. See MuteSources in ui.rs for the full precedence.
+    pub idle_muted: bool,
+    pub auto_unmute_on_connect: bool,
+
+    // Keep a call muted until both ends are off-hook and connected, then chirp and unmute.
+    // Both phones need it enabled, the timeout unmutes anyway if the other end never confirms
+    pub answer_confirmation: bool,
+    #[serde(deserialize_with = "millis")]
+    pub answer_confirmation_timeout: Duration,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            test_mode: false,
+            test_mode_ring_interval: Duration::from_secs(30),
+
+            side: None,
+            api_key: None,
+            extension: None,
+            caller_id: None,
+            intercom_number: None,
+            intercom_auto_answer: false,
+            id_path: String::from("phone-id"),
+
+            gpio_enabled: true,
+            hook_switch_pin: 17,
+            dial_latch_pin: 22,
+            dial_pulse_pin: 27,
+            dial_mapping: DialMapping::Nanp,
+            dial_latch_max_duration: Duration::from_secs(3),
+            bell_solenoid_forward_pin: 24,
+            bell_solenoid_reverse_pin: 23,
+            ring_min_interval: Duration::from_millis(500),
+            ring_cadence: Vec::new(),
+            ptt_button_pin: 25,
+            privacy_light_pin: None,
+            ptt_enabled: false,
+
+            evdev_device: None,
+            // KEY_PHONE
+            evdev_hook_key: 169,
+            evdev_hook_inverted: false,
+            evdev_ptt_key: None,
+            evdev_pulse_key: None,
+            evdev_pulse_digit_gap: Duration::from_millis(300),
+            // KEY_NUMERIC_0 to KEY_NUMERIC_9, KEY_NUMERIC_STAR and KEY_NUMERIC_POUND
+            evdev_digit_keys: (0x200..=0x20b).collect(),
+
+            audio_host: None,
+            monitor_output_device: None,
+            frame_strategy: FrameStrategy::Balanced,
+            output_underrun_callback_limit: 100,
+            output_target_depth: Duration::from_millis(60),
+            direct_output_target_depth: Duration::from_millis(40),
+            relayed_output_target_depth: Duration::from_millis(120),
+            output_depth_tolerance: Duration::from_millis(20),
+            drift_correction_interval: 200,
+            drift_averaging_time: Duration::from_secs(2),
+            limiter_threshold: 0.9,
+            limiter_attack: Duration::from_millis(5),
+            limiter_release: Duration::from_millis(200),
+            audio_profile: String::from("default"),
+            audio_profiles: HashMap::from([
+                (
+                    String::from("quiet"),
+                    AudioProfile {
+                        gain: 0.5,
+                        ..AudioProfile::default()
+                    },
+                ),
+                (String::from("default"), AudioProfile::default()),
+                (
+                    String::from("loud"),
+                    AudioProfile {
+                        gain: 2.0,
+                        limiter_threshold: Some(0.95),
+                        high_pass: true,
+                    },
+                ),
+            ]),
+            voice_output_channels: vec![0],
+            tone_output_channels: vec![0],
+            local_sounds_enabled: true,
+            queue_one_shot_sounds: true,
+            mute_during_tones: false,
+            comfort_noise_level: 0.003,
+            mic_buffer_limit: Duration::from_millis(100),
+            flush_mic_on_hangup: true,
+            audio_warm_up: Duration::from_millis(300),
+            audio_idle_release: None,
+
+            call_waiting_enabled: false,
+            call_waiting_tone_frequency: 440.0,
+            call_waiting_tone_length: Duration::from_millis(300),
+            call_waiting_tone_interval: Duration::from_secs(10),
+            reconnecting_tone_enabled: true,
+            notice_tone_enabled: true,
+            reconnecting_tone_interval: Duration::from_millis(1500),
+
+            flash_hook_max_duration: Duration::from_millis(800),
+            hangup_confirm_time: Duration::from_millis(400),
+            hook_settle_time: Duration::from_millis(50),
+
+            hold_enabled: false,
+            hold_music_enabled: true,
+            hold_reminder_interval: Duration::from_secs(15),
+            hold_reminder_tone_frequency: 620.0,
+            hold_reminder_tone_length: Duration::from_millis(150),
+
+            connecting_tone_enabled: false,
+            dial_setup_timeout: Duration::from_secs(30),
+            off_hook_reorder_after: Some(Duration::from_secs(20)),
+            off_hook_howler_after: Some(Duration::from_secs(60)),
+            in_call_dtmf: true,
+            max_dialed_digits: 32,
+            ring_test_duration: Duration::from_secs(2),
+
+            diagnostic_dial_code: String::new(),
+            announce_dial_code: String::new(),
+            replay_dial_code: String::new(),
+            replay_length: Duration::from_secs(5),
+            switch_audio_devices_dial_code: String::new(),
+            short_codes: HashMap::from([(String::from("0"), ShortCodeAction::DoorOpen)]),
+            short_code_timeout: Duration::from_secs(2),
+
+            metrics_enabled: false,
+            metrics_port: 9100,
+
+            watchdog_device: None,
+            watchdog_stall_timeout: Duration::from_secs(10),
+
+            keepalive_interval: Duration::from_secs(15),
+            message_log_rate: 10,
+
+            ice_servers: vec![String::from("stun:stun.l.google.com:19302")],
+            ice_gathering_timeout: None,
+            ice_allow_host_candidates: true,
+            ice_allow_ipv6_candidates: true,
+            ice_allow_mdns_candidates: true,
+            webrtc_interceptors: vec![
+                WebRtcInterceptor::Nack,
+                WebRtcInterceptor::RtcpReports,
+                WebRtcInterceptor::Twcc,
+            ],
+            max_peers: 4,
+            peer_disconnect_grace_period: Duration::from_secs(5),
+            ice_restart_after_stall: Some(Duration::from_secs(3)),
+            ice_restart_min_interval: Duration::from_secs(15),
+            reconnect_attempts: 3,
+            reconnect_backoff: Duration::from_secs(1),
+            audio_dscp: None,
+
+            shutdown_grace_period: Duration::from_secs(2),
+
+            idle_muted: true,
+            auto_unmute_on_connect: false,
+
+            answer_confirmation: false,
+            answer_confirmation_timeout: Duration::from_secs(3),
+        }
+    }
+}
+
+impl Config {
+    // Reads the file named by PHONE_CONFIG, or phone-bell.toml if it exists, then applies
+    // PHONE_* environment overrides
+    fn load() -> Result<Config, Vec<String>> {
+        let explicit_path = env::var("PHONE_CONFIG").ok();
+        let path = explicit_path.as_deref().unwrap_or(DEFAULT_CONFIG_PATH);
+
+        let mut config = match fs::read_to_string(path) {
+            Ok(contents) => {
+                toml::from_str(&contents).map_err(|error| vec![format!("{}: {}", path, error)])?
+            }
+            Err(_) if explicit_path.is_none() => Config::default(),
+            Err(error) => return Err(vec![format!("{}: {}", path, error)]),
+        };
+
+        let mut problems = Vec::new();
+
+        if let Some(test_mode) = env_override("PHONE_TEST_MODE", &mut problems) {
+            config.test_mode = test_mode;
+        }
+        if let Some(side) = env_override("PHONE_SIDE", &mut problems) {
+            config.side = Some(side);
+        }
+        if let Some(api_key) = env_override("PHONE_API_KEY", &mut problems) {
+            config.api_key = Some(api_key);
+        }
+        if let Some(extension) = env_override("PHONE_EXTENSION", &mut problems) {
+            config.extension = Some(extension);
+        }
+        if let Some(caller_id) = env_override("PHONE_CALLER_ID", &mut problems) {
+            config.caller_id = Some(caller_id);
+        }
+        if let Some(id_path) = env_override("PHONE_ID_PATH", &mut problems) {
+            config.id_path = id_path;
+        }
+        if let Some(metrics_enabled) = env_override("PHONE_METRICS_ENABLED", &mut problems) {
+            config.metrics_enabled = metrics_enabled;
+        }
+        if let Some(metrics_port) = env_override("PHONE_METRICS_PORT", &mut problems) {
+            config.metrics_port = metrics_port;
+        }
+
+        problems.extend(config.validate());
+
+        if problems.is_empty() {
+            Ok(config)
+        } else {
+            Err(problems)
+        }
+    }
+
+    fn validate(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+
+        if self.side.is_none() && !self.test_mode {
+            problems.push(String::from("side is required (or PHONE_SIDE)"));
+        }
+        if self.api_key.is_none() && !self.test_mode {
+            problems.push(String::from("api_key is required (or PHONE_API_KEY)"));
+        }
+
+        let mut pins = vec![
+            ("hook_switch_pin", self.hook_switch_pin),
+            ("dial_latch_pin", self.dial_latch_pin),
+            ("dial_pulse_pin", self.dial_pulse_pin),
+            ("bell_solenoid_forward_pin", self.bell_solenoid_forward_pin),
+            ("bell_solenoid_reverse_pin", self.bell_solenoid_reverse_pin),
+        ];
+
+        if self.ptt_enabled {
+            pins.push(("ptt_button_pin", self.ptt_button_pin));
+        }
+
+        if let Some(privacy_light_pin) = self.privacy_light_pin {
+            pins.push(("privacy_light_pin", privacy_light_pin));
+        }
+
+        for (index, (name, pin)) in pins.iter().enumerate() {
+            if *pin > 27 {
+                problems.push(format!("{} {} is not a GPIO pin", name, pin));
+            }
+
+            if let Some((other_name, _)) = pins[..index].iter().find(|(_, other)| other == pin) {
+                problems.push(format!("{} and {} are both pin {}", other_name, name, pin));
+            }
+        }
+
+        if self.output_underrun_callback_limit == 0 {
+            problems.push(String::from(
+                "output_underrun_callback_limit must be at least 1",
+            ));
+        }
+        for (name, depth) in [
+            ("output_target_depth", self.output_target_depth),
+            (
+                "direct_output_target_depth",
+                self.direct_output_target_depth,
+            ),
+            (
+                "relayed_output_target_depth",
+                self.relayed_output_target_depth,
+            ),
+        ] {
+            if self.output_depth_tolerance >= depth {
+                problems.push(format!("output_depth_tolerance must be less than {}", name));
+            }
+        }
+        if !(self.limiter_threshold > 0.0 && self.limiter_threshold <= 1.0) {
+            problems.push(format!(
+                "limiter_threshold {} must be above 0 and at most 1",
+                self.limiter_threshold
+            ));
+        }
+
+        if !self.audio_profiles.contains_key(&self.audio_profile) {
+            problems.push(format!(
+                "audio_profile {:?} isn't one of audio_profiles",
+                self.audio_profile
+            ));
+        }
+
+        for (name, profile) in &self.audio_profiles {
+            if !(profile.gain.is_finite() && profile.gain >= 0.0) {
+                problems.push(format!(
+                    "audio profile {:?} gain {} can't be negative",
+                    name, profile.gain
+                ));
+            }
+
+            if let Some(threshold) = profile.limiter_threshold {
+                if !(threshold > 0.0 && threshold <= 1.0) {
+                    problems.push(format!(
+                        "audio profile {:?} limiter_threshold {} must be above 0 and at most 1",
+                        name, threshold
+                    ));
+                }
+            }
+        }
+
+        if self.drift_correction_interval == 0 {
+            problems.push(String::from("drift_correction_interval must be at least 1"));
+        }
+        if let Some(audio_host) = &self.audio_host {
+            // Only hosts built into this binary, whether they work is checked at startup
+            if !cpal::ALL_HOSTS
+                .iter()
+                .any(|host| host.name().eq_ignore_ascii_case(audio_host))
+            {
+                let known: Vec<&str> = cpal::ALL_HOSTS.iter().map(|host| host.name()).collect();
+
+                problems.push(format!(
+                    "audio_host {:?} isn't one of {}",
+                    audio_host,
+                    known.join(", ")
+                ));
+            }
+        }
+
+        if self.voice_output_channels.is_empty() {
+            problems.push(String::from("voice_output_channels can't be empty"));
+        }
+        if self.tone_output_channels.is_empty() {
+            problems.push(String::from("tone_output_channels can't be empty"));
+        }
+
+        // Has to fit the longest Opus frame or no frame would ever fill up
+        if self.mic_buffer_limit < Duration::from_millis(60) {
+            problems.push(String::from("mic_buffer_limit must be at least 60"));
+        }
+
+        if !(0.0..=0.1).contains(&self.comfort_noise_level) {
+            problems.push(format!(
+                "comfort_noise_level {} must be between 0 and 0.1",
+                self.comfort_noise_level
+            ));
+        }
+
+        if !(self.call_waiting_tone_frequency > 0.0
+            && self.call_waiting_tone_frequency < SAMPLE_RATE as f32 / 2.0)
+        {
+            problems.push(format!(
+                "call_waiting_tone_frequency {} is out of range",
+                self.call_waiting_tone_frequency
+            ));
+        }
+
+        if !(self.hold_reminder_tone_frequency > 0.0
+            && self.hold_reminder_tone_frequency < SAMPLE_RATE as f32 / 2.0)
+        {
+            problems.push(format!(
+                "hold_reminder_tone_frequency {} is out of range",
+                self.hold_reminder_tone_frequency
+            ));
+        }
+
+        if self.watchdog_stall_timeout < Duration::from_secs(2) {
+            problems.push(String::from("watchdog_stall_timeout must be at least 2000"));
+        }
+
+        if self.max_peers == 0 {
+            problems.push(String::from("max_peers must be at least 1"));
+        }
+
+        if self.max_dialed_digits == 0 {
+            problems.push(String::from("max_dialed_digits must be at least 1"));
+        }
+
+        if self.flash_hook_max_duration.is_zero() {
+            problems.push(String::from("flash_hook_max_duration must be positive"));
+        }
+
+        if !self.hangup_confirm_time.is_zero() && self.hook_settle_time >= self.hangup_confirm_time
+        {
+            problems.push(String::from(
+                "hook_settle_time has to be shorter than hangup_confirm_time",
+            ));
+        }
+
+        if self.hook_settle_time >= self.flash_hook_max_duration {
+            problems.push(String::from(
+                "hook_settle_time has to be shorter than flash_hook_max_duration",
+            ));
+        }
+
+        for (name, code) in [
+            ("diagnostic_dial_code", &self.diagnostic_dial_code),
+            ("announce_dial_code", &self.announce_dial_code),
+            ("replay_dial_code", &self.replay_dial_code),
+            (
+                "switch_audio_devices_dial_code",
+                &self.switch_audio_devices_dial_code,
+            ),
+        ] {
+            if !code.chars().all(|digit| digit.is_ascii_digit()) {
+                problems.push(format!("{} {:?} can only contain digits", name, code));
+            }
+        }
+
+        if let Some(intercom_number) = &self.intercom_number {
+            if !network::is_dialable(intercom_number) {
+                problems.push(format!(
+                    "intercom_number {:?} can only contain digits",
+                    intercom_number
+                ));
+            }
+        }
+
+        if let Some(extension) = &self.extension {
+            if extension.is_empty() || !extension.chars().all(|digit| digit.is_ascii_digit()) {
+                problems.push(format!("extension {:?} can only contain digits", extension));
+            }
+        }
+
+        for (code, action) in &self.short_codes {
+            if code.is_empty() || !code.chars().all(|digit| digit.is_ascii_digit()) {
+                problems.push(format!("short code {:?} can only contain digits", code));
+            }
+
+            if matches!(action, ShortCodeAction::Intercom) && self.intercom_number.is_none() {
+                problems.push(format!("short code {:?} needs intercom_number", code));
+            }
+        }
+
+        if self.keepalive_interval.is_zero() {
+            problems.push(String::from("keepalive_interval must be positive"));
+        }
+
+        if self.metrics_enabled && self.metrics_port == 0 {
+            problems.push(String::from(
+                "metrics_port must be set when metrics are enabled",
+            ));
+        }
+
+        for ice_server in &self.ice_servers {
+            if !["stun:", "stuns:", "turn:", "turns:"]
+                .iter()
+                .any(|scheme| ice_server.starts_with(scheme))
+            {
+                problems.push(format!(
+                    "ice server {:?} is not a STUN/TURN url",
+                    ice_server
+                ));
+            }
+        }
+
+        if self.audio_dscp.is_some_and(|dscp| dscp > 63) {
+            problems.push(String::from("audio_dscp must be 0-63"));
+        }
+
+        for (index, interceptor) in self.webrtc_interceptors.iter().enumerate() {
+            if self.webrtc_interceptors[..index].contains(interceptor) {
+                problems.push(format!("webrtc_interceptors lists {:?} twice", interceptor));
+            }
+        }
+
+        if self.evdev_digit_keys.len() > 12 {
+            problems.push(String::from(
+                "evdev_digit_keys has more than the 12 keys for 0-9, * and #",
+            ));
+        }
+
+        if let (Some(reorder_after), Some(howler_after)) =
+            (self.off_hook_reorder_after, self.off_hook_howler_after)
+        {
+            if howler_after <= reorder_after {
+                problems.push(String::from(
+                    "off_hook_howler_after must be longer than off_hook_reorder_after",
+                ));
+            }
+        }
+
+        problems
+    }
+}
+
+pub fn get() -> &'static Config {
+    CONFIG.get_or_init(|| {
+        // Tests run against the defaults, whatever config file or environment is around
+        if cfg!(test) {
+            return Config::default();
+        }
+
+        match Config::load() {
+            Ok(config) => config,
+            Err(problems) => {
+                println!("Invalid configuration:");
+
+                for problem in problems {
+                    println!("  - {}", problem);
+                }
+
+                process::exit(1);
+            }
+        }
+    })
+}
+
+fn env_override<T: FromStr>(name: &str, problems: &mut Vec<String>) -> Option<T>
+where
+    T::Err: Display,
+{
+    let value = env::var(name).ok()?;
+
+    match value.parse() {
+        Ok(value) => Some(value),
+        Err(error) => {
+            problems.push(format!("{} {:?}: {}", name, value, error));
+
+            None
+        }
+    }
+}
+
+fn millis<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+    u64::deserialize(deserializer).map(Duration::from_millis)
+}
+
+fn millis_list<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<Duration>, D::Error> {
+    Vec::<u64>::deserialize(deserializer)
+        .map(|millis| millis.into_iter().map(Duration::from_millis).collect())
+}
+
+fn optional_millis<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<Duration>, D::Error> {
+    // 0 is the same as leaving it out, so a default that's set can still be turned off
+    Option::<u64>::deserialize(deserializer).map(|millis| {
+        millis
+            .filter(|millis| *millis > 0)
+            .map(Duration::from_millis)
+    })
+}
@@ -1,39 +1,74 @@
-use std::sync::mpsc::{self, Receiver, Sender};
+use std::{
+    collections::VecDeque,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc::{self, Receiver, Sender, TryRecvError},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
 
 use cpal::{
     traits::{DeviceTrait, HostTrait, StreamTrait},
     BuildStreamError, Device, FromSample, Host, Sample, SampleFormat, SampleRate, Stream,
-    StreamConfig, StreamError, SupportedStreamConfig,
+    StreamConfig, StreamError, SupportedStreamConfig, SupportedStreamConfigRange,
 };
 
-use crate::config::SAMPLE_RATE;
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast;
+
+use crate::{
+    config::{self, opus_frame_length, AudioProfile, SAMPLE_RATE},
+    metrics,
+};
 
 #[macro_export]
 macro_rules! create_output_stream {
-    ($device:tt, $config:tt, $x:ty, $audio_receiver:tt, $error_sender:tt, $config_copy:tt) => {
+    (
+        $device:tt,
+        $config:tt,
+        $x:ty,
+        $audio_receiver:tt,
+        $buffer_depth:tt,
+        $event_sender:tt,
+        $config_copy:tt
+    ) => {{
+        let data_event_sender = $event_sender.clone();
+        let data_buffer_depth = $buffer_depth.clone();
+        let mut output_monitor = OutputMonitor::default();
+
         $device.build_output_stream(
             &$config.config(),
             move |data, info| {
-                Self::output_stream_data_callback::<$x>(data, info, &$audio_receiver, &$config_copy)
+                Self::output_stream_data_callback::<$x>(
+                    data,
+                    info,
+                    &$audio_receiver,
+                    &data_buffer_depth,
+                    &$config_copy,
+                    &mut output_monitor,
+                    &data_event_sender,
+                )
             },
             move |error| {
-                let _ = $error_sender.send((StreamKind::Outgoing, error));
+                let _ = $event_sender.send(AudioEvent::StreamError(StreamKind::Outgoing, error));
             },
             None,
         )
-    };
+    }};
 }
 
 #[macro_export]
 macro_rules! create_input_stream {
-    ($device:tt, $config:tt, $x:ty, $audio_receiver:tt, $error_sender:tt, $config_copy:tt) => {
+    ($device:tt, $config:tt, $x:ty, $audio_receiver:tt, $event_sender:tt, $config_copy:tt) => {
         $device.build_input_stream(
             &$config.config(),
             move |data, info| {
                 Self::input_stream_data_callback::<$x>(data, info, &$audio_receiver, &$config_copy)
             },
             move |error| {
-                let _ = $error_sender.send((StreamKind::Incoming, error));
+                let _ = $event_sender.send(AudioEvent::StreamError(StreamKind::Incoming, error));
             },
             None,
         )
@@ -48,6 +83,78 @@ enum CPALStreamState {
     DeviceConfigStream(Device, SupportedStreamConfig, Stream),
 }
 
+impl CPALStreamState {
+    fn device_name(&self) -> Option<String> {
+        match self {
+            CPALStreamState::Nothing => None,
+            CPALStreamState::Device(device)
+            | CPALStreamState::DeviceConfig(device, _)
+            | CPALStreamState::DeviceConfigStream(device, _, _) => device.name().ok(),
+        }
+    }
+}
+
+// Where captured audio comes from and mixed audio goes. AudioSystem is the real one, tests can
+// swap in hardware::injected to drive a call without cpal
+pub trait AudioIo {
+    fn read_next_frames(&mut self) -> Result<Vec<Vec<f32>>, StreamReadError>;
+
+    // The samples read_next_frames held back, padded out to a frame
+    fn flush_remainder(&mut self) -> Option<Vec<f32>>;
+
+    fn write_next_samples(
+        &mut self,
+        samples: &[f32],
+        purpose: OutputPurpose,
+    ) -> Result<(), StreamWriteError>;
+}
+
+// What mixed audio is, so it can go to different output channels, e.g. tones on a loudspeaker
+// and the call on the earpiece
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OutputPurpose {
+    Voice,
+    Tone,
+}
+
+pub type MixedSamples = (OutputPurpose, Vec<f32>);
+
+impl OutputPurpose {
+    fn channels(self) -> &'static [u16] {
+        match self {
+            OutputPurpose::Voice => &config::get().voice_output_channels,
+            OutputPurpose::Tone => &config::get().tone_output_channels,
+        }
+    }
+}
+
+// One pass of moving mic frames to the peers and mixed audio to the output
+pub fn transfer_audio(
+    audio_io: &mut impl AudioIo,
+    mic_sender: &broadcast::Sender<Vec<f32>>,
+    mixed_output: &Receiver<MixedSamples>,
+) {
+    if let Ok(frames) = audio_io.read_next_frames() {
+        for frame in frames {
+            let _ = mic_sender.send(frame);
+        }
+    }
+    // With no output stream, e.g. while a busy device waits to be retried, the mix is dropped
+    // and the audio thread carries on until the stream is back
+    if let Ok((purpose, samples)) = mixed_output.try_recv() {
+        if let Err(error) = audio_io.write_next_samples(samples.as_slice(), purpose) {
+            let dropped = metrics::OUTPUT_FRAMES_DROPPED.fetch_add(1, Ordering::Relaxed) + 1;
+
+            if dropped.is_power_of_two() {
+                println!(
+                    "Dropping mixed audio, no output ({:?}), {} dropped so far",
+                    error, dropped
+                );
+            }
+        }
+    }
+}
+
 #[derive(Debug)]
 pub enum StreamReadError {
     NoStream,
@@ -58,31 +165,480 @@ pub enum StreamWriteError {
     WriteFailed,
 }
 
+// Cuts as many frames off the front of the buffer as the frame strategy allows. Whatever is too
+// short for another frame stays at the front for the next read, so nothing is lost between reads
+pub fn take_frames(sample_buffer: &mut Vec<f32>) -> Vec<Vec<f32>> {
+    let mut frames = Vec::new();
+
+    while let Some(frame_length) = config::get()
+        .frame_strategy
+        .next_frame_length(sample_buffer.len())
+    {
+        frames.push(sample_buffer.drain(0..frame_length).collect());
+    }
+
+    frames
+}
+
+// Keeps latency bounded after a stall by dropping the oldest samples past the limit. Returns how
+// many were dropped
+fn trim_oldest(sample_buffer: &mut Vec<f32>, limit: usize) -> usize {
+    let excess = sample_buffer.len().saturating_sub(limit);
+
+    sample_buffer.drain(..excess);
+
+    excess
+}
+
+// Empties the held back remainder into the shortest Opus frame it fits, padded with silence
+pub fn pad_remainder(sample_buffer: &mut Vec<f32>) -> Option<Vec<f32>> {
+    if sample_buffer.is_empty() {
+        return None;
+    }
+
+    let frame_length = [
+        FRAME_LENGTH_25,
+        FRAME_LENGTH_50,
+        FRAME_LENGTH_100,
+        FRAME_LENGTH_200,
+        FRAME_LENGTH_400,
+        FRAME_LENGTH_600,
+    ]
+    .into_iter()
+    .find(|frame_length| *frame_length >= sample_buffer.len())
+    .unwrap_or(sample_buffer.len());
+
+    let mut frame = std::mem::take(sample_buffer);
+    frame.resize(frame_length, 0.0);
+
+    Some(frame)
+}
+
+// How often a missing or failed monitor output is looked for again
+const MONITOR_RETRY: Duration = Duration::from_secs(10);
+// How far the monitor may lag the earpiece before its samples are dropped
+const MONITOR_MAX_DEPTH: Duration = Duration::from_millis(200);
+
+// How long past the fade itself fade_out waits for the output to play it
+const FADE_OUT_DRAIN_TIMEOUT: Duration = Duration::from_millis(200);
+
+const SAMPLE_RATE_PER_MILLISECOND: f32 = (SAMPLE_RATE / 1000) as f32;
+
+fn sample_count(duration: Duration) -> usize {
+    (duration.as_secs_f32() * SAMPLE_RATE as f32) as usize
+}
+
+const FRAME_LENGTH_25: usize = (SAMPLE_RATE_PER_MILLISECOND * 2.5) as usize;
+const FRAME_LENGTH_50: usize = (SAMPLE_RATE_PER_MILLISECOND * 5.0) as usize;
+const FRAME_LENGTH_100: usize = (SAMPLE_RATE_PER_MILLISECOND * 10.0) as usize;
+const FRAME_LENGTH_200: usize = (SAMPLE_RATE_PER_MILLISECOND * 20.0) as usize;
+const FRAME_LENGTH_400: usize = (SAMPLE_RATE_PER_MILLISECOND * 40.0) as usize;
+const FRAME_LENGTH_600: usize = (SAMPLE_RATE_PER_MILLISECOND * 60.0) as usize;
+
+const _: () = {
+    assert!(FRAME_LENGTH_25 == opus_frame_length(25));
+    assert!(FRAME_LENGTH_50 == opus_frame_length(50));
+    assert!(FRAME_LENGTH_100 == opus_frame_length(100));
+    assert!(FRAME_LENGTH_200 == opus_frame_length(200));
+    assert!(FRAME_LENGTH_400 == opus_frame_length(400));
+    assert!(FRAME_LENGTH_600 == opus_frame_length(600));
+};
+
+// How long a stream that failed on a busy device waits before trying again
+const DEVICE_BUSY_RETRY: Duration = Duration::from_secs(1);
+
+/// How captured audio is cut into Opus frames. Every frame waits for its full length of audio
+/// before it can be sent, so longer frames add latency (and more audio per lost packet) in
+/// exchange for fewer packets and less per-packet overhead. The receive side sizes its decode
+/// buffer for the longest (60ms) frame, so any strategy works with any peer.
+#[derive(Deserialize, Debug, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum FrameStrategy {
+    /// Fixed 10ms frames
+    LowLatency,
+    /// Fixed 20ms frames
+    Balanced,
+    /// Largest frame that fits the buffered audio, up to 60ms
+    Efficient,
+}
+
+impl FrameStrategy {
+    pub fn next_frame_length(&self, available_samples: usize) -> Option<usize> {
+        let frame_length = match self {
+            FrameStrategy::LowLatency => FRAME_LENGTH_100,
+            FrameStrategy::Balanced => FRAME_LENGTH_200,
+            FrameStrategy::Efficient => [
+                FRAME_LENGTH_600,
+                FRAME_LENGTH_400,
+                FRAME_LENGTH_200,
+                FRAME_LENGTH_100,
+                FRAME_LENGTH_50,
+                FRAME_LENGTH_25,
+            ]
+            .into_iter()
+            .find(|frame_length| available_samples >= *frame_length)?,
+        };
+
+        (available_samples >= frame_length).then_some(frame_length)
+    }
+}
+
+pub enum AudioCommand {
+    // Rebuild both streams on whatever the default devices are now
+    SwitchDevices,
+    // Send what's left of the mic buffer, at the end of a call
+    FlushInput,
+    // Fade out, let the output drain and stop both streams, answered once they're stopped
+    Shutdown(Sender<()>),
+    // Answered with AudioEvent::Diagnostics
+    ReportDiagnostics,
+    // Where the output buffer should settle, from the call's network path
+    SetOutputTargetDepth(Duration),
+    // On-hook with no call or peer, see config.audio_idle_release
+    SetIdle(bool),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AudioDiagnostics {
+    pub input_device: Option<String>,
+    pub output_device: Option<String>,
+    pub input_paused: bool,
+    pub output_buffer_depth_ms: u64,
+    pub clock_drift_ppm: f64,
+}
+
+#[derive(Debug)]
 pub enum StreamKind {
     Incoming,
     Outgoing,
 }
 
+#[derive(Debug)]
+pub enum AudioEvent {
+    StreamError(StreamKind, StreamError),
+    OutputStalled,
+    OutputDisconnected,
+    Diagnostics(AudioDiagnostics),
+}
+
+#[derive(Default)]
+struct OutputMonitor {
+    had_samples: bool,
+    empty_callbacks: u32,
+    disconnect_reported: bool,
+}
+
+impl OutputMonitor {
+    /// Returns true once when output has gone without samples for too many callbacks in a row
+    /// after previously receiving some, so idle silence between calls isn't reported.
+    fn update(&mut self, received_samples: bool) -> bool {
+        if received_samples {
+            self.had_samples = true;
+            self.empty_callbacks = 0;
+
+            return false;
+        }
+
+        if !self.had_samples {
+            return false;
+        }
+
+        self.empty_callbacks += 1;
+
+        if self.empty_callbacks >= config::get().output_underrun_callback_limit {
+            self.had_samples = false;
+            self.empty_callbacks = 0;
+
+            return true;
+        }
+
+        false
+    }
+
+    fn disconnected(&mut self) -> bool {
+        !std::mem::replace(&mut self.disconnect_reported, true)
+    }
+}
+
+// Keeps the output buffer near a target depth by dropping a sample when it runs deep, or
+// interpolating an extra one when it runs shallow, at most once per correction interval. The
+// depth is averaged over a few seconds first, so jitter bursts come and go without corrections
+// and only a steady mismatch between the peer's capture clock and our playback clock is fixed
+pub struct DriftCompensator {
+    target_depth: usize,
+    tolerance: usize,
+    interval: usize,
+    samples_since_correction: usize,
+    averaging_samples: usize,
+    average_depth: f32,
+    // Inserted minus dropped samples out of everything written, the long-term clock mismatch
+    net_corrections: i64,
+    samples_written: u64,
+}
+
+impl DriftCompensator {
+    pub fn new(target_depth: usize, tolerance: usize, interval: usize) -> Self {
+        Self {
+            target_depth,
+            tolerance,
+            interval,
+            samples_since_correction: 0,
+            averaging_samples: sample_count(config::get().drift_averaging_time),
+            average_depth: target_depth as f32,
+            net_corrections: 0,
+            samples_written: 0,
+        }
+    }
+
+    // How much faster our output runs than the audio arriving for it, in parts per million
+    pub fn drift_ppm(&self) -> f64 {
+        if self.samples_written == 0 {
+            return 0.0;
+        }
+
+        self.net_corrections as f64 * 1_000_000.0 / self.samples_written as f64
+    }
+
+    pub fn set_target_depth(&mut self, target_depth: usize) {
+        self.target_depth = target_depth;
+    }
+
+    pub fn compensate(&mut self, samples: &[f32], buffered: usize) -> Vec<f32> {
+        let weight = if self.averaging_samples == 0 {
+            1.0
+        } else {
+            (samples.len() as f32 / self.averaging_samples as f32).min(1.0)
+        };
+
+        self.average_depth += (buffered as f32 - self.average_depth) * weight;
+        self.samples_written += samples.len() as u64;
+
+        let depth = self.average_depth as usize;
+
+        let too_deep = depth > self.target_depth + self.tolerance;
+        let too_shallow = depth + samples.len() < self.target_depth.saturating_sub(self.tolerance);
+
+        let mut compensated = Vec::with_capacity(samples.len() + samples.len() / self.interval + 1);
+
+        for (index, sample) in samples.iter().enumerate() {
+            self.samples_since_correction += 1;
+
+            if self.samples_since_correction < self.interval || !(too_deep || too_shallow) {
+                compensated.push(*sample);
+
+                continue;
+            }
+
+            self.samples_since_correction = 0;
+
+            if too_shallow {
+                let next_sample = samples.get(index + 1).unwrap_or(sample);
+
+                compensated.push(*sample);
+                compensated.push((sample + next_sample) / 2.0);
+
+                self.net_corrections += 1;
+            } else {
+                self.net_corrections -= 1;
+            }
+        }
+
+        compensated
+    }
+}
+
+// Soft limiter on the final mix. An envelope follows the peak level, rising over the attack
+// time and falling over the release time, and the mix is scaled down while the envelope is above
+// the threshold. Too short a release makes quiet audio after a loud burst audibly swell back up
+pub struct Limiter {
+    threshold: f32,
+    attack_coefficient: f32,
+    release_coefficient: f32,
+    envelope: f32,
+}
+
+impl Limiter {
+    pub fn new(threshold: f32, attack: Duration, release: Duration) -> Self {
+        Self {
+            threshold,
+            attack_coefficient: smoothing_coefficient(attack),
+            release_coefficient: smoothing_coefficient(release),
+            envelope: 0.0,
+        }
+    }
+
+    pub fn process(&mut self, samples: &mut [f32]) {
+        for sample in samples {
+            let level = sample.abs();
+
+            let coefficient = if level > self.envelope {
+                self.attack_coefficient
+            } else {
+                self.release_coefficient
+            };
+
+            self.envelope = coefficient * self.envelope + (1.0 - coefficient) * level;
+
+            if self.envelope > self.threshold {
+                *sample *= self.threshold / self.envelope;
+            }
+
+            // Whatever the attack lets through still can't clip
+            *sample = sample.clamp(-1.0, 1.0);
+        }
+    }
+
+    pub fn set_threshold(&mut self, threshold: f32) {
+        self.threshold = threshold;
+    }
+
+    pub fn reset(&mut self) {
+        self.envelope = 0.0;
+    }
+}
+
+// How long switching audio profiles fades the gain and high-pass over, so it doesn't click
+const PROFILE_RAMP: Duration = Duration::from_millis(200);
+
+// Below this the loud profile's high-pass rolls off, under where speech needs anything
+const HIGH_PASS_CUTOFF: f32 = 250.0;
+
+// Applies the current audio profile's gain and high-pass to call audio. The high-pass always
+// runs and is blended in, so switching it on or off fades like the gain does
+struct ProfileStage {
+    gain: f32,
+    high_pass_mix: f32,
+    target_gain: f32,
+    target_high_pass_mix: f32,
+    gain_step: f32,
+    high_pass_mix_step: f32,
+    ramp_remaining: usize,
+    // One-pole high-pass
+    high_pass_coefficient: f32,
+    last_input: f32,
+    last_output: f32,
+}
+
+impl ProfileStage {
+    fn new(profile: &AudioProfile) -> Self {
+        let rc = 1.0 / (2.0 * std::f32::consts::PI * HIGH_PASS_CUTOFF);
+        let dt = 1.0 / SAMPLE_RATE as f32;
+        let high_pass_mix = if profile.high_pass { 1.0 } else { 0.0 };
+
+        Self {
+            gain: profile.gain,
+            high_pass_mix,
+            target_gain: profile.gain,
+            target_high_pass_mix: high_pass_mix,
+            gain_step: 0.0,
+            high_pass_mix_step: 0.0,
+            ramp_remaining: 0,
+            high_pass_coefficient: rc / (rc + dt),
+            last_input: 0.0,
+            last_output: 0.0,
+        }
+    }
+
+    fn set(&mut self, profile: &AudioProfile) {
+        self.target_gain = profile.gain;
+        self.target_high_pass_mix = if profile.high_pass { 1.0 } else { 0.0 };
+        self.ramp_remaining = sample_count(PROFILE_RAMP).max(1);
+        self.gain_step = (self.target_gain - self.gain) / self.ramp_remaining as f32;
+        self.high_pass_mix_step =
+            (self.target_high_pass_mix - self.high_pass_mix) / self.ramp_remaining as f32;
+    }
+
+    fn process(&mut self, samples: &mut [f32]) {
+        for sample in samples {
+            if self.ramp_remaining > 0 {
+                self.ramp_remaining -= 1;
+
+                if self.ramp_remaining == 0 {
+                    self.gain = self.target_gain;
+                    self.high_pass_mix = self.target_high_pass_mix;
+                } else {
+                    self.gain += self.gain_step;
+                    self.high_pass_mix += self.high_pass_mix_step;
+                }
+            }
+
+            let filtered =
+                self.high_pass_coefficient * (self.last_output + *sample - self.last_input);
+
+            self.last_input = *sample;
+            self.last_output = filtered;
+
+            *sample = self.gain * (*sample + self.high_pass_mix * (filtered - *sample));
+        }
+    }
+
+    fn reset(&mut self) {
+        self.last_input = 0.0;
+        self.last_output = 0.0;
+    }
+}
+
+// Per sample, so the envelope covers about 63% of a step in the given time
+fn smoothing_coefficient(time: Duration) -> f32 {
+    let samples = time.as_secs_f32() * SAMPLE_RATE as f32;
+
+    if samples <= 0.0 {
+        0.0
+    } else {
+        (-1.0 / samples).exp()
+    }
+}
+
 pub struct AudioMixer {
     from_inputs: Receiver<MixerMessage>,
-    to_output: Sender<Vec<f32>>,
+    to_output: Sender<MixedSamples>,
+    profile_stage: ProfileStage,
+    limiter: Limiter,
+    // Recently received call audio, without local tones, for replay
+    recent_samples: VecDeque<f32>,
+    recent_samples_capacity: usize,
 }
 
+// Channel used for locally generated tones, RTC channels count up from 0
+pub const TONE_CHANNEL: i64 = -1;
+
 pub enum MixerMessage {
     Open(i64),
     Samples(i64, u16, Vec<f32>),
     Close(i64),
+    Replay,
+    // Fades call audio over to a new profile, see config.audio_profiles
+    SetProfile(AudioProfile),
 }
 
 impl AudioMixer {
-    pub fn create() -> (Self, mpsc::Sender<MixerMessage>, mpsc::Receiver<Vec<f32>>) {
+    pub fn create() -> (
+        Self,
+        mpsc::Sender<MixerMessage>,
+        mpsc::Receiver<MixedSamples>,
+    ) {
+        let config = config::get();
+
         let (mixer_input, from_inputs) = mpsc::channel();
         let (to_output, mixer_output) = mpsc::channel();
 
+        // validate makes sure it's there
+        let profile = &config.audio_profiles[&config.audio_profile];
+
         (
             Self {
                 from_inputs,
                 to_output,
+                profile_stage: ProfileStage::new(profile),
+                limiter: Limiter::new(
+                    profile
+                        .limiter_threshold
+                        .unwrap_or(config.limiter_threshold),
+                    config.limiter_attack,
+                    config.limiter_release,
+                ),
+                recent_samples: VecDeque::new(),
+                recent_samples_capacity: sample_count(config.replay_length),
             },
             mixer_input,
             mixer_output,
@@ -115,19 +671,74 @@ impl AudioMixer {
 
         // ! this code is for testing purposes only
         loop {
+            // Every sender is gone, nothing can reach the mixer anymore
             let Ok(mixer_message) = self.from_inputs.recv() else {
-                continue;
+                return;
             };
 
             match mixer_message {
                 MixerMessage::Open(_) => {}
-                MixerMessage::Samples(_, _, samples) => {
-                    let _ = self.to_output.send(samples);
+                MixerMessage::Samples(channel_number, _, mut samples) => {
+                    let purpose = if channel_number == TONE_CHANNEL {
+                        OutputPurpose::Tone
+                    } else {
+                        self.remember(&samples);
+                        self.profile_stage.process(&mut samples);
+
+                        OutputPurpose::Voice
+                    };
+
+                    self.limiter.process(&mut samples);
+
+                    let _ = self.to_output.send((purpose, samples));
                 }
                 MixerMessage::Close(_) => {}
+                MixerMessage::Replay => {
+                    let mut samples: Vec<f32> = self.recent_samples.iter().copied().collect();
+
+                    self.profile_stage.process(&mut samples);
+                    self.limiter.process(&mut samples);
+
+                    let _ = self.to_output.send((OutputPurpose::Voice, samples));
+                }
+                MixerMessage::SetProfile(profile) => {
+                    self.profile_stage.set(&profile);
+                    self.limiter.set_threshold(
+                        profile
+                            .limiter_threshold
+                            .unwrap_or(config::get().limiter_threshold),
+                    );
+                }
             }
         }
     }
+
+    // Drops state that a panic may have left inconsistent, the channels stay connected
+    pub fn reset(&mut self) {
+        self.recent_samples.clear();
+        self.profile_stage.reset();
+        self.limiter.reset();
+    }
+
+    fn remember(&mut self, samples: &[f32]) {
+        self.recent_samples.extend(samples);
+
+        let excess = self
+            .recent_samples
+            .len()
+            .saturating_sub(self.recent_samples_capacity);
+
+        self.recent_samples.drain(..excess);
+    }
+}
+
+// A second output playing the same mix, e.g. a speaker so bystanders can hear a call. It has its
+// own buffer and stream, so a slow or missing monitor never holds up the earpiece
+struct MonitorOutput {
+    _stream: Stream,
+    sender: Sender<(f32, OutputPurpose)>,
+    buffer_depth: Arc<AtomicUsize>,
+    events: Receiver<AudioEvent>,
 }
 
 pub struct AudioSystem {
@@ -135,43 +746,133 @@ pub struct AudioSystem {
 
     input_stream: CPALStreamState,
     output_stream: CPALStreamState,
+    // Nobody is listening to the mic, so capture is stopped
+    input_paused: bool,
+    // Set while waiting out a busy device, see stream_failed
+    input_retry_at: Option<Instant>,
+    output_retry_at: Option<Instant>,
 
     incoming_audio_buffer: Option<Receiver<f32>>,
 
-    outgoing_audio_buffer: Option<Sender<f32>>,
+    outgoing_audio_buffer: Option<Sender<(f32, OutputPurpose)>>,
+    outgoing_buffer_depth: Arc<AtomicUsize>,
+    outgoing_drift_compensator: DriftCompensator,
     outgoing_sample_buffer: Vec<f32>,
 
-    pub error_buffer: Receiver<(StreamKind, StreamError)>,
-    error_buffer_sender: Sender<(StreamKind, StreamError)>,
+    monitor: Option<MonitorOutput>,
+    monitor_retry_at: Option<Instant>,
+
+    idle_since: Option<Instant>,
+    // Both streams were closed for being idle and aren't reopened until something needs them
+    released: bool,
+
+    pub event_buffer: Receiver<AudioEvent>,
+    event_buffer_sender: Sender<AudioEvent>,
 }
 
 impl AudioSystem {
     pub fn create() -> AudioSystem {
-        let cpal_host = cpal::default_host();
+        let mut audio_system = AudioSystem::new(configured_host());
+
+        audio_system.prepare_input();
+        audio_system.prepare_output();
+
+        let input_device = audio_system.input_stream.device_name();
 
-        let (error_buffer_sender, error_buffer) = mpsc::channel();
+        if input_device.is_some() && input_device == audio_system.output_stream.device_name() {
+            println!(
+                "{} is both input and output, opened as two streams",
+                input_device.unwrap_or_default()
+            );
+        }
+
+        let warm_up = config::get().audio_warm_up;
+
+        if !warm_up.is_zero() {
+            audio_system.warm_up(warm_up);
+        }
+
+        audio_system
+    }
 
-        let mut audio_system = AudioSystem {
+    // Nothing opened yet, create() brings the streams up
+    fn new(cpal_host: Host) -> AudioSystem {
+        let (event_buffer_sender, event_buffer) = mpsc::channel();
+
+        AudioSystem {
             cpal_host,
 
             input_stream: CPALStreamState::Nothing,
             output_stream: CPALStreamState::Nothing,
+            input_paused: false,
+            input_retry_at: None,
+            output_retry_at: None,
 
             incoming_audio_buffer: Option::None,
             outgoing_audio_buffer: Option::None,
+            outgoing_buffer_depth: Arc::new(AtomicUsize::new(0)),
+            outgoing_drift_compensator: DriftCompensator::new(
+                sample_count(config::get().output_target_depth),
+                sample_count(config::get().output_depth_tolerance),
+                config::get().drift_correction_interval,
+            ),
             outgoing_sample_buffer: Vec::new(),
 
-            error_buffer,
-            error_buffer_sender,
-        };
+            monitor: None,
+            monitor_retry_at: None,
 
-        audio_system.prepare_input();
-        audio_system.prepare_output();
+            idle_since: None,
+            released: false,
 
-        audio_system
+            event_buffer,
+            event_buffer_sender,
+        }
+    }
+
+    // Nothing reads the mic or feeds the mixer yet, so what's captured here is only counted and
+    // thrown away. Doubles as a check that both devices actually run
+    fn warm_up(&mut self, duration: Duration) {
+        let started = Instant::now();
+        let step = Duration::from_millis(10);
+        let silence = vec![0.0; sample_count(step)];
+        let mut captured = 0;
+        let mut output_ok = true;
+
+        while started.elapsed() < duration {
+            if let Ok(frames) = self.read_next_frames() {
+                captured += frames.iter().map(Vec::len).sum::<usize>();
+            }
+
+            output_ok &= self
+                .write_next_samples(&silence, OutputPurpose::Voice)
+                .is_ok();
+
+            thread::sleep(step);
+        }
+
+        self.outgoing_sample_buffer.clear();
+
+        if captured == 0 {
+            println!("Audio warm-up got nothing from the input device");
+        }
+
+        if !output_ok {
+            println!("Audio warm-up couldn't write to the output device");
+        }
+
+        if captured > 0 && output_ok {
+            println!("Audio warmed up in {:?}", duration);
+        }
     }
 
     pub fn prepare_input(&mut self) -> bool {
+        if self
+            .input_retry_at
+            .is_some_and(|retry_at| retry_at > Instant::now())
+        {
+            return false;
+        }
+
         loop {
             match &self.input_stream {
                 CPALStreamState::Nothing => {
@@ -195,18 +896,23 @@ impl AudioSystem {
                 CPALStreamState::DeviceConfig(device, config) => {
                     let (audio_sender, audio_receiver) = mpsc::channel::<f32>();
 
-                    let Some(stream) = self.new_input_stream(
+                    let stream = match self.new_input_stream(
                         device,
                         config,
                         audio_sender,
-                        self.error_buffer_sender.clone(),
-                    ) else {
-                        println!("Failed to init audio streams!");
-
-                        return false;
+                        self.event_buffer_sender.clone(),
+                    ) {
+                        Ok(stream) => stream,
+                        Err(error) => {
+                            self.stream_failed(StreamKind::Incoming, error);
+
+                            return false;
+                        }
                     };
 
-                    let _ = stream.play();
+                    if !self.input_paused {
+                        let _ = stream.play();
+                    }
 
                     self.incoming_audio_buffer = Option::Some(audio_receiver);
 
@@ -220,6 +926,13 @@ impl AudioSystem {
         }
     }
     pub fn prepare_output(&mut self) -> bool {
+        if self
+            .output_retry_at
+            .is_some_and(|retry_at| retry_at > Instant::now())
+        {
+            return false;
+        }
+
         loop {
             match &self.output_stream {
                 CPALStreamState::Nothing => {
@@ -241,17 +954,23 @@ impl AudioSystem {
                     self.output_stream = CPALStreamState::DeviceConfig(device.clone(), config);
                 }
                 CPALStreamState::DeviceConfig(device, config) => {
-                    let (audio_sender, audio_receiver) = mpsc::channel::<f32>();
+                    let (audio_sender, audio_receiver) = mpsc::channel();
 
-                    let Some(stream) = self.new_output_stream(
+                    self.outgoing_buffer_depth = Arc::new(AtomicUsize::new(0));
+
+                    let stream = match self.new_output_stream(
                         device,
                         config,
                         audio_receiver,
-                        self.error_buffer_sender.clone(),
-                    ) else {
-                        println!("Failed to init audio streams!");
-
-                        return false;
+                        self.outgoing_buffer_depth.clone(),
+                        self.event_buffer_sender.clone(),
+                    ) {
+                        Ok(stream) => stream,
+                        Err(error) => {
+                            self.stream_failed(StreamKind::Outgoing, error);
+
+                            return false;
+                        }
                     };
 
                     let _ = stream.play();
@@ -268,6 +987,208 @@ impl AudioSystem {
         }
     }
 
+    // A busy device is usually the other direction of the same handset, which some backends
+    // won't open as two streams. cpal has no duplex streams, so all that can be done is waiting
+    // for it to be let go, e.g. while switching devices, and trying again
+    fn stream_failed(&mut self, kind: StreamKind, error: BuildStreamError) {
+        let busy = match &error {
+            BuildStreamError::DeviceNotAvailable => true,
+            BuildStreamError::BackendSpecific { err } => {
+                err.description.to_lowercase().contains("busy")
+            }
+            _ => false,
+        };
+
+        if !busy {
+            println!("Failed to init audio streams: {}", error);
+
+            return;
+        }
+
+        let (device, other_device) = match kind {
+            StreamKind::Incoming => (&self.input_stream, &self.output_stream),
+            StreamKind::Outgoing => (&self.output_stream, &self.input_stream),
+        };
+
+        let name = device
+            .device_name()
+            .unwrap_or_else(|| String::from("Audio device"));
+
+        if device.device_name().is_some() && device.device_name() == other_device.device_name() {
+            println!(
+                "{} is busy opening {:?} while the other direction has it, retrying in {:?}. \
+                If it keeps failing, pick a host that shares devices or separate devices",
+                name, kind, DEVICE_BUSY_RETRY
+            );
+        } else {
+            println!("{} is busy, retrying in {:?}", name, DEVICE_BUSY_RETRY);
+        }
+
+        let retry_at = Some(Instant::now() + DEVICE_BUSY_RETRY);
+
+        match kind {
+            StreamKind::Incoming => self.input_retry_at = retry_at,
+            StreamKind::Outgoing => self.output_retry_at = retry_at,
+        }
+    }
+
+    // Stops the input stream while paused, anything captured before the pause is thrown away
+    pub fn set_input_paused(&mut self, paused: bool) {
+        if self.input_paused == paused {
+            return;
+        }
+
+        self.input_paused = paused;
+
+        println!("Mic capture {}", if paused { "paused" } else { "resumed" });
+
+        if let CPALStreamState::DeviceConfigStream(_, _, stream) = &self.input_stream {
+            if paused {
+                let _ = stream.pause();
+            } else {
+                let _ = stream.play();
+            }
+        }
+
+        if let Some(buffer) = &self.incoming_audio_buffer {
+            while buffer.try_recv().is_ok() {}
+        }
+
+        self.outgoing_sample_buffer.clear();
+    }
+
+    // Ramps the mix down to silence over duration and waits for the output to play it, so
+    // stopping the stream afterwards doesn't cut off mid-waveform with a click
+    pub fn fade_out(&mut self, mixed_output: &Receiver<MixedSamples>, duration: Duration) {
+        self.set_input_paused(true);
+
+        let fade_length = sample_count(duration).max(1);
+        let mut faded = 0;
+        let deadline = Instant::now() + duration + FADE_OUT_DRAIN_TIMEOUT;
+
+        while faded < fade_length && Instant::now() < deadline {
+            let Ok((purpose, mut samples)) = mixed_output.recv_timeout(Duration::from_millis(5))
+            else {
+                continue;
+            };
+
+            for sample in &mut samples {
+                *sample *= 1.0 - (faded as f32 / fade_length as f32).min(1.0);
+                faded += 1;
+            }
+
+            let _ = self.write_next_samples(&samples, purpose);
+        }
+
+        while self.outgoing_buffer_depth.load(Ordering::Relaxed) > 0 && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(5));
+        }
+    }
+
+    pub fn reset_output(&mut self) {
+        self.output_retry_at = None;
+        self.outgoing_audio_buffer = None;
+        self.output_stream = CPALStreamState::Nothing;
+    }
+
+    pub fn reset_input(&mut self) {
+        self.input_retry_at = None;
+        self.incoming_audio_buffer = None;
+        self.input_stream = CPALStreamState::Nothing;
+    }
+
+    // Reopens both streams right away so the gap is only as long as building them
+    pub fn switch_devices(&mut self) {
+        self.reset_input();
+        self.reset_output();
+
+        self.monitor = None;
+        self.monitor_retry_at = None;
+
+        let input_ready = self.prepare_input();
+        let output_ready = self.prepare_output();
+
+        println!(
+            "Switched audio devices (input {}, output {})",
+            if input_ready { "ok" } else { "failed" },
+            if output_ready { "ok" } else { "failed" }
+        );
+    }
+
+    pub fn set_idle(&mut self, idle: bool) {
+        if idle {
+            self.idle_since.get_or_insert_with(Instant::now);
+        } else {
+            self.idle_since = None;
+
+            self.reopen();
+        }
+    }
+
+    // Closes both streams once the phone has been idle for audio_idle_release
+    pub fn release_if_idle(&mut self) {
+        let Some(idle_release) = config::get().audio_idle_release else {
+            return;
+        };
+
+        if self.released
+            || self
+                .idle_since
+                .is_none_or(|idle_since| idle_since.elapsed() < idle_release)
+        {
+            return;
+        }
+
+        self.reset_input();
+        self.reset_output();
+
+        self.monitor = None;
+        self.monitor_retry_at = None;
+
+        self.outgoing_sample_buffer.clear();
+        self.released = true;
+
+        println!("Idle for {:?}, released the audio devices", idle_release);
+    }
+
+    // Builds both streams straight away rather than on first use, so the start of a call isn't
+    // spent opening devices
+    fn reopen(&mut self) {
+        if !self.released {
+            return;
+        }
+
+        self.released = false;
+
+        let input_ready = self.prepare_input();
+        let output_ready = self.prepare_output();
+
+        println!(
+            "Reopened audio devices (input {}, output {})",
+            if input_ready { "ok" } else { "failed" },
+            if output_ready { "ok" } else { "failed" }
+        );
+    }
+
+    pub fn set_output_target_depth(&mut self, target_depth: Duration) {
+        println!("Output buffer target is now {:?}", target_depth);
+
+        self.outgoing_drift_compensator
+            .set_target_depth(sample_count(target_depth));
+    }
+
+    pub fn diagnostics(&self) -> AudioDiagnostics {
+        let depth = self.outgoing_buffer_depth.load(Ordering::Relaxed);
+
+        AudioDiagnostics {
+            input_device: self.input_stream.device_name(),
+            output_device: self.output_stream.device_name(),
+            input_paused: self.input_paused,
+            output_buffer_depth_ms: (depth as u64 * 1000) / SAMPLE_RATE as u64,
+            clock_drift_ppm: self.outgoing_drift_compensator.drift_ppm(),
+        }
+    }
+
     fn new_input_device(&self) -> Option<Device> {
         self.cpal_host.default_input_device()
     }
@@ -277,18 +1198,30 @@ impl AudioSystem {
 
     fn new_input_config(&self, device: &Device) -> Option<SupportedStreamConfig> {
         match device.supported_input_configs() {
-            Ok(mut supported_configs_range) => supported_configs_range
-                .next()
-                .map(|supported_config| supported_config.with_sample_rate(SampleRate(SAMPLE_RATE))),
-            Err(_) => None,
+            Ok(supported_configs_range) => choose_config(
+                device,
+                StreamKind::Incoming,
+                supported_configs_range.collect(),
+            ),
+            Err(error) => {
+                println!("Couldn't list input configs: {}", error);
+
+                None
+            }
         }
     }
     fn new_output_config(&self, device: &Device) -> Option<SupportedStreamConfig> {
         match device.supported_output_configs() {
-            Ok(mut supported_configs_range) => supported_configs_range
-                .next()
-                .map(|supported_config| supported_config.with_sample_rate(SampleRate(SAMPLE_RATE))),
-            Err(_) => None,
+            Ok(supported_configs_range) => choose_config(
+                device,
+                StreamKind::Outgoing,
+                supported_configs_range.collect(),
+            ),
+            Err(error) => {
+                println!("Couldn't list output configs: {}", error);
+
+                None
+            }
         }
     }
 
@@ -297,44 +1230,43 @@ impl AudioSystem {
         device: &Device,
         config: &SupportedStreamConfig,
         audio_sender: Sender<f32>,
-        error_sender: Sender<(StreamKind, StreamError)>,
-    ) -> Option<Stream> {
+        event_sender: Sender<AudioEvent>,
+    ) -> Result<Stream, BuildStreamError> {
         let config_copy = config.clone();
 
         match config.sample_format() {
             SampleFormat::F32 => {
-                create_input_stream!(device, config, f32, audio_sender, error_sender, config_copy)
+                create_input_stream!(device, config, f32, audio_sender, event_sender, config_copy)
             }
             SampleFormat::I16 => {
-                create_input_stream!(device, config, i16, audio_sender, error_sender, config_copy)
+                create_input_stream!(device, config, i16, audio_sender, event_sender, config_copy)
             }
             SampleFormat::U16 => {
-                create_input_stream!(device, config, u16, audio_sender, error_sender, config_copy)
+                create_input_stream!(device, config, u16, audio_sender, event_sender, config_copy)
             }
             SampleFormat::I8 => {
-                create_input_stream!(device, config, i8, audio_sender, error_sender, config_copy)
+                create_input_stream!(device, config, i8, audio_sender, event_sender, config_copy)
             }
             SampleFormat::I32 => {
-                create_input_stream!(device, config, i32, audio_sender, error_sender, config_copy)
+                create_input_stream!(device, config, i32, audio_sender, event_sender, config_copy)
             }
             SampleFormat::I64 => {
-                create_input_stream!(device, config, i64, audio_sender, error_sender, config_copy)
+                create_input_stream!(device, config, i64, audio_sender, event_sender, config_copy)
             }
             SampleFormat::U8 => {
-                create_input_stream!(device, config, u8, audio_sender, error_sender, config_copy)
+                create_input_stream!(device, config, u8, audio_sender, event_sender, config_copy)
             }
             SampleFormat::U32 => {
-                create_input_stream!(device, config, u32, audio_sender, error_sender, config_copy)
+                create_input_stream!(device, config, u32, audio_sender, event_sender, config_copy)
             }
             SampleFormat::U64 => {
-                create_input_stream!(device, config, u64, audio_sender, error_sender, config_copy)
+                create_input_stream!(device, config, u64, audio_sender, event_sender, config_copy)
             }
             SampleFormat::F64 => {
-                create_input_stream!(device, config, f64, audio_sender, error_sender, config_copy)
+                create_input_stream!(device, config, f64, audio_sender, event_sender, config_copy)
             }
             _ => Err(BuildStreamError::StreamConfigNotSupported),
         }
-        .ok()
     }
     fn input_stream_data_callback<T: Sample>(
         data: &[T],
@@ -353,9 +1285,10 @@ impl AudioSystem {
         &self,
         device: &Device,
         config: &SupportedStreamConfig,
-        audio_receiver: Receiver<f32>,
-        error_sender: Sender<(StreamKind, StreamError)>,
-    ) -> Option<Stream> {
+        audio_receiver: Receiver<(f32, OutputPurpose)>,
+        buffer_depth: Arc<AtomicUsize>,
+        event_sender: Sender<AudioEvent>,
+    ) -> Result<Stream, BuildStreamError> {
         let config_copy = config.clone();
 
         match config.sample_format() {
@@ -365,7 +1298,8 @@ impl AudioSystem {
                     config,
                     f32,
                     audio_receiver,
-                    error_sender,
+                    buffer_depth,
+                    event_sender,
                     config_copy
                 )
             }
@@ -375,7 +1309,8 @@ impl AudioSystem {
                     config,
                     i16,
                     audio_receiver,
-                    error_sender,
+                    buffer_depth,
+                    event_sender,
                     config_copy
                 )
             }
@@ -385,7 +1320,8 @@ impl AudioSystem {
                     config,
                     u16,
                     audio_receiver,
-                    error_sender,
+                    buffer_depth,
+                    event_sender,
                     config_copy
                 )
             }
@@ -395,7 +1331,8 @@ impl AudioSystem {
                     config,
                     i8,
                     audio_receiver,
-                    error_sender,
+                    buffer_depth,
+                    event_sender,
                     config_copy
                 )
             }
@@ -405,7 +1342,8 @@ impl AudioSystem {
                     config,
                     i32,
                     audio_receiver,
-                    error_sender,
+                    buffer_depth,
+                    event_sender,
                     config_copy
                 )
             }
@@ -415,7 +1353,8 @@ impl AudioSystem {
                     config,
                     i64,
                     audio_receiver,
-                    error_sender,
+                    buffer_depth,
+                    event_sender,
                     config_copy
                 )
             }
@@ -425,7 +1364,8 @@ impl AudioSystem {
                     config,
                     u8,
                     audio_receiver,
-                    error_sender,
+                    buffer_depth,
+                    event_sender,
                     config_copy
                 )
             }
@@ -435,7 +1375,8 @@ impl AudioSystem {
                     config,
                     u32,
                     audio_receiver,
-                    error_sender,
+                    buffer_depth,
+                    event_sender,
                     config_copy
                 )
             }
@@ -445,7 +1386,8 @@ impl AudioSystem {
                     config,
                     u64,
                     audio_receiver,
-                    error_sender,
+                    buffer_depth,
+                    event_sender,
                     config_copy
                 )
             }
@@ -455,51 +1397,200 @@ impl AudioSystem {
                     config,
                     f64,
                     audio_receiver,
-                    error_sender,
+                    buffer_depth,
+                    event_sender,
                     config_copy
                 )
             }
             _ => Err(BuildStreamError::StreamConfigNotSupported),
         }
-        .ok()
     }
     fn output_stream_data_callback<T: Sample + FromSample<f32>>(
         data: &mut [T],
         _output_callback_info: &cpal::OutputCallbackInfo,
-        audio_buffer_reference: &Receiver<f32>,
+        audio_buffer_reference: &Receiver<(f32, OutputPurpose)>,
+        buffer_depth: &AtomicUsize,
         config: &SupportedStreamConfig,
+        output_monitor: &mut OutputMonitor,
+        event_sender: &Sender<AudioEvent>,
     ) {
-        for sample in data.iter_mut().step_by(config.channels() as usize) {
+        let mut received_samples = 0;
+        let mut disconnected = false;
+
+        for frame in data.chunks_mut(config.channels() as usize) {
+            frame.fill(Sample::EQUILIBRIUM);
+
             match audio_buffer_reference.try_recv() {
-                Ok(sample_value) => *sample = T::from_sample(sample_value),
-                Err(_) => *sample = Sample::EQUILIBRIUM,
+                Ok((sample_value, purpose)) => {
+                    // Channels the device doesn't have are skipped
+                    for channel in purpose.channels() {
+                        if let Some(sample) = frame.get_mut(*channel as usize) {
+                            *sample = T::from_sample(sample_value);
+                        }
+                    }
+
+                    received_samples += 1;
+                }
+                Err(TryRecvError::Empty) => {}
+                Err(TryRecvError::Disconnected) => disconnected = true,
             }
         }
+
+        buffer_depth.fetch_sub(received_samples, Ordering::Relaxed);
+
+        if disconnected && output_monitor.disconnected() {
+            let _ = event_sender.send(AudioEvent::OutputDisconnected);
+        }
+
+        if output_monitor.update(received_samples > 0) {
+            let _ = event_sender.send(AudioEvent::OutputStalled);
+        }
     }
 
-    pub fn write_next_samples(&mut self, new_samples: &[f32]) -> Result<(), StreamWriteError> {
+    pub fn write_next_samples(
+        &mut self,
+        new_samples: &[f32],
+        purpose: OutputPurpose,
+    ) -> Result<(), StreamWriteError> {
+        // Something to play means the phone isn't idle after all, e.g. a tone while on-hook.
+        // The idle timer starts over
+        if self.released {
+            self.reopen();
+
+            if self.idle_since.is_some() {
+                self.idle_since = Some(Instant::now());
+            }
+        }
+
         self.prepare_output();
 
         match &self.outgoing_audio_buffer {
             Some(buffer) => {
-                for sample in new_samples.iter() {
-                    let _ = buffer.send(*sample);
+                let compensated_samples = self.outgoing_drift_compensator.compensate(
+                    new_samples,
+                    self.outgoing_buffer_depth.load(Ordering::Relaxed),
+                );
+
+                self.outgoing_buffer_depth
+                    .fetch_add(compensated_samples.len(), Ordering::Relaxed);
+
+                for sample in &compensated_samples {
+                    let _ = buffer.send((*sample, purpose));
                 }
+
+                self.write_monitor(&compensated_samples, purpose);
+
                 Ok(())
             }
             None => Err(StreamWriteError::NoStream),
         }
     }
 
-    pub fn read_next_frames(&mut self) -> Result<Vec<Vec<f32>>, StreamReadError> {
-        const SAMPLE_RATE_PER_MILLISECOND: f32 = (SAMPLE_RATE / 1000) as f32;
+    // After the earpiece has its samples. Whatever would push the monitor past
+    // MONITOR_MAX_DEPTH is dropped rather than letting it fall further behind
+    fn write_monitor(&mut self, samples: &[f32], purpose: OutputPurpose) {
+        self.prepare_monitor();
+
+        let Some(monitor) = &self.monitor else {
+            return;
+        };
 
-        const FRAME_LENGTH_25: usize = (SAMPLE_RATE_PER_MILLISECOND * 2.5) as usize;
-        const FRAME_LENGTH_50: usize = (SAMPLE_RATE_PER_MILLISECOND * 5.0) as usize;
-        const FRAME_LENGTH_100: usize = (SAMPLE_RATE_PER_MILLISECOND * 10.0) as usize;
-        const FRAME_LENGTH_200: usize = (SAMPLE_RATE_PER_MILLISECOND * 20.0) as usize;
-        const FRAME_LENGTH_400: usize = (SAMPLE_RATE_PER_MILLISECOND * 40.0) as usize;
-        const FRAME_LENGTH_600: usize = (SAMPLE_RATE_PER_MILLISECOND * 60.0) as usize;
+        let failed = monitor.events.try_iter().any(|event| {
+            matches!(
+                event,
+                AudioEvent::OutputDisconnected | AudioEvent::StreamError(_, _)
+            )
+        });
+
+        if failed {
+            println!("Monitor output failed, retrying in {:?}", MONITOR_RETRY);
+
+            self.monitor = None;
+
+            return;
+        }
+
+        if monitor.buffer_depth.load(Ordering::Relaxed) + samples.len()
+            > sample_count(MONITOR_MAX_DEPTH)
+        {
+            return;
+        }
+
+        monitor
+            .buffer_depth
+            .fetch_add(samples.len(), Ordering::Relaxed);
+
+        for sample in samples {
+            let _ = monitor.sender.send((*sample, purpose));
+        }
+    }
+
+    // Looked up by name, see --list-audio-devices. A missing device is tried again every
+    // MONITOR_RETRY, only the first miss is logged
+    fn prepare_monitor(&mut self) {
+        let Some(name) = &config::get().monitor_output_device else {
+            return;
+        };
+
+        if self.monitor.is_some() || self.monitor_retry_at.is_some_and(|at| Instant::now() < at) {
+            return;
+        }
+
+        let first_attempt = self.monitor_retry_at.is_none();
+
+        self.monitor_retry_at = Some(Instant::now() + MONITOR_RETRY);
+
+        let device = self
+            .cpal_host
+            .output_devices()
+            .ok()
+            .and_then(|mut devices| {
+                devices.find(|device| device.name().is_ok_and(|device_name| &device_name == name))
+            });
+
+        let Some(device) = device else {
+            if first_attempt {
+                println!("Monitor output {} isn't there, skipping it", name);
+            }
+
+            return;
+        };
+
+        let Some(config) = self.new_output_config(&device) else {
+            return;
+        };
+
+        let (sender, receiver) = mpsc::channel();
+        let (event_sender, events) = mpsc::channel();
+        let buffer_depth = Arc::new(AtomicUsize::new(0));
+
+        match self.new_output_stream(
+            &device,
+            &config,
+            receiver,
+            buffer_depth.clone(),
+            event_sender,
+        ) {
+            Ok(stream) => {
+                let _ = stream.play();
+
+                println!("Monitoring the call on {}", name);
+
+                self.monitor = Some(MonitorOutput {
+                    _stream: stream,
+                    sender,
+                    buffer_depth,
+                    events,
+                });
+            }
+            Err(error) => println!("Couldn't open monitor output {}: {}", name, error),
+        }
+    }
+
+    pub fn read_next_frames(&mut self) -> Result<Vec<Vec<f32>>, StreamReadError> {
+        if self.input_paused || self.released {
+            return Ok(Vec::new());
+        }
 
         self.prepare_input();
 
@@ -509,58 +1600,29 @@ impl AudioSystem {
                     self.outgoing_sample_buffer.push(sample);
                 }
 
-                let mut frames = Vec::new();
-
-                let mut available_samples = self.outgoing_sample_buffer.len();
-
-                while available_samples >= FRAME_LENGTH_25 {
-                    if available_samples >= FRAME_LENGTH_600 {
-                        frames.push(
-                            self.outgoing_sample_buffer
-                                .drain(0..FRAME_LENGTH_600)
-                                .collect(),
-                        );
-                    } else if available_samples >= FRAME_LENGTH_400 {
-                        frames.push(
-                            self.outgoing_sample_buffer
-                                .drain(0..FRAME_LENGTH_400)
-                                .collect(),
-                        );
-                    } else if available_samples >= FRAME_LENGTH_200 {
-                        frames.push(
-                            self.outgoing_sample_buffer
-                                .drain(0..FRAME_LENGTH_200)
-                                .collect(),
-                        );
-                    } else if available_samples >= FRAME_LENGTH_100 {
-                        frames.push(
-                            self.outgoing_sample_buffer
-                                .drain(0..FRAME_LENGTH_100)
-                                .collect(),
-                        );
-                    } else if available_samples >= FRAME_LENGTH_50 {
-                        frames.push(
-                            self.outgoing_sample_buffer
-                                .drain(0..FRAME_LENGTH_50)
-                                .collect(),
-                        );
-                    } else {
-                        frames.push(
-                            self.outgoing_sample_buffer
-                                .drain(0..FRAME_LENGTH_25)
-                                .collect(),
-                        );
-                    }
+                let dropped = trim_oldest(
+                    &mut self.outgoing_sample_buffer,
+                    sample_count(config::get().mic_buffer_limit),
+                );
 
-                    available_samples = self.outgoing_sample_buffer.len();
+                if dropped > 0 {
+                    println!("Mic buffer overrun, dropped {} samples", dropped);
                 }
 
-                Ok(frames)
+                Ok(take_frames(&mut self.outgoing_sample_buffer))
             }
             None => Err(StreamReadError::NoStream),
         }
     }
 
+    pub fn flush_remainder(&mut self) -> Option<Vec<f32>> {
+        if self.input_paused {
+            return None;
+        }
+
+        pad_remainder(&mut self.outgoing_sample_buffer)
+    }
+
     pub fn get_input_config(&self) -> Option<StreamConfig> {
         match &self.input_stream {
             CPALStreamState::Nothing => None,
@@ -579,3 +1641,520 @@ impl AudioSystem {
         }
     }
 }
+
+fn supports_sample_rate(config: &SupportedStreamConfigRange) -> bool {
+    config.min_sample_rate().0 <= SAMPLE_RATE && SAMPLE_RATE <= config.max_sample_rate().0
+}
+
+fn describe_config(config: &SupportedStreamConfigRange) -> String {
+    format!(
+        "{} {}ch {}-{}Hz",
+        config.sample_format(),
+        config.channels(),
+        config.min_sample_rate().0,
+        config.max_sample_rate().0
+    )
+}
+
+// Takes the first config that can run at SAMPLE_RATE, since Opus needs it and nothing resamples,
+// logging what the device offers so a failure says why
+fn choose_config(
+    device: &Device,
+    kind: StreamKind,
+    configs: Vec<SupportedStreamConfigRange>,
+) -> Option<SupportedStreamConfig> {
+    let name = device
+        .name()
+        .unwrap_or_else(|_| String::from("unknown device"));
+
+    let chosen = configs.iter().copied().find(supports_sample_rate);
+
+    match chosen {
+        Some(config) => {
+            println!(
+                "{:?} audio on {}: using {}",
+                kind,
+                name,
+                describe_config(&config)
+            );
+        }
+        None => {
+            println!(
+                "{:?} audio on {}: none of its {} configs support {}Hz",
+                kind,
+                name,
+                configs.len(),
+                SAMPLE_RATE
+            );
+
+            for config in &configs {
+                println!("  rejected {}", describe_config(config));
+            }
+        }
+    }
+
+    chosen.map(|config| config.with_sample_rate(SampleRate(SAMPLE_RATE)))
+}
+
+// The host named by audio_host if it's usable here, otherwise the default
+fn configured_host() -> Host {
+    let host = config::get().audio_host.as_ref().and_then(|name| {
+        let available = cpal::available_hosts()
+            .into_iter()
+            .find(|host| host.name().eq_ignore_ascii_case(name));
+
+        let Some(host_id) = available else {
+            println!("Audio host {} isn't available, using the default", name);
+
+            return None;
+        };
+
+        match cpal::host_from_id(host_id) {
+            Ok(host) => Some(host),
+            Err(error) => {
+                println!(
+                    "Couldn't open audio host {}, using the default: {}",
+                    name, error
+                );
+
+                None
+            }
+        }
+    });
+
+    let host = host.unwrap_or_else(cpal::default_host);
+
+    println!("Using audio host {}", host.id().name());
+
+    host
+}
+
+// For --list-audio-devices
+pub fn list_devices() {
+    let host = cpal::default_host();
+
+    let available: Vec<&str> = cpal::available_hosts()
+        .iter()
+        .map(|host| host.name())
+        .collect();
+
+    println!("Audio hosts: {}", available.join(", "));
+    println!("Audio host: {:?}", host.id());
+
+    let default_input = host
+        .default_input_device()
+        .and_then(|device| device.name().ok());
+    let default_output = host
+        .default_output_device()
+        .and_then(|device| device.name().ok());
+
+    let Ok(devices) = host.devices() else {
+        println!("Couldn't list audio devices");
+
+        return;
+    };
+
+    for device in devices {
+        let name = device
+            .name()
+            .unwrap_or_else(|_| String::from("unknown device"));
+
+        println!("{}", name);
+
+        if let Ok(configs) = device.supported_input_configs() {
+            let default = if default_input.as_ref() == Some(&name) {
+                " (default)"
+            } else {
+                ""
+            };
+
+            for config in configs {
+                println!(
+                    "  input{} {}{}",
+                    default,
+                    describe_config(&config),
+                    if supports_sample_rate(&config) {
+                        ""
+                    } else {
+                        " (no 48kHz)"
+                    }
+                );
+            }
+        }
+
+        if let Ok(configs) = device.supported_output_configs() {
+            let default = if default_output.as_ref() == Some(&name) {
+                " (default)"
+            } else {
+                ""
+            };
+
+            for config in configs {
+                println!(
+                    "  output{} {}{}",
+                    default,
+                    describe_config(&config),
+                    if supports_sample_rate(&config) {
+                        ""
+                    } else {
+                        " (no 48kHz)"
+                    }
+                );
+            }
+        }
+    }
+}
+
+impl AudioIo for AudioSystem {
+    fn read_next_frames(&mut self) -> Result<Vec<Vec<f32>>, StreamReadError> {
+        AudioSystem::read_next_frames(self)
+    }
+
+    fn flush_remainder(&mut self) -> Option<Vec<f32>> {
+        AudioSystem::flush_remainder(self)
+    }
+
+    fn write_next_samples(
+        &mut self,
+        samples: &[f32],
+        purpose: OutputPurpose,
+    ) -> Result<(), StreamWriteError> {
+        AudioSystem::write_next_samples(self, samples, purpose)
+    }
+}
+
+// cpal stops a stream when it's dropped, pausing first just makes sure the device is released
+// even if a backend is slow to tear it down
+impl Drop for AudioSystem {
+    fn drop(&mut self) {
+        for stream in [&self.input_stream, &self.output_stream] {
+            if let CPALStreamState::DeviceConfigStream(_, _, stream) = stream {
+                let _ = stream.pause();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A ramp, so inserted samples can be told apart from their neighbours
+    fn ramp(length: usize) -> Vec<f32> {
+        (0..length).map(|index| index as f32).collect()
+    }
+
+    fn milliseconds(length: f32) -> usize {
+        (SAMPLE_RATE_PER_MILLISECOND * length) as usize
+    }
+
+    // Frame lengths a strategy cuts out of the buffer, in order, and what's left over
+    fn frames(strategy: FrameStrategy, mut available: usize) -> (Vec<usize>, usize) {
+        let mut frames = Vec::new();
+
+        while let Some(frame_length) = strategy.next_frame_length(available) {
+            frames.push(frame_length);
+            available -= frame_length;
+        }
+
+        (frames, available)
+    }
+
+    #[test]
+    fn overfilled_mic_buffer_keeps_the_newest_samples() {
+        let mut sample_buffer = ramp(10);
+
+        assert_eq!(trim_oldest(&mut sample_buffer, 4), 6);
+        assert_eq!(sample_buffer, vec![6.0, 7.0, 8.0, 9.0]);
+    }
+
+    #[test]
+    fn mic_buffer_at_the_limit_is_left_alone() {
+        let mut sample_buffer = ramp(4);
+
+        assert_eq!(trim_oldest(&mut sample_buffer, 4), 0);
+        assert_eq!(sample_buffer, ramp(4));
+    }
+
+    #[test]
+    fn low_latency_frames_are_10ms() {
+        assert_eq!(
+            frames(FrameStrategy::LowLatency, milliseconds(75.0)),
+            (vec![milliseconds(10.0); 7], milliseconds(5.0))
+        );
+    }
+
+    #[test]
+    fn balanced_frames_are_20ms() {
+        assert_eq!(
+            frames(FrameStrategy::Balanced, milliseconds(75.0)),
+            (vec![milliseconds(20.0); 3], milliseconds(15.0))
+        );
+    }
+
+    #[test]
+    fn efficient_frames_are_the_largest_that_fit() {
+        assert_eq!(
+            frames(FrameStrategy::Efficient, milliseconds(75.0)),
+            (
+                vec![milliseconds(60.0), milliseconds(10.0), milliseconds(5.0)],
+                0
+            )
+        );
+        assert_eq!(
+            frames(FrameStrategy::Efficient, milliseconds(1.0)),
+            (vec![], milliseconds(1.0))
+        );
+    }
+
+    #[test]
+    fn no_frame_until_one_fits() {
+        assert_eq!(
+            FrameStrategy::LowLatency.next_frame_length(milliseconds(9.0)),
+            None
+        );
+        assert_eq!(
+            FrameStrategy::Balanced.next_frame_length(milliseconds(19.0)),
+            None
+        );
+        assert_eq!(
+            FrameStrategy::Efficient.next_frame_length(milliseconds(2.0)),
+            None
+        );
+    }
+
+    // Reacts to each write's depth on its own, instead of averaging over drift_averaging_time
+    fn compensator(target_depth: usize, tolerance: usize, interval: usize) -> DriftCompensator {
+        let mut compensator = DriftCompensator::new(target_depth, tolerance, interval);
+        compensator.averaging_samples = 0;
+
+        compensator
+    }
+
+    #[test]
+    fn drift_drops_samples_when_too_deep() {
+        let mut compensator = compensator(1000, 100, 50);
+
+        let compensated = compensator.compensate(&ramp(1000), 2000);
+
+        assert_eq!(compensated.len(), 1000 - 1000 / 50);
+        // The 50th sample is the first one dropped
+        assert_eq!(compensated[48], 48.0);
+        assert_eq!(compensated[49], 50.0);
+        assert!(compensator.drift_ppm() < 0.0);
+    }
+
+    #[test]
+    fn drift_interpolates_when_too_shallow() {
+        let mut compensator = compensator(4000, 100, 50);
+
+        let compensated = compensator.compensate(&ramp(1000), 0);
+
+        assert_eq!(compensated.len(), 1000 + 1000 / 50);
+        // Halfway between the 50th sample and the next one
+        assert_eq!(compensated[49], 49.0);
+        assert_eq!(compensated[50], 49.5);
+        assert_eq!(compensated[51], 50.0);
+        assert!(compensator.drift_ppm() > 0.0);
+    }
+
+    #[test]
+    fn drift_leaves_audio_alone_within_tolerance() {
+        let mut compensator = compensator(1000, 100, 50);
+
+        let samples = ramp(1000);
+
+        assert_eq!(compensator.compensate(&samples, 1050), samples);
+        assert_eq!(compensator.compensate(&samples, 950), samples);
+        assert_eq!(compensator.drift_ppm(), 0.0);
+    }
+
+    // The interval counts across writes, so small writes still only get one correction per
+    // interval
+    #[test]
+    fn drift_correction_interval_spans_writes() {
+        let mut compensator = compensator(1000, 100, 50);
+
+        let lengths: Vec<usize> = (0..10)
+            .map(|_| compensator.compensate(&ramp(30), 2000).len())
+            .collect();
+
+        assert_eq!(lengths.iter().sum::<usize>(), 300 - 300 / 50);
+        assert_eq!(lengths[..2], [30, 29]);
+    }
+
+    // The envelope after a burst at full scale then silence for the given time
+    fn envelope_after_release(release: Duration, silence: Duration) -> f32 {
+        let mut limiter = Limiter::new(0.5, Duration::ZERO, release);
+
+        limiter.process(&mut vec![1.0; milliseconds(50.0)]);
+
+        assert_eq!(limiter.envelope, 1.0);
+
+        limiter.process(&mut vec![0.0; milliseconds(silence.as_millis() as f32)]);
+
+        limiter.envelope
+    }
+
+    #[test]
+    fn limiter_envelope_follows_the_release_time() {
+        let one_release = (-1.0f32).exp();
+
+        for release in [50, 200, 500].map(Duration::from_millis) {
+            let envelope = envelope_after_release(release, release);
+
+            assert!(
+                (envelope - one_release).abs() < 0.01,
+                "{:?} release left {}",
+                release,
+                envelope
+            );
+
+            assert!(envelope_after_release(release, release * 3) < 0.06);
+        }
+    }
+
+    #[test]
+    fn limiter_gain_recovers_over_the_release() {
+        let mut limiter = Limiter::new(0.5, Duration::ZERO, Duration::from_millis(200));
+
+        limiter.process(&mut vec![1.0; milliseconds(50.0)]);
+
+        // Quiet speech right after the burst is still turned down
+        let mut quiet = [0.2];
+        limiter.process(&mut quiet);
+        assert!(quiet[0] < 0.11);
+
+        limiter.process(&mut vec![0.0; milliseconds(1000.0)]);
+
+        // And back to untouched once the release has run its course
+        let mut quiet = [0.2];
+        limiter.process(&mut quiet);
+        assert_eq!(quiet[0], 0.2);
+    }
+
+    #[test]
+    fn framing_keeps_every_sample() {
+        let mut sample_buffer = Vec::new();
+        let mut samples_in = 0;
+        let mut samples_out = 0;
+
+        // Odd sized reads, like a device running at a rate that doesn't divide into frames
+        for read in 0..200 {
+            let length = 441 + read % 7;
+
+            sample_buffer.extend(ramp(length));
+            samples_in += length;
+
+            samples_out += take_frames(&mut sample_buffer)
+                .iter()
+                .map(Vec::len)
+                .sum::<usize>();
+
+            assert_eq!(samples_in, samples_out + sample_buffer.len());
+            assert!(sample_buffer.len() < FRAME_LENGTH_200);
+        }
+    }
+
+    #[test]
+    fn flushed_remainder_is_padded_to_a_frame() {
+        let mut sample_buffer = ramp(FRAME_LENGTH_25 + 10);
+
+        let frame = pad_remainder(&mut sample_buffer).unwrap();
+
+        assert_eq!(frame.len(), FRAME_LENGTH_50);
+        assert_eq!(frame[..FRAME_LENGTH_25 + 10], ramp(FRAME_LENGTH_25 + 10));
+        assert!(frame[FRAME_LENGTH_25 + 10..]
+            .iter()
+            .all(|sample| *sample == 0.0));
+        assert!(sample_buffer.is_empty());
+
+        assert_eq!(pad_remainder(&mut sample_buffer), None);
+    }
+
+    // Runs the output for the given time with the device clock off by drift_ppm, writing a 20ms
+    // frame at a time, and returns the deepest and shallowest the buffer got after settling
+    fn simulate_drift(drift_ppm: f64, length: Duration) -> (usize, usize) {
+        let config = config::get();
+        let mut compensator = DriftCompensator::new(
+            sample_count(config.output_target_depth),
+            sample_count(config.output_depth_tolerance),
+            config.drift_correction_interval,
+        );
+
+        let frame = vec![0.0; milliseconds(20.0)];
+        let frames = (length.as_secs_f64() * 50.0) as usize;
+        let settled_after = 50 * 30;
+
+        let mut depth = sample_count(config.output_target_depth) as f64;
+        let (mut deepest, mut shallowest) = (0, usize::MAX);
+
+        for index in 0..frames {
+            depth += compensator.compensate(&frame, depth as usize).len() as f64;
+            depth -= frame.len() as f64 * (1.0 + drift_ppm / 1_000_000.0);
+
+            if index >= settled_after {
+                deepest = deepest.max(depth as usize);
+                shallowest = shallowest.min(depth as usize);
+            }
+        }
+
+        (deepest, shallowest)
+    }
+
+    #[test]
+    fn drift_keeps_latency_bounded_over_a_long_call() {
+        let config = config::get();
+        let target_depth = sample_count(config.output_target_depth);
+        let tolerance = sample_count(config.output_depth_tolerance);
+
+        // A whole hour with the clocks half a millisecond a second apart either way, which
+        // uncorrected would be 1.8s of latency gained or lost
+        for drift_ppm in [-500.0, 500.0] {
+            let (deepest, shallowest) = simulate_drift(drift_ppm, Duration::from_secs(3600));
+
+            // The depth is measured before each frame lands and lags behind the averaging, so it
+            // can wander a couple of frames past the tolerance but never runs dry or away
+            let slack = tolerance + milliseconds(40.0);
+
+            assert!(
+                deepest <= target_depth + slack,
+                "{drift_ppm}ppm reached {deepest}"
+            );
+            assert!(
+                shallowest + slack >= target_depth,
+                "{drift_ppm}ppm fell to {shallowest}"
+            );
+            assert!(shallowest > 0);
+        }
+    }
+
+    #[test]
+    fn writing_while_the_output_retry_is_pending_drops_the_mix() {
+        let mut audio_system = AudioSystem::new(cpal::default_host());
+        let (mic_sender, _) = broadcast::channel(4);
+        let (mixed_sender, mixed_output) = mpsc::channel();
+
+        // As stream_failed leaves it for a busy device, the mic too so no device is touched
+        let retry_at = Some(Instant::now() + Duration::from_secs(60));
+
+        audio_system.output_retry_at = retry_at;
+        audio_system.input_retry_at = retry_at;
+
+        let dropped_before = metrics::OUTPUT_FRAMES_DROPPED.load(Ordering::Relaxed);
+
+        for _ in 0..3 {
+            mixed_sender
+                .send((OutputPurpose::Voice, vec![0.0; milliseconds(20.0)]))
+                .unwrap();
+
+            transfer_audio(&mut audio_system, &mic_sender, &mixed_output);
+        }
+
+        assert!(audio_system.outgoing_audio_buffer.is_none());
+        assert_eq!(
+            metrics::OUTPUT_FRAMES_DROPPED.load(Ordering::Relaxed) - dropped_before,
+            3
+        );
+    }
+}
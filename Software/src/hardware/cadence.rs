@@ -0,0 +1,175 @@
+use std::time::{Duration, Instant};
+
+use crate::config;
+
+// Splits ringing into on and off phases, e.g. 2s on and 4s off, starting from when ringing
+// was switched on. Shared by the solenoid and the emulator's bell so tuning it on a laptop shows
+// what the real bell will do. No phases (or all of them 0) rings the whole time
+pub struct RingCadence {
+    phases: Vec<Duration>,
+    ringing_since: Option<Instant>,
+}
+
+impl RingCadence {
+    pub fn new(phases: Vec<Duration>) -> Self {
+        RingCadence {
+            phases,
+            ringing_since: None,
+        }
+    }
+
+    pub fn from_config() -> Self {
+        Self::new(config::get().ring_cadence.clone())
+    }
+
+    // Switching on while already ringing keeps the cadence going instead of restarting it
+    pub fn set_ringing(&mut self, ringing: bool, now: Instant) {
+        if !ringing {
+            self.ringing_since = None;
+        } else if self.ringing_since.is_none() {
+            self.ringing_since = Some(now);
+        }
+    }
+
+    pub fn is_on(&self, now: Instant) -> bool {
+        let Some(ringing_since) = self.ringing_since else {
+            return false;
+        };
+
+        let cycle: Duration = self.phases.iter().sum();
+
+        if cycle.is_zero() {
+            return true;
+        }
+
+        let mut into_cycle = Duration::from_nanos(
+            (now.saturating_duration_since(ringing_since).as_nanos() % cycle.as_nanos()) as u64,
+        );
+
+        // Even phases are on and odd ones off, an odd number of phases ends on an on phase
+        for (index, phase) in self.phases.iter().enumerate() {
+            if into_cycle < *phase {
+                return index % 2 == 0;
+            }
+
+            into_cycle -= *phase;
+        }
+
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::{Clock, ManualClock};
+
+    // On and off every 100ms for the given time, as # and .
+    fn timeline(cadence: &RingCadence, start: Instant, length: Duration) -> String {
+        (0..length.as_millis() / 100)
+            .map(|step| {
+                if cadence.is_on(start + Duration::from_millis(step as u64 * 100)) {
+                    '#'
+                } else {
+                    '.'
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn double_ring_timeline() {
+        let mut cadence = RingCadence::new(
+            [400, 200, 400, 2000]
+                .into_iter()
+                .map(Duration::from_millis)
+                .collect(),
+        );
+        let start = Instant::now();
+
+        assert_eq!(
+            timeline(&cadence, start, Duration::from_secs(3)),
+            ".".repeat(30)
+        );
+
+        cadence.set_ringing(true, start);
+
+        assert_eq!(
+            timeline(&cadence, start, Duration::from_secs(6)),
+            format!("####..####{0}####..####{0}", ".".repeat(20))
+        );
+    }
+
+    #[test]
+    fn odd_phase_count_ends_on() {
+        let mut cadence = RingCadence::new(
+            [200, 100, 300]
+                .into_iter()
+                .map(Duration::from_millis)
+                .collect(),
+        );
+        let start = Instant::now();
+
+        cadence.set_ringing(true, start);
+
+        assert_eq!(
+            timeline(&cadence, start, Duration::from_millis(1200)),
+            "##.#####.###"
+        );
+    }
+
+    #[test]
+    fn ringing_again_keeps_the_cadence() {
+        let mut cadence = RingCadence::new(
+            [1000, 1000]
+                .into_iter()
+                .map(Duration::from_millis)
+                .collect(),
+        );
+        let start = Instant::now();
+
+        cadence.set_ringing(true, start);
+        cadence.set_ringing(true, start + Duration::from_millis(1500));
+
+        assert!(!cadence.is_on(start + Duration::from_millis(1500)));
+
+        // Switching off and on again starts over
+        cadence.set_ringing(false, start + Duration::from_millis(1500));
+        cadence.set_ringing(true, start + Duration::from_millis(1500));
+
+        assert!(cadence.is_on(start + Duration::from_millis(1500)));
+    }
+
+    #[test]
+    fn no_phases_rings_steadily() {
+        let mut cadence = RingCadence::new(Vec::new());
+        let start = Instant::now();
+
+        cadence.set_ringing(true, start);
+
+        assert_eq!(
+            timeline(&cadence, start, Duration::from_secs(2)),
+            "#".repeat(20)
+        );
+    }
+
+    #[test]
+    fn cadence_follows_the_clock() {
+        let clock = ManualClock::new();
+        let mut cadence = RingCadence::new(vec![Duration::from_secs(2), Duration::from_secs(4)]);
+
+        cadence.set_ringing(true, clock.now());
+
+        let at = |by: Duration| {
+            clock.advance(by);
+
+            cadence.is_on(clock.now())
+        };
+
+        assert!(at(Duration::ZERO));
+        assert!(at(Duration::from_millis(1999)));
+        assert!(!at(Duration::from_millis(1)));
+        assert!(!at(Duration::from_millis(3999)));
+        assert!(at(Duration::from_millis(1)));
+    }
+}
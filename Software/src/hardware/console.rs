@@ -0,0 +1,237 @@
+use std::{
+    io::BufRead,
+    sync::mpsc::{self, Receiver},
+    thread,
+};
+
+#[cfg(feature = "evdev")]
+use crate::hardware::evdev;
+use crate::{
+    clock, config,
+    hardware::{physical, PhoneHardware},
+};
+
+// For a Pi with a USB handset but none of the phone wired up. Real audio and networking, with
+// the hook, dial and PTT typed into stdin one line at a time:
+//   h       lift or hang up the handset
+//   p       hold or release PTT
+//   digits  dial them, if dialing is enabled
+// The bell and caller id are only logged
+pub struct Hardware {
+    lines: Receiver<String>,
+    hook_state: bool,
+    ptt_state: bool,
+    ringing: bool,
+    dialing_enabled: bool,
+    dialed_number: String,
+}
+
+impl PhoneHardware for Hardware {
+    fn create() -> Self {
+        let (line_sender, lines) = mpsc::channel();
+
+        thread::spawn(move || {
+            for line in std::io::stdin().lock().lines() {
+                let Ok(line) = line else {
+                    break;
+                };
+
+                if line_sender.send(line).is_err() {
+                    break;
+                }
+            }
+        });
+
+        println!("Console hardware: type h for the hook, p for PTT, or digits to dial");
+
+        Hardware {
+            lines,
+            hook_state: true,
+            ptt_state: false,
+            ringing: false,
+            dialing_enabled: false,
+            dialed_number: String::new(),
+        }
+    }
+
+    fn update(&mut self) {
+        while let Ok(line) = self.lines.try_recv() {
+            match line.trim() {
+                "h" => {
+                    self.hook_state = !self.hook_state;
+
+                    println!("Hook: {}", if self.hook_state { "on" } else { "off" });
+                }
+                "p" => {
+                    self.ptt_state = !self.ptt_state;
+
+                    println!("PTT: {}", if self.ptt_state { "held" } else { "released" });
+                }
+                digits if !digits.is_empty() && digits.chars().all(|c| c.is_ascii_digit()) => {
+                    if self.dialing_enabled {
+                        self.dialed_number.push_str(digits);
+                    } else {
+                        println!("Dialing is disabled");
+                    }
+                }
+                "" => {}
+                other => println!("Unknown console command {:?}", other),
+            }
+        }
+    }
+
+    fn ring(&mut self, enabled: bool) {
+        if enabled != self.ringing {
+            println!("Bell: {}", if enabled { "ringing" } else { "off" });
+        }
+
+        self.ringing = enabled;
+    }
+
+    fn enable_dialing(&mut self, enabled: bool) {
+        self.dialing_enabled = enabled;
+    }
+
+    fn dialed_number(&mut self) -> &mut String {
+        &mut self.dialed_number
+    }
+
+    fn get_hook_state(&self) -> bool {
+        self.hook_state
+    }
+
+    fn get_ptt_state(&self) -> bool {
+        self.ptt_state
+    }
+
+    fn show_caller_id(&mut self, caller_id: Option<&str>) {
+        if let Some(caller_id) = caller_id {
+            println!("Caller id: {}", caller_id);
+        }
+    }
+
+    // Notices are already logged
+    fn show_notice(&mut self, _notice: Option<&str>) {}
+
+    // Mute changes are already logged
+    fn show_transmitting(&mut self, _transmitting: bool) {}
+}
+
+// The physical phone, or the console when the GPIO isn't usable or gpio_enabled is off. With the
+// evdev feature, an input device takes the GPIO's place when evdev_device is set
+pub enum PhysicalOrConsole {
+    Physical(Box<physical::Hardware>),
+    Console(Hardware),
+    #[cfg(feature = "evdev")]
+    Evdev(evdev::Hardware),
+}
+
+impl PhoneHardware for PhysicalOrConsole {
+    fn create() -> Self {
+        #[cfg(feature = "evdev")]
+        if let Some(path) = &config::get().evdev_device {
+            return match evdev::Hardware::try_create(path, clock::system()) {
+                Ok(hardware) => PhysicalOrConsole::Evdev(hardware),
+                Err(error) => {
+                    println!("{}, falling back to console hardware", error);
+
+                    PhysicalOrConsole::Console(Hardware::create())
+                }
+            };
+        }
+
+        if !config::get().gpio_enabled {
+            return PhysicalOrConsole::Console(Hardware::create());
+        }
+
+        match physical::Hardware::try_create(clock::system()) {
+            Ok(hardware) => PhysicalOrConsole::Physical(Box::new(hardware)),
+            Err(error) => {
+                println!("{}, falling back to console hardware", error);
+
+                PhysicalOrConsole::Console(Hardware::create())
+            }
+        }
+    }
+
+    fn update(&mut self) {
+        match self {
+            PhysicalOrConsole::Physical(hardware) => hardware.update(),
+            PhysicalOrConsole::Console(hardware) => hardware.update(),
+            #[cfg(feature = "evdev")]
+            PhysicalOrConsole::Evdev(hardware) => hardware.update(),
+        }
+    }
+
+    fn ring(&mut self, enabled: bool) {
+        match self {
+            PhysicalOrConsole::Physical(hardware) => hardware.ring(enabled),
+            PhysicalOrConsole::Console(hardware) => hardware.ring(enabled),
+            #[cfg(feature = "evdev")]
+            PhysicalOrConsole::Evdev(hardware) => hardware.ring(enabled),
+        }
+    }
+
+    fn enable_dialing(&mut self, enabled: bool) {
+        match self {
+            PhysicalOrConsole::Physical(hardware) => hardware.enable_dialing(enabled),
+            PhysicalOrConsole::Console(hardware) => hardware.enable_dialing(enabled),
+            #[cfg(feature = "evdev")]
+            PhysicalOrConsole::Evdev(hardware) => hardware.enable_dialing(enabled),
+        }
+    }
+
+    fn dialed_number(&mut self) -> &mut String {
+        match self {
+            PhysicalOrConsole::Physical(hardware) => hardware.dialed_number(),
+            PhysicalOrConsole::Console(hardware) => hardware.dialed_number(),
+            #[cfg(feature = "evdev")]
+            PhysicalOrConsole::Evdev(hardware) => hardware.dialed_number(),
+        }
+    }
+
+    fn get_hook_state(&self) -> bool {
+        match self {
+            PhysicalOrConsole::Physical(hardware) => hardware.get_hook_state(),
+            PhysicalOrConsole::Console(hardware) => hardware.get_hook_state(),
+            #[cfg(feature = "evdev")]
+            PhysicalOrConsole::Evdev(hardware) => hardware.get_hook_state(),
+        }
+    }
+
+    fn get_ptt_state(&self) -> bool {
+        match self {
+            PhysicalOrConsole::Physical(hardware) => hardware.get_ptt_state(),
+            PhysicalOrConsole::Console(hardware) => hardware.get_ptt_state(),
+            #[cfg(feature = "evdev")]
+            PhysicalOrConsole::Evdev(hardware) => hardware.get_ptt_state(),
+        }
+    }
+
+    fn show_caller_id(&mut self, caller_id: Option<&str>) {
+        match self {
+            PhysicalOrConsole::Physical(hardware) => hardware.show_caller_id(caller_id),
+            PhysicalOrConsole::Console(hardware) => hardware.show_caller_id(caller_id),
+            #[cfg(feature = "evdev")]
+            PhysicalOrConsole::Evdev(hardware) => hardware.show_caller_id(caller_id),
+        }
+    }
+
+    fn show_notice(&mut self, notice: Option<&str>) {
+        match self {
+            PhysicalOrConsole::Physical(hardware) => hardware.show_notice(notice),
+            PhysicalOrConsole::Console(hardware) => hardware.show_notice(notice),
+            #[cfg(feature = "evdev")]
+            PhysicalOrConsole::Evdev(hardware) => hardware.show_notice(notice),
+        }
+    }
+
+    fn show_transmitting(&mut self, transmitting: bool) {
+        match self {
+            PhysicalOrConsole::Physical(hardware) => hardware.show_transmitting(transmitting),
+            PhysicalOrConsole::Console(hardware) => hardware.show_transmitting(transmitting),
+            #[cfg(feature = "evdev")]
+            PhysicalOrConsole::Evdev(hardware) => hardware.show_transmitting(transmitting),
+        }
+    }
+}
@@ -0,0 +1,75 @@
+pub type PulseMapping = fn(i32) -> Option<char>;
+
+// North America: one pulse per digit, ten pulses for 0
+pub fn nanp(pulses: i32) -> Option<char> {
+    match pulses {
+        1..=9 => char::from_digit(pulses as u32, 10),
+        10.. => Some('0'),
+        _ => None,
+    }
+}
+
+// Sweden: one pulse for 0, ten pulses for 9
+pub fn sweden(pulses: i32) -> Option<char> {
+    match pulses {
+        1..=10 => char::from_digit((pulses - 1) as u32, 10),
+        _ => None,
+    }
+}
+
+// New Zealand: inverted dial, one pulse for 9, ten pulses for 0
+pub fn new_zealand(pulses: i32) -> Option<char> {
+    match pulses {
+        1..=10 => char::from_digit((10 - pulses) as u32, 10),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dial(mapping: PulseMapping, pulses: impl IntoIterator<Item = i32>) -> Vec<Option<char>> {
+        pulses.into_iter().map(mapping).collect()
+    }
+
+    #[test]
+    fn nanp_maps_ten_pulses_to_zero() {
+        assert_eq!(
+            dial(nanp, 1..=10),
+            "1234567890".chars().map(Some).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn sweden_maps_one_pulse_to_zero() {
+        assert_eq!(
+            dial(sweden, 1..=10),
+            "0123456789".chars().map(Some).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn new_zealand_is_inverted() {
+        assert_eq!(
+            dial(new_zealand, 1..=10),
+            "9876543210".chars().map(Some).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn no_pulses_is_no_digit() {
+        for mapping in [nanp, sweden, new_zealand] {
+            assert_eq!(mapping(0), None);
+            assert_eq!(mapping(-1), None);
+        }
+    }
+
+    // A dial that overshoots still gives NANP's 0, the others have no digit past ten
+    #[test]
+    fn more_than_ten_pulses() {
+        assert_eq!(dial(nanp, [11, 12, 20]), vec![Some('0'); 3]);
+        assert_eq!(dial(sweden, [11, 12, 20]), vec![None; 3]);
+        assert_eq!(dial(new_zealand, [11, 12, 20]), vec![None; 3]);
+    }
+}
@@ -1,11 +1,14 @@
-use std::sync::mpsc;
+use std::{sync::mpsc, time::Instant};
 
-use crate::hardware::PhoneHardware;
+use crate::{
+    config,
+    hardware::{cadence::RingCadence, PhoneHardware},
+};
 
 use druid::{
     theme,
-    widget::{CrossAxisAlignment, Either, Flex, Image, Label, Painter},
-    Color, Data, ExtEventSink, ImageBuf, Lens,
+    widget::{Controller, CrossAxisAlignment, Either, Flex, Image, Label, LineBreaking, Painter},
+    Color, Data, Env, Event, EventCtx, ExtEventSink, ImageBuf, Lens,
 };
 use druid::{AppLauncher, RenderContext, Widget, WidgetExt, WindowDesc};
 
@@ -16,23 +19,71 @@ struct UIState {
     #[data(ignore)]
     dial_sender: mpsc::Sender<u8>,
 
+    // Only ever set from Hardware, clicks ask it to toggle instead
     hook_state: bool,
     #[data(ignore)]
-    hook_state_sender: mpsc::Sender<bool>,
+    hook_toggle_sender: mpsc::Sender<()>,
+
+    ptt_state: bool,
+    #[data(ignore)]
+    ptt_state_sender: mpsc::Sender<bool>,
 
     ringing: bool,
+    caller_id: String,
+    notice: String,
+
+    read_only: bool,
 }
 
 impl UIState {
     fn digit(&mut self, digit: u8) {
-        if self.dialing_enabled {
+        if self.dialing_enabled && !self.read_only {
             let _ = self.dial_sender.send(digit);
         }
     }
 
     fn toggle_hook(&mut self) {
-        self.hook_state = !self.hook_state;
-        let _ = self.hook_state_sender.send(self.hook_state);
+        if self.read_only {
+            return;
+        }
+
+        let _ = self.hook_toggle_sender.send(());
+    }
+
+    fn set_ptt(&mut self, held: bool) {
+        if self.read_only {
+            return;
+        }
+
+        self.ptt_state = held;
+        let _ = self.ptt_state_sender.send(self.ptt_state);
+    }
+}
+
+struct PttController;
+
+impl<W: Widget<UIState>> Controller<UIState, W> for PttController {
+    fn event(
+        &mut self,
+        child: &mut W,
+        ctx: &mut EventCtx,
+        event: &Event,
+        data: &mut UIState,
+        env: &Env,
+    ) {
+        match event {
+            Event::MouseDown(_) => {
+                ctx.set_active(true);
+                data.set_ptt(true);
+            }
+            Event::MouseUp(_) if ctx.is_active() => {
+                ctx.set_active(false);
+                data.set_ptt(false);
+            }
+            _ => {}
+        }
+
+        child.event(ctx, event, data, env);
     }
 }
 
@@ -90,6 +141,29 @@ fn digit_button(digit: u8) -> impl Widget<UIState> {
         .on_click(move |_ctx, data: &mut UIState, _env| data.digit(digit))
 }
 
+fn ptt_button() -> impl Widget<UIState> {
+    let painter = Painter::new(|ctx, data: &UIState, env| {
+        let bounds = ctx.size().to_rect();
+
+        ctx.fill(bounds, &env.get(theme::BACKGROUND_LIGHT));
+
+        if ctx.is_hot() {
+            ctx.stroke(bounds.inset(-0.5), &Color::WHITE, 1.0);
+        }
+
+        if data.ptt_state {
+            ctx.fill(bounds, &env.get(theme::PRIMARY_LIGHT));
+        }
+    });
+
+    Label::new("PTT")
+        .with_text_size(24.)
+        .center()
+        .background(painter)
+        .expand()
+        .controller(PttController)
+}
+
 fn flex_row_3<T: Data>(
     w1: impl Widget<T> + 'static,
     w2: impl Widget<T> + 'static,
@@ -120,6 +194,20 @@ fn ui_builder() -> impl Widget<UIState> {
         .expand_width()
         .padding(5.0);
 
+    let caller_id = Label::new(|data: &String, _env: &_| data.clone())
+        .with_text_size(14.0)
+        .lens(UIState::caller_id)
+        .expand_width()
+        .padding(5.0);
+
+    // Under the caller id, wrapped since a notice can run to a couple of lines
+    let notice = Label::new(|data: &String, _env: &_| data.clone())
+        .with_text_size(12.0)
+        .with_line_break_mode(LineBreaking::WordWrap)
+        .lens(UIState::notice)
+        .expand_width()
+        .padding(5.0);
+
     let bell_data = ImageBuf::from_data(include_bytes!("../../assets/bell.png")).unwrap();
     let bell_ring_data = ImageBuf::from_data(include_bytes!("../../assets/bell-ring.png")).unwrap();
 
@@ -142,7 +230,13 @@ fn ui_builder() -> impl Widget<UIState> {
                     1.0,
                 )
                 .with_spacer(1.0)
-                .with_flex_child(number, 2.0)
+                .with_flex_child(
+                    Flex::column()
+                        .with_child(number)
+                        .with_child(caller_id)
+                        .with_child(notice),
+                    2.0,
+                )
                 .with_spacer(1.0),
             1.0,
         )
@@ -163,18 +257,55 @@ fn ui_builder() -> impl Widget<UIState> {
             1.0,
         )
         .with_spacer(1.0)
-        .with_flex_child(flex_row_2(digit_button(0), call_button()), 1.0)
+        .with_flex_child(
+            if config::get().ptt_enabled {
+                flex_row_3(ptt_button(), digit_button(0), call_button()).boxed()
+            } else {
+                flex_row_2(digit_button(0), call_button()).boxed()
+            },
+            1.0,
+        )
+}
+
+// Where state changes for the window go. They're applied between druid events, in order
+enum UiSink {
+    Druid(ExtEventSink),
+    // Held until the test applies them, like druid would
+    #[cfg(test)]
+    Queued(std::sync::Arc<std::sync::Mutex<Vec<UiUpdate>>>),
+}
+
+#[cfg(test)]
+type UiUpdate = Box<dyn FnOnce(&mut UIState) + Send>;
+
+impl UiSink {
+    fn update(&self, update: impl FnOnce(&mut UIState) + Send + 'static) {
+        match self {
+            UiSink::Druid(event_sink) => event_sink.add_idle_callback(update),
+            #[cfg(test)]
+            UiSink::Queued(updates) => updates.lock().unwrap().push(Box::new(update)),
+        }
+    }
 }
 
 pub struct Hardware {
-    event_sink: ExtEventSink,
+    event_sink: UiSink,
 
     last_dialed_number: String,
     dialed_number: String,
     dial_receiver: mpsc::Receiver<u8>,
 
     hook_state: bool,
-    hook_state_receiver: mpsc::Receiver<bool>,
+    displayed_hook_state: bool,
+    hook_toggle_receiver: mpsc::Receiver<()>,
+
+    ptt_state: bool,
+    ptt_state_receiver: mpsc::Receiver<bool>,
+
+    // The bell icon blinks in the same cadence as the real bell
+    bell_cadence: RingCadence,
+    displayed_ringing: bool,
+
     launcher: Option<force_send_sync::Send<Launcher>>,
 }
 
@@ -193,17 +324,29 @@ impl Hardware {
     pub fn take_gui(&mut self) -> Launcher {
         self.launcher.take().expect("whered the gui go???").unwrap()
     }
-}
 
-impl PhoneHardware for Hardware {
-    fn create() -> Self {
+    // A view that only reflects state pushed to it, ignoring clicks
+    pub fn create_read_only() -> Self {
+        Self::create_view(true)
+    }
+
+    pub fn mirror_hook_state(&mut self, hook_state: bool) {
+        self.hook_state = hook_state;
+    }
+
+    fn create_view(read_only: bool) -> Self {
         let (sender, receiver) = mpsc::channel::<ExtEventSink>();
 
-        let (hook_state_sender, hook_state_receiver) = mpsc::channel::<bool>();
+        let (hook_toggle_sender, hook_toggle_receiver) = mpsc::channel::<()>();
         let (dial_sender, dial_receiver) = mpsc::channel::<u8>();
+        let (ptt_state_sender, ptt_state_receiver) = mpsc::channel::<bool>();
 
         let main_window = WindowDesc::new(ui_builder())
-            .title("Phone Bell")
+            .title(if read_only {
+                "Phone Bell (Mirror)"
+            } else {
+                "Phone Bell"
+            })
             .window_size((300., 500.))
             .resizable(false);
 
@@ -219,29 +362,72 @@ impl PhoneHardware for Hardware {
             dial_sender,
 
             hook_state: true,
-            hook_state_sender,
+            hook_toggle_sender,
+
+            ptt_state: false,
+            ptt_state_sender,
 
             ringing: false,
+            caller_id: String::new(),
+            notice: String::new(),
+
+            read_only,
         };
 
         // let _ = launcher.log_to_console().launch(state);
 
         Hardware {
-            event_sink: receiver.recv().unwrap(),
+            event_sink: UiSink::Druid(receiver.recv().unwrap()),
 
             last_dialed_number: String::new(),
             dialed_number: String::new(),
             dial_receiver,
 
             hook_state: true,
-            hook_state_receiver,
+            displayed_hook_state: true,
+            hook_toggle_receiver,
+
+            ptt_state: false,
+            ptt_state_receiver,
+
+            bell_cadence: RingCadence::from_config(),
+            displayed_ringing: false,
+
             launcher: Some(unsafe { force_send_sync::Send::new(Launcher { launcher, state }) }),
         }
     }
+}
+
+impl PhoneHardware for Hardware {
+    fn create() -> Self {
+        Self::create_view(false)
+    }
 
     fn update(&mut self) {
-        if let Ok(new_hook_state) = self.hook_state_receiver.try_recv() {
-            self.hook_state = new_hook_state;
+        while let Ok(()) = self.hook_toggle_receiver.try_recv() {
+            self.hook_state = !self.hook_state;
+        }
+
+        if self.hook_state != self.displayed_hook_state {
+            let hook_state = self.hook_state;
+
+            self.event_sink.update(move |data| {
+                data.hook_state = hook_state;
+            });
+            self.displayed_hook_state = hook_state;
+        }
+
+        let ringing = self.bell_cadence.is_on(Instant::now());
+
+        if ringing != self.displayed_ringing {
+            self.event_sink.update(move |data| {
+                data.ringing = ringing;
+            });
+            self.displayed_ringing = ringing;
+        }
+
+        while let Ok(new_ptt_state) = self.ptt_state_receiver.try_recv() {
+            self.ptt_state = new_ptt_state;
         }
 
         if let Ok(new_digit) = self.dial_receiver.try_recv() {
@@ -251,26 +437,21 @@ impl PhoneHardware for Hardware {
 
         if self.dialed_number != self.last_dialed_number {
             let new_number = self.dialed_number.clone();
-            self.event_sink
-                .add_idle_callback(move |data: &mut UIState| {
-                    data.dialed_number = new_number;
-                });
+            self.event_sink.update(move |data| {
+                data.dialed_number = new_number;
+            });
             self.last_dialed_number = self.dialed_number.clone();
         }
     }
 
     fn ring(&mut self, enabled: bool) {
-        self.event_sink
-            .add_idle_callback(move |data: &mut UIState| {
-                data.ringing = enabled;
-            });
+        self.bell_cadence.set_ringing(enabled, Instant::now());
     }
 
     fn enable_dialing(&mut self, enabled: bool) {
-        self.event_sink
-            .add_idle_callback(move |data: &mut UIState| {
-                data.dialing_enabled = enabled;
-            });
+        self.event_sink.update(move |data| {
+            data.dialing_enabled = enabled;
+        });
     }
 
     fn dialed_number(&mut self) -> &mut String {
@@ -280,4 +461,96 @@ impl PhoneHardware for Hardware {
     fn get_hook_state(&self) -> bool {
         self.hook_state
     }
+
+    fn get_ptt_state(&self) -> bool {
+        self.ptt_state
+    }
+
+    fn show_caller_id(&mut self, caller_id: Option<&str>) {
+        let caller_id = caller_id.unwrap_or_default().to_string();
+
+        self.event_sink.update(move |data| {
+            data.caller_id = caller_id;
+        });
+    }
+
+    fn show_notice(&mut self, notice: Option<&str>) {
+        let notice = notice.unwrap_or_default().to_string();
+
+        self.event_sink.update(move |data| {
+            data.notice = notice;
+        });
+    }
+
+    fn show_transmitting(&mut self, _transmitting: bool) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::{Arc, Mutex};
+
+    // The emulator with its window state, and the updates it has sent the window so far
+    fn emulator() -> (Hardware, UIState, Arc<Mutex<Vec<UiUpdate>>>) {
+        let mut hardware = Hardware::create();
+        let state = hardware.take_gui().state;
+        let updates = Arc::new(Mutex::new(Vec::new()));
+
+        hardware.event_sink = UiSink::Queued(updates.clone());
+
+        (hardware, state, updates)
+    }
+
+    fn apply(state: &mut UIState, updates: &Mutex<Vec<UiUpdate>>) {
+        for update in updates.lock().unwrap().drain(..) {
+            update(state);
+        }
+    }
+
+    #[test]
+    fn every_hook_toggle_is_displayed() {
+        let (mut hardware, mut state, updates) = emulator();
+        let mut on_hook = true;
+
+        // Several clicks between updates, including a double click that ends where it started
+        for toggles in [3, 2, 1] {
+            for _ in 0..toggles {
+                state.toggle_hook();
+                on_hook = !on_hook;
+            }
+
+            hardware.update();
+            apply(&mut state, &updates);
+
+            assert_eq!(state.hook_state, on_hook);
+            assert_eq!(hardware.get_hook_state(), on_hook);
+        }
+
+        // Six toggles in all, back on-hook
+        assert!(state.hook_state);
+    }
+
+    #[test]
+    fn rapid_toggles_display_the_last_one() {
+        let (mut hardware, mut state, updates) = emulator();
+
+        // The window doesn't get to apply anything until both toggles are in
+        for _ in 0..2 {
+            state.toggle_hook();
+            hardware.update();
+        }
+
+        apply(&mut state, &updates);
+
+        assert!(state.hook_state);
+        assert_eq!(state.hook_state, hardware.get_hook_state());
+
+        state.toggle_hook();
+        hardware.update();
+        apply(&mut state, &updates);
+
+        assert!(!state.hook_state);
+        assert_eq!(state.hook_state, hardware.get_hook_state());
+    }
 }
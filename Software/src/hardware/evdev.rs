@@ -0,0 +1,272 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::{Read, Write},
+    sync::mpsc::{self, Receiver},
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    clock::{self, SharedClock},
+    config,
+    hardware::PhoneHardware,
+};
+
+// struct input_event is a timeval, then the type, code and value
+#[cfg(target_pointer_width = "64")]
+const TIME_LENGTH: usize = 16;
+#[cfg(not(target_pointer_width = "64"))]
+const TIME_LENGTH: usize = 8;
+const EVENT_LENGTH: usize = TIME_LENGTH + 8;
+
+const EV_SYN: u16 = 0x00;
+const EV_KEY: u16 = 0x01;
+const EV_SND: u16 = 0x12;
+const SND_BELL: u16 = 0x01;
+
+// Key value for a held key repeating
+const KEY_REPEAT: i32 = 2;
+
+const DIGITS: [char; 12] = ['0', '1', '2', '3', '4', '5', '6', '7', '8', '9', '*', '#'];
+
+// Counts pulse key presses into a digit, which ends once they stop for the gap
+struct PulseCounter {
+    clock: SharedClock,
+    pulses: i32,
+    last_pulse: Option<Instant>,
+}
+
+impl PulseCounter {
+    fn new(clock: SharedClock) -> Self {
+        Self {
+            clock,
+            pulses: 0,
+            last_pulse: None,
+        }
+    }
+
+    fn pulse(&mut self) {
+        self.pulses += 1;
+        self.last_pulse = Some(self.clock.now());
+    }
+
+    // Returns the pulse count of a digit once the gap has passed since its last pulse
+    fn finished(&mut self, gap: Duration) -> Option<i32> {
+        let last_pulse = self.last_pulse?;
+
+        if self.clock.elapsed_since(last_pulse) < gap {
+            return None;
+        }
+
+        self.last_pulse = None;
+
+        Some(std::mem::take(&mut self.pulses))
+    }
+}
+
+// A phone that shows up as /dev/input/event*, with the keys mapped by the evdev_* config. The
+// hook starts out on-hook until the device reports otherwise. The bell is sent as EV_SND, for
+// devices that have a sounder, otherwise it's only logged
+pub struct Hardware {
+    key_events: Receiver<(u16, bool)>,
+    bell: Option<File>,
+    hook_state: bool,
+    ptt_state: bool,
+    ringing: bool,
+    dialing_enabled: bool,
+    dialed_number: String,
+    pulse_counter: PulseCounter,
+}
+
+impl Hardware {
+    pub fn try_create(path: &str, clock: SharedClock) -> Result<Self, String> {
+        let mut device =
+            File::open(path).map_err(|error| format!("Failed to open {}: {}", path, error))?;
+
+        // Only the bell needs it, plenty of devices don't take writes
+        let bell = OpenOptions::new().write(true).open(path).ok();
+
+        let (key_sender, key_events) = mpsc::channel();
+        let device_path = path.to_string();
+
+        thread::spawn(move || {
+            let mut event = [0; EVENT_LENGTH];
+
+            loop {
+                if let Err(error) = device.read_exact(&mut event) {
+                    println!("Reading {} failed: {}", device_path, error);
+
+                    break;
+                }
+
+                let field = &event[TIME_LENGTH..];
+
+                let kind = u16::from_ne_bytes([field[0], field[1]]);
+                let code = u16::from_ne_bytes([field[2], field[3]]);
+                let value = i32::from_ne_bytes([field[4], field[5], field[6], field[7]]);
+
+                if kind != EV_KEY || value == KEY_REPEAT {
+                    continue;
+                }
+
+                if key_sender.send((code, value != 0)).is_err() {
+                    break;
+                }
+            }
+        });
+
+        println!("Evdev hardware on {}", path);
+
+        Ok(Hardware {
+            key_events,
+            bell,
+            hook_state: true,
+            ptt_state: false,
+            ringing: false,
+            dialing_enabled: false,
+            dialed_number: String::new(),
+            pulse_counter: PulseCounter::new(clock),
+        })
+    }
+
+    fn write_event(device: &mut File, kind: u16, code: u16, value: i32) -> std::io::Result<()> {
+        let mut event = [0; EVENT_LENGTH];
+
+        event[TIME_LENGTH..TIME_LENGTH + 2].copy_from_slice(&kind.to_ne_bytes());
+        event[TIME_LENGTH + 2..TIME_LENGTH + 4].copy_from_slice(&code.to_ne_bytes());
+        event[TIME_LENGTH + 4..].copy_from_slice(&value.to_ne_bytes());
+
+        device.write_all(&event)
+    }
+}
+
+impl PhoneHardware for Hardware {
+    fn create() -> Self {
+        let path = config::get()
+            .evdev_device
+            .as_deref()
+            .unwrap_or_else(|| panic!("evdev_device isn't set"));
+
+        Self::try_create(path, clock::system()).unwrap_or_else(|error| panic!("{}", error))
+    }
+
+    fn update(&mut self) {
+        let config = config::get();
+
+        while let Ok((code, pressed)) = self.key_events.try_recv() {
+            if code == config.evdev_hook_key {
+                self.hook_state = pressed == config.evdev_hook_inverted;
+            } else if Some(code) == config.evdev_ptt_key {
+                self.ptt_state = pressed;
+            } else if Some(code) == config.evdev_pulse_key {
+                if pressed {
+                    self.pulse_counter.pulse();
+                }
+            } else if pressed {
+                let Some(index) = config.evdev_digit_keys.iter().position(|key| *key == code)
+                else {
+                    continue;
+                };
+
+                if self.dialing_enabled {
+                    self.dialed_number.push(DIGITS[index]);
+                }
+            }
+        }
+
+        let finished_pulses = self.pulse_counter.finished(config.evdev_pulse_digit_gap);
+
+        if let Some(pulses) = finished_pulses.filter(|_| self.dialing_enabled) {
+            if let Some(digit) = config.dial_mapping.pulse_mapping()(pulses) {
+                self.dialed_number.push(digit);
+            }
+        }
+    }
+
+    fn ring(&mut self, enabled: bool) {
+        if enabled == self.ringing {
+            return;
+        }
+
+        self.ringing = enabled;
+
+        let Some(device) = &mut self.bell else {
+            println!("Bell: {}", if enabled { "ringing" } else { "off" });
+
+            return;
+        };
+
+        let result = Self::write_event(device, EV_SND, SND_BELL, enabled as i32)
+            .and_then(|_| Self::write_event(device, EV_SYN, 0, 0));
+
+        if let Err(error) = result {
+            println!(
+                "Ringing through the input device failed, only logging it: {}",
+                error
+            );
+
+            self.bell = None;
+        }
+    }
+
+    fn enable_dialing(&mut self, enabled: bool) {
+        self.dialing_enabled = enabled;
+    }
+
+    fn dialed_number(&mut self) -> &mut String {
+        &mut self.dialed_number
+    }
+
+    fn get_hook_state(&self) -> bool {
+        self.hook_state
+    }
+
+    fn get_ptt_state(&self) -> bool {
+        self.ptt_state
+    }
+
+    fn show_caller_id(&mut self, caller_id: Option<&str>) {
+        if let Some(caller_id) = caller_id {
+            println!("Caller id: {}", caller_id);
+        }
+    }
+
+    // Notices are already logged
+    fn show_notice(&mut self, _notice: Option<&str>) {}
+
+    fn show_transmitting(&mut self, _transmitting: bool) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+
+    #[test]
+    fn digit_ends_once_the_pulses_stop_for_the_gap() {
+        let clock = ManualClock::new();
+        let mut pulse_counter = PulseCounter::new(clock.clone());
+        let gap = Duration::from_millis(300);
+
+        assert_eq!(pulse_counter.finished(gap), None);
+
+        // Pulses closer together than the gap all count toward one digit
+        for _ in 0..4 {
+            pulse_counter.pulse();
+            clock.advance(gap - Duration::from_millis(1));
+
+            assert_eq!(pulse_counter.finished(gap), None);
+        }
+
+        clock.advance(Duration::from_millis(1));
+
+        assert_eq!(pulse_counter.finished(gap), Some(4));
+        assert_eq!(pulse_counter.finished(gap), None);
+
+        // The next digit counts from zero
+        pulse_counter.pulse();
+        clock.advance(gap);
+
+        assert_eq!(pulse_counter.finished(gap), Some(1));
+    }
+}
@@ -0,0 +1,142 @@
+use std::sync::mpsc;
+
+use super::audio::{
+    pad_remainder, take_frames, AudioIo, MixedSamples, OutputPurpose, StreamReadError,
+    StreamWriteError,
+};
+
+// Stands in for AudioSystem: samples sent to the injector are read as mic input, cut into frames
+// the same way, and everything written for playback comes out of the capture receiver
+pub struct InjectedAudio {
+    injected_receiver: mpsc::Receiver<Vec<f32>>,
+    captured_sender: mpsc::Sender<MixedSamples>,
+    sample_buffer: Vec<f32>,
+}
+
+impl InjectedAudio {
+    pub fn create() -> (
+        InjectedAudio,
+        mpsc::Sender<Vec<f32>>,
+        mpsc::Receiver<MixedSamples>,
+    ) {
+        let (injected_sender, injected_receiver) = mpsc::channel();
+        let (captured_sender, captured_receiver) = mpsc::channel();
+
+        (
+            InjectedAudio {
+                injected_receiver,
+                captured_sender,
+                sample_buffer: Vec::new(),
+            },
+            injected_sender,
+            captured_receiver,
+        )
+    }
+}
+
+impl AudioIo for InjectedAudio {
+    fn read_next_frames(&mut self) -> Result<Vec<Vec<f32>>, StreamReadError> {
+        while let Ok(samples) = self.injected_receiver.try_recv() {
+            self.sample_buffer.extend(samples);
+        }
+
+        Ok(take_frames(&mut self.sample_buffer))
+    }
+
+    fn flush_remainder(&mut self) -> Option<Vec<f32>> {
+        pad_remainder(&mut self.sample_buffer)
+    }
+
+    fn write_next_samples(
+        &mut self,
+        samples: &[f32],
+        purpose: OutputPurpose,
+    ) -> Result<(), StreamWriteError> {
+        self.captured_sender
+            .send((purpose, samples.to_vec()))
+            .map_err(|_| StreamWriteError::WriteFailed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{f32::consts::TAU, time::Duration};
+
+    use tokio::sync::broadcast;
+
+    use super::*;
+    use crate::{
+        config::SAMPLE_RATE,
+        hardware::{
+            audio::{transfer_audio, MixerMessage},
+            tone,
+        },
+        network::rtc::{tests::loopback_pair, MuteState},
+    };
+
+    const UNMUTED: MuteState = MuteState {
+        input: false,
+        output: false,
+    };
+
+    // How much of a frequency is in the samples, as the amplitude of a sine at it
+    fn amplitude_at(samples: &[f32], frequency: f32) -> f32 {
+        let (real, imaginary) =
+            samples
+                .iter()
+                .enumerate()
+                .fold((0.0, 0.0), |(real, imaginary), (index, sample)| {
+                    let phase = TAU * frequency * index as f32 / SAMPLE_RATE as f32;
+
+                    (
+                        real + sample * phase.cos(),
+                        imaginary + sample * phase.sin(),
+                    )
+                });
+
+        2.0 * (real * real + imaginary * imaginary).sqrt() / samples.len() as f32
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn injected_tone_comes_out_of_the_other_phone() {
+        let (mut audio, injector, _) = InjectedAudio::create();
+        let (mic_in, _) = broadcast::channel(64);
+        let (_, mixed_output) = mpsc::channel();
+        let (mixer_out, mixer_in) = mpsc::channel();
+
+        let _call = loopback_pair(
+            (mpsc::channel().0, mic_in.clone(), UNMUTED),
+            (mixer_out, broadcast::channel(16).0, UNMUTED),
+        )
+        .await;
+
+        // Fed in as the mic would, a frame's worth at a time
+        let tone = tone::sine(&[1000.0], Duration::from_secs(1), 0.5);
+
+        for chunk in tone.chunks(SAMPLE_RATE as usize / 50) {
+            injector.send(chunk.to_vec()).unwrap();
+            transfer_audio(&mut audio, &mic_in, &mixed_output);
+
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        let received: Vec<f32> = mixer_in
+            .try_iter()
+            .flat_map(|message| match message {
+                MixerMessage::Samples(_, _, samples) => samples,
+                _ => Vec::new(),
+            })
+            .collect();
+
+        assert!(received.len() > SAMPLE_RATE as usize / 2);
+
+        // Opus takes a few frames to settle, and the call picks up partway into the tone, so
+        // only the middle is judged
+        let settled = &received[received.len() / 4..received.len() * 3 / 4];
+
+        assert!(amplitude_at(settled, 1000.0) > 0.3);
+        assert!(amplitude_at(settled, 1500.0) < 0.05);
+    }
+}
@@ -0,0 +1,66 @@
+use crate::hardware::{emulated, physical, PhoneHardware};
+
+// Physical hardware with a read-only emulator window mirroring its state, for field setup
+pub struct Hardware {
+    physical: physical::Hardware,
+    view: emulated::Hardware,
+}
+
+impl Hardware {
+    pub fn take_gui(&mut self) -> emulated::Launcher {
+        self.view.take_gui()
+    }
+}
+
+impl PhoneHardware for Hardware {
+    fn create() -> Self {
+        Hardware {
+            physical: physical::Hardware::create(),
+            view: emulated::Hardware::create_read_only(),
+        }
+    }
+
+    fn update(&mut self) {
+        self.physical.update();
+
+        self.view.mirror_hook_state(self.physical.get_hook_state());
+        self.view
+            .dialed_number()
+            .clone_from(self.physical.dialed_number());
+        self.view.update();
+    }
+
+    fn ring(&mut self, enabled: bool) {
+        self.physical.ring(enabled);
+        self.view.ring(enabled);
+    }
+
+    fn enable_dialing(&mut self, enabled: bool) {
+        self.physical.enable_dialing(enabled);
+        self.view.enable_dialing(enabled);
+    }
+
+    fn dialed_number(&mut self) -> &mut String {
+        self.physical.dialed_number()
+    }
+
+    fn get_hook_state(&self) -> bool {
+        self.physical.get_hook_state()
+    }
+
+    fn get_ptt_state(&self) -> bool {
+        self.physical.get_ptt_state()
+    }
+
+    fn show_caller_id(&mut self, caller_id: Option<&str>) {
+        self.view.show_caller_id(caller_id);
+    }
+
+    fn show_notice(&mut self, notice: Option<&str>) {
+        self.view.show_notice(notice);
+    }
+
+    fn show_transmitting(&mut self, transmitting: bool) {
+        self.physical.show_transmitting(transmitting);
+    }
+}
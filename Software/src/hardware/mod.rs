@@ -1,8 +1,19 @@
 pub mod audio;
-#[cfg(not(feature = "real"))]
+pub mod cadence;
+#[cfg(all(feature = "real", not(feature = "mirror")))]
+pub mod console;
+pub mod dial;
+#[cfg(any(not(feature = "real"), feature = "mirror"))]
 pub mod emulated;
+#[cfg(all(feature = "evdev", not(feature = "mirror")))]
+pub mod evdev;
+#[cfg(any(test, feature = "audio_injection"))]
+pub mod injected;
+#[cfg(feature = "mirror")]
+pub mod mirrored;
 #[cfg(feature = "real")]
 pub mod physical;
+pub mod tone;
 
 pub trait PhoneHardware {
     fn create() -> Self;
@@ -16,4 +27,14 @@ pub trait PhoneHardware {
     fn dialed_number(&mut self) -> &mut String;
 
     fn get_hook_state(&self) -> bool;
+
+    fn get_ptt_state(&self) -> bool;
+
+    fn show_caller_id(&mut self, caller_id: Option<&str>);
+
+    // A notice from the server, already cleaned up and cut to length. None clears it
+    fn show_notice(&mut self, notice: Option<&str>);
+
+    // Whether the mic is actually going out to a peer, for a privacy light
+    fn show_transmitting(&mut self, transmitting: bool);
 }
@@ -2,16 +2,73 @@ use std::time::{Duration, Instant};
 
 use debouncr::{debounce_4, Debouncer, Repeat4};
 
-use crate::hardware::PhoneHardware;
+use crate::{
+    clock::{self, SharedClock},
+    hardware::{cadence::RingCadence, PhoneHardware},
+};
 
 use rppal::gpio::{Gpio, InputPin, Level, OutputPin};
 
-use crate::config::{
-    BELL_SOLENOID_FORWARD_PIN, BELL_SOLENOID_REVERSE_PIN, DIAL_LATCH_PIN, DIAL_PULSE_PIN,
-    HOOK_SWITCH_PIN,
-};
+use crate::config;
+
+// Counts pulses while the dial latch is closed and hands them over as a digit once it opens. A
+// latch closed past dial_latch_max_duration is stuck, its pulses are discarded and nothing
+// counts until it opens again
+struct PulseDecoder {
+    last_pulse_state: bool,
+    pulses: i32,
+    // When the latch closed, None while it's open
+    latch_since: Option<Instant>,
+    latch_stuck: bool,
+}
+
+impl PulseDecoder {
+    fn new() -> Self {
+        Self {
+            last_pulse_state: false,
+            pulses: 0,
+            latch_since: None,
+            latch_stuck: false,
+        }
+    }
+
+    // Takes the debounced latch and pulse states, returns the pulse count of a finished digit
+    fn update(&mut self, latch_state: bool, pulse_state: bool, now: Instant) -> Option<i32> {
+        let mut finished = None;
+
+        if latch_state {
+            let latch_since = *self.latch_since.get_or_insert(now);
+
+            if !self.latch_stuck
+                && now.duration_since(latch_since) > config::get().dial_latch_max_duration
+            {
+                println!("Dial latch stuck closed, discarding {} pulses", self.pulses);
+
+                self.latch_stuck = true;
+                self.pulses = 0;
+            }
+
+            if !self.latch_stuck && self.last_pulse_state != pulse_state && pulse_state {
+                self.pulses += 1;
+            }
+        } else if self.latch_since.take().is_some() && self.latch_stuck {
+            println!("Dial latch released");
+
+            self.latch_stuck = false;
+        } else if self.pulses > 0 {
+            finished = Some(self.pulses);
+
+            self.pulses = 0;
+        }
+
+        self.last_pulse_state = pulse_state;
+
+        finished
+    }
+}
 
 pub struct Hardware {
+    clock: SharedClock,
     last_update_instant: Instant,
 
     gpio_read_timer: Duration,
@@ -26,47 +83,51 @@ pub struct Hardware {
     dial_pulse: InputPin,
     dial_pulse_debounce: Debouncer<u8, Repeat4>,
 
+    // Only claimed with ptt_enabled, so the pin is free for something else otherwise
+    ptt_button: Option<InputPin>,
+    ptt_button_debounce: Debouncer<u8, Repeat4>,
+
     bell_solenoid_forward: OutputPin,
     bell_solenoid_reverse: OutputPin,
 
-    ringing_bell: bool,
+    // Lit while the mic is live, if one is wired up
+    privacy_light: Option<OutputPin>,
+
+    bell_cadence: RingCadence,
     bell_ring_timer: Duration,
     current_bell_signal: bool,
 
-    last_dial_pulse_state: bool,
+    pulse_decoder: PulseDecoder,
     dialing_enabled: bool,
     dialed_number: String,
-    dial_pulses: i32,
 }
 
-impl PhoneHardware for Hardware {
-    fn create() -> Self {
-        let Ok(gpio) = Gpio::new() else {
-            panic!("Failed to initialize GPIO")
-        };
-
-        let Ok(hook_switch) = gpio.get(HOOK_SWITCH_PIN) else {
-            panic!("Failed to get pin")
-        };
-
-        let Ok(dial_latch) = gpio.get(DIAL_LATCH_PIN) else {
-            panic!("Failed to get pin")
-        };
+impl Hardware {
+    // Errs instead of panicking when the GPIO or a pin isn't there, see hardware::console
+    pub fn try_create(clock: SharedClock) -> Result<Self, String> {
+        let config = config::get();
 
-        let Ok(dial_pulse) = gpio.get(DIAL_PULSE_PIN) else {
-            panic!("Failed to get pin")
-        };
-
-        let Ok(bell_solenoid_forward) = gpio.get(BELL_SOLENOID_FORWARD_PIN) else {
-            panic!("Failed to get pin")
-        };
+        let gpio = Gpio::new().map_err(|error| format!("Failed to initialize GPIO: {}", error))?;
 
-        let Ok(bell_solenoid_reverse) = gpio.get(BELL_SOLENOID_REVERSE_PIN) else {
-            panic!("Failed to get pin")
+        let pin = |number: u8| {
+            gpio.get(number)
+                .map_err(|error| format!("Failed to get pin {}: {}", number, error))
         };
 
-        Hardware {
-            last_update_instant: Instant::now(),
+        let hook_switch = pin(config.hook_switch_pin)?;
+        let dial_latch = pin(config.dial_latch_pin)?;
+        let dial_pulse = pin(config.dial_pulse_pin)?;
+        let ptt_button = config
+            .ptt_enabled
+            .then(|| pin(config.ptt_button_pin))
+            .transpose()?;
+        let bell_solenoid_forward = pin(config.bell_solenoid_forward_pin)?;
+        let bell_solenoid_reverse = pin(config.bell_solenoid_reverse_pin)?;
+        let privacy_light = config.privacy_light_pin.map(pin).transpose()?;
+
+        Ok(Hardware {
+            last_update_instant: clock.now(),
+            clock,
 
             gpio_read_timer: Duration::ZERO,
 
@@ -80,22 +141,32 @@ impl PhoneHardware for Hardware {
             dial_pulse: dial_pulse.into_input(),
             dial_pulse_debounce: debounce_4(false),
 
+            ptt_button: ptt_button.map(|pin| pin.into_input_pullup()),
+            ptt_button_debounce: debounce_4(false),
+
             bell_solenoid_forward: bell_solenoid_forward.into_output(),
             bell_solenoid_reverse: bell_solenoid_reverse.into_output(),
 
-            ringing_bell: false,
+            privacy_light: privacy_light.map(|pin| pin.into_output_low()),
+
+            bell_cadence: RingCadence::from_config(),
             bell_ring_timer: Duration::ZERO,
             current_bell_signal: false,
 
-            last_dial_pulse_state: false,
+            pulse_decoder: PulseDecoder::new(),
             dialing_enabled: false,
             dialed_number: String::new(),
-            dial_pulses: 0,
-        }
+        })
+    }
+}
+
+impl PhoneHardware for Hardware {
+    fn create() -> Self {
+        Self::try_create(clock::system()).unwrap_or_else(|error| panic!("{}", error))
     }
 
     fn update(&mut self) {
-        let now = Instant::now();
+        let now = self.clock.now();
 
         let time_delta = now.duration_since(self.last_update_instant);
 
@@ -112,12 +183,16 @@ impl PhoneHardware for Hardware {
 
             self.dial_latch_debounce.update(self.dial_latch.is_high());
             self.dial_pulse_debounce.update(self.dial_pulse.is_low());
+
+            if let Some(ptt_button) = &self.ptt_button {
+                self.ptt_button_debounce.update(ptt_button.is_low());
+            }
         }
 
         if self.bell_ring_timer >= Duration::from_millis(50) {
             self.bell_ring_timer = Duration::ZERO;
 
-            self.current_bell_signal = !self.current_bell_signal & self.ringing_bell;
+            self.current_bell_signal = !self.current_bell_signal & self.bell_cadence.is_on(now);
 
             if self.current_bell_signal {
                 self.bell_solenoid_forward.set_high();
@@ -128,28 +203,21 @@ impl PhoneHardware for Hardware {
             }
         }
 
-        let dial_latch_state = self.dial_latch_debounce.is_high();
-        let dial_pulse_state = self.dial_pulse_debounce.is_high();
+        let finished_pulses = self.pulse_decoder.update(
+            self.dial_latch_debounce.is_high(),
+            self.dial_pulse_debounce.is_high(),
+            now,
+        );
 
-        if dial_latch_state {
-            if self.last_dial_pulse_state != dial_pulse_state && dial_pulse_state {
-                self.dial_pulses += 1;
-            }
-        } else if self.dial_pulses > 0 {
-            if self.dial_pulses >= 10 && self.dialing_enabled {
-                self.dialed_number += "0";
-            } else if self.dialing_enabled {
-                self.dialed_number += &self.dial_pulses.to_string();
+        if let Some(pulses) = finished_pulses.filter(|_| self.dialing_enabled) {
+            if let Some(digit) = config::get().dial_mapping.pulse_mapping()(pulses) {
+                self.dialed_number.push(digit);
             }
-
-            self.dial_pulses = 0;
         }
-
-        self.last_dial_pulse_state = dial_pulse_state;
     }
 
     fn ring(&mut self, enabled: bool) {
-        self.ringing_bell = enabled;
+        self.bell_cadence.set_ringing(enabled, self.clock.now());
     }
 
     fn enable_dialing(&mut self, enabled: bool) {
@@ -163,4 +231,76 @@ impl PhoneHardware for Hardware {
     fn get_hook_state(&self) -> bool {
         self.hook_switch_debounce.is_high()
     }
+
+    fn get_ptt_state(&self) -> bool {
+        self.ptt_button_debounce.is_high()
+    }
+
+    fn show_caller_id(&mut self, _caller_id: Option<&str>) {}
+
+    fn show_notice(&mut self, _notice: Option<&str>) {}
+
+    fn show_transmitting(&mut self, transmitting: bool) {
+        if let Some(privacy_light) = &mut self.privacy_light {
+            privacy_light.write(transmitting.into());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Steps the decoder through a pulse every 100ms, as a dial turning at 10 pulses a second
+    fn dial_pulses(
+        decoder: &mut PulseDecoder,
+        pulses: usize,
+        now: &mut Instant,
+    ) -> Vec<Option<i32>> {
+        let mut finished = Vec::new();
+
+        for _ in 0..pulses {
+            for pulse_state in [true, false] {
+                *now += Duration::from_millis(50);
+
+                finished.push(decoder.update(true, pulse_state, *now));
+            }
+        }
+
+        finished
+    }
+
+    #[test]
+    fn released_latch_finishes_the_digit() {
+        let mut decoder = PulseDecoder::new();
+        let mut now = Instant::now();
+
+        assert!(dial_pulses(&mut decoder, 3, &mut now)
+            .iter()
+            .all(Option::is_none));
+        assert_eq!(decoder.update(false, false, now), Some(3));
+        assert_eq!(decoder.update(false, false, now), None);
+    }
+
+    #[test]
+    fn stuck_latch_discards_its_pulses() {
+        let mut decoder = PulseDecoder::new();
+        let mut now = Instant::now();
+
+        let max_duration = config::get().dial_latch_max_duration;
+        let pulses = (max_duration.as_millis() / 100) as usize + 20;
+
+        // Never released, so nothing ever comes out
+        assert!(dial_pulses(&mut decoder, pulses, &mut now)
+            .iter()
+            .all(Option::is_none));
+        assert_eq!(decoder.pulses, 0);
+
+        // Opening it only clears the fault, the next digit dials normally
+        assert_eq!(decoder.update(false, false, now), None);
+
+        dial_pulses(&mut decoder, 2, &mut now);
+
+        assert_eq!(decoder.update(false, false, now), Some(2));
+    }
 }
@@ -0,0 +1,152 @@
+use std::{f32::consts::TAU, time::Duration};
+
+use crate::config::SAMPLE_RATE;
+
+pub fn sine(frequencies: &[f32], duration: Duration, amplitude: f32) -> Vec<f32> {
+    let sample_count = (duration.as_secs_f32() * SAMPLE_RATE as f32) as usize;
+    let amplitude = amplitude / frequencies.len().max(1) as f32;
+
+    (0..sample_count)
+        .map(|index| {
+            let time = index as f32 / SAMPLE_RATE as f32;
+
+            frequencies
+                .iter()
+                .map(|frequency| (TAU * frequency * time).sin() * amplitude)
+                .sum()
+        })
+        .collect()
+}
+
+// Touch tones for the digits, 100ms each with 100ms gaps. Anything but 0-9, * and # is skipped
+pub fn dtmf(digits: &str) -> Vec<f32> {
+    let mut samples = Vec::new();
+
+    for digit in digits.chars() {
+        let (low, high) = match digit {
+            '1' => (697.0, 1209.0),
+            '2' => (697.0, 1336.0),
+            '3' => (697.0, 1477.0),
+            '4' => (770.0, 1209.0),
+            '5' => (770.0, 1336.0),
+            '6' => (770.0, 1477.0),
+            '7' => (852.0, 1209.0),
+            '8' => (852.0, 1336.0),
+            '9' => (852.0, 1477.0),
+            '*' => (941.0, 1209.0),
+            '0' => (941.0, 1336.0),
+            '#' => (941.0, 1477.0),
+            _ => continue,
+        };
+
+        samples.extend(sine(&[low, high], Duration::from_millis(100), 0.4));
+        samples.extend(silence(Duration::from_millis(100)));
+    }
+
+    samples
+}
+
+pub fn hold_music() -> Vec<f32> {
+    let mut samples = Vec::new();
+
+    for frequency in [440.0, 554.37, 659.25] {
+        samples.extend(sine(&[frequency], Duration::from_millis(300), 0.1));
+    }
+
+    samples.extend(silence(Duration::from_secs(1)));
+
+    samples
+}
+
+pub fn silence(duration: Duration) -> Vec<f32> {
+    vec![0.0; (duration.as_secs_f32() * SAMPLE_RATE as f32) as usize]
+}
+
+// Fills DTX gaps with noise at the level the far end's background was at, so the line doesn't
+// sound dead while they're quiet
+pub struct ComfortNoise {
+    noise_floor: f32,
+    max_level: f32,
+    seed: u32,
+}
+
+impl ComfortNoise {
+    pub fn new(max_level: f32) -> Self {
+        Self {
+            noise_floor: max_level,
+            max_level,
+            seed: 0x9E37_79B9,
+        }
+    }
+
+    // Tracks the quietest recent frames, creeping back up slowly so a loud call doesn't stick
+    pub fn observe(&mut self, samples: &[f32]) {
+        if samples.is_empty() {
+            return;
+        }
+
+        let rms = (samples.iter().map(|sample| sample * sample).sum::<f32>()
+            / samples.len() as f32)
+            .sqrt();
+
+        self.noise_floor = if rms < self.noise_floor {
+            rms
+        } else {
+            self.noise_floor * 1.02
+        }
+        .min(self.max_level);
+    }
+
+    pub fn generate(&mut self, sample_count: usize) -> Vec<f32> {
+        (0..sample_count)
+            .map(|_| {
+                // xorshift, quality doesn't matter for background hiss
+                self.seed ^= self.seed << 13;
+                self.seed ^= self.seed >> 17;
+                self.seed ^= self.seed << 5;
+
+                (self.seed as f32 / u32::MAX as f32 * 2.0 - 1.0) * self.noise_floor
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rms(samples: &[f32]) -> f32 {
+        (samples.iter().map(|sample| sample * sample).sum::<f32>() / samples.len() as f32).sqrt()
+    }
+
+    #[test]
+    fn comfort_noise_fills_a_dtx_gap_at_the_noise_floor() {
+        let mut comfort_noise = ComfortNoise::new(0.003);
+
+        // The far end's background just before it went quiet
+        let background = sine(&[300.0], Duration::from_millis(20), 0.002);
+        let noise_floor = rms(&background);
+
+        comfort_noise.observe(&background);
+
+        let gap = comfort_noise.generate(960);
+
+        assert_eq!(gap.len(), 960);
+        assert!(rms(&gap) > noise_floor / 4.0);
+        assert!(gap.iter().all(|sample| sample.abs() <= noise_floor));
+    }
+
+    #[test]
+    fn comfort_noise_stays_under_its_level() {
+        let mut comfort_noise = ComfortNoise::new(0.003);
+
+        for _ in 0..100 {
+            comfort_noise.observe(&sine(&[300.0], Duration::from_millis(20), 0.8));
+        }
+
+        let gap = comfort_noise.generate(960);
+
+        assert!(rms(&gap) > 0.0);
+        assert!(gap.iter().all(|sample| sample.abs() <= 0.003));
+    }
+}
@@ -1,71 +1,316 @@
+pub mod clock;
 pub mod config;
+pub mod metrics;
 pub mod network;
 pub mod ui;
+pub mod watchdog;
 
 pub mod hardware;
 
-use std::{str::FromStr, thread};
+use std::{
+    panic::{self, AssertUnwindSafe},
+    str::FromStr,
+    sync::{atomic::Ordering, mpsc},
+    thread,
+    time::{Duration, Instant},
+};
 
-use hardware::audio::{AudioMixer, AudioSystem};
-use network::{rtc::PhoneRTC, socket::PhoneSocket};
+use hardware::{
+    audio::{transfer_audio, AudioCommand, AudioEvent, AudioMixer, AudioSystem},
+    PhoneHardware,
+};
+use network::{
+    rtc::{PhoneRTC, RTCCommand},
+    socket::PhoneSocket,
+    Sound,
+};
 
 use dotenv::dotenv;
-use tokio::sync::broadcast;
+use serde::Deserialize;
+use tokio::{
+    signal::unix::{signal, SignalKind},
+    sync::broadcast,
+};
 
-use crate::ui::ui_entry;
+use crate::{ui::ui_entry, watchdog::Heartbeat};
 
+// How long the audio takes to fade to silence on shutdown
+const SHUTDOWN_FADE_OUT: Duration = Duration::from_millis(50);
+// Covers the fade and the output draining, a stuck audio thread doesn't hold up the rest
+const AUDIO_SHUTDOWN_TIMEOUT: Duration = Duration::from_millis(500);
+
+#[derive(Deserialize, Debug, Clone, Copy)]
 pub enum PhoneSide {
     Inside,
     Outside,
 }
 
 impl FromStr for PhoneSide {
-    type Err = ();
+    type Err = String;
 
     fn from_str(input: &str) -> Result<PhoneSide, Self::Err> {
         match input {
             "Inside" => Ok(PhoneSide::Inside),
             "Outside" => Ok(PhoneSide::Outside),
-            _ => Err(()),
+            _ => Err(format!("expected Inside or Outside, got {:?}", input)),
         }
     }
 }
 
+async fn shutdown_signal() {
+    let Ok(mut terminate) = signal(SignalKind::terminate()) else {
+        let _ = tokio::signal::ctrl_c().await;
+
+        return;
+    };
+
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {}
+        _ = terminate.recv() => {}
+    }
+}
+
+// Plays dialtone off-hook, prints dialed digits and rings on a timer, so a new phone can be
+// checked before it has credentials
+async fn test_mode_entry() {
+    println!("Test mode, networking is disabled");
+
+    #[cfg(not(feature = "real"))]
+    let (mut hardware, ui) = {
+        let mut hardware = hardware::emulated::Hardware::create();
+        let ui = hardware.take_gui();
+        (hardware, ui)
+    };
+    #[cfg(all(feature = "real", not(feature = "mirror")))]
+    let mut hardware = hardware::console::PhysicalOrConsole::create();
+    #[cfg(feature = "mirror")]
+    let (mut hardware, ui) = {
+        let mut hardware = hardware::mirrored::Hardware::create();
+        let ui = hardware.take_gui();
+        (hardware, ui)
+    };
+
+    let (_stream, sink) = ui::local_sound_sink();
+
+    hardware.ring(false);
+    hardware.enable_dialing(true);
+
+    let config = config::get();
+
+    #[allow(unused_variables)]
+    let test_process_join_handle = tokio::spawn(async move {
+        let mut last_hook_state = true;
+        let mut last_ring = Instant::now();
+        let mut ringing = false;
+
+        loop {
+            hardware.update();
+
+            if hardware.get_hook_state() != last_hook_state {
+                last_hook_state = hardware.get_hook_state();
+
+                println!("Hook: {}", if last_hook_state { "on" } else { "off" });
+
+                sink.clear();
+
+                if last_hook_state {
+                    sink.pause();
+                } else {
+                    if let Some(source) = ui::looped_sound(&Sound::Dialtone) {
+                        sink.append(ui::on_tone_channels(source));
+                        sink.play();
+                    }
+                }
+            }
+
+            if !hardware.dialed_number().is_empty() {
+                println!("Dialed: {}", std::mem::take(hardware.dialed_number()));
+            }
+
+            if ringing && last_ring.elapsed() > config.ring_test_duration {
+                ringing = false;
+
+                hardware.ring(false);
+            } else if !ringing
+                && last_hook_state
+                && last_ring.elapsed() > config.test_mode_ring_interval
+            {
+                ringing = true;
+                last_ring = Instant::now();
+
+                hardware.ring(true);
+            }
+        }
+    });
+
+    #[cfg(all(feature = "real", not(feature = "mirror")))]
+    {
+        let _ = test_process_join_handle.await;
+    }
+    #[cfg(any(not(feature = "real"), feature = "mirror"))]
+    {
+        ui.go();
+    }
+}
+
 #[tokio::main]
 async fn main() {
     dotenv().ok();
 
-    let phone_side = PhoneSide::from_str(&std::env::var("PHONE_SIDE").unwrap()).unwrap();
+    metrics::mark_start();
+
+    // Doesn't need a config, so it works on a phone that isn't set up yet
+    if std::env::args().any(|argument| argument == "--list-audio-devices") {
+        hardware::audio::list_devices();
+
+        return;
+    }
+
+    let config = config::get();
+
+    if config.test_mode {
+        test_mode_entry().await;
+
+        return;
+    }
+
+    let Some(phone_side) = config.side else {
+        unreachable!("validated with the rest of the config");
+    };
+
+    if config.metrics_enabled {
+        metrics::serve(config.metrics_port);
+    }
+
+    if let Some(watchdog_device) = &config.watchdog_device {
+        watchdog::serve(watchdog_device, config.watchdog_stall_timeout);
+    }
 
     let (mut audio_mixer, mixer_inputs, mixed_output) = AudioMixer::create();
 
-    thread::spawn(move || {
-        audio_mixer.run();
+    thread::spawn(move || loop {
+        // Restart the mixer in place on a panic so its channels keep working
+        if panic::catch_unwind(AssertUnwindSafe(|| audio_mixer.run())).is_ok() {
+            break;
+        }
+
+        println!("Audio mixer panicked, restarting it");
+
+        metrics::MIXER_RESTARTS.fetch_add(1, Ordering::Relaxed);
+
+        audio_mixer.reset();
     });
 
+    // Sending on a broadcast never blocks, so a slow peer can't stall capture for everyone.
+    // Once a peer is 256 frames behind it loses the oldest ones, counted in
+    // phonebell_mic_frames_dropped_total
     let (mic_sender, _) = broadcast::channel(256);
 
     let audio_system_mic_sender = mic_sender.clone();
 
+    let (audio_event_sender, audio_event_receiver) = mpsc::channel();
+    let (audio_command_sender, audio_command_receiver) = mpsc::channel();
+
     thread::spawn(move || {
         let mut audio_system = AudioSystem::create();
 
-        loop {
-            if let Ok(frames) = audio_system.read_next_frames() {
-                for frame in frames {
-                    let _ = audio_system_mic_sender.send(frame);
+        'audio: loop {
+            watchdog::beat(Heartbeat::Audio);
+
+            while let Ok(command) = audio_command_receiver.try_recv() {
+                match command {
+                    AudioCommand::SwitchDevices => audio_system.switch_devices(),
+                    AudioCommand::Shutdown(done_sender) => {
+                        audio_system.fade_out(&mixed_output, SHUTDOWN_FADE_OUT);
+
+                        // Dropping it pauses both streams
+                        drop(audio_system);
+
+                        let _ = done_sender.send(());
+
+                        break 'audio;
+                    }
+                    AudioCommand::FlushInput => {
+                        if let Some(frame) = audio_system.flush_remainder() {
+                            let _ = audio_system_mic_sender.send(frame);
+                        }
+                    }
+                    AudioCommand::SetOutputTargetDepth(target_depth) => {
+                        audio_system.set_output_target_depth(target_depth);
+                    }
+                    AudioCommand::SetIdle(idle) => audio_system.set_idle(idle),
+                    AudioCommand::ReportDiagnostics => {
+                        let _ = audio_event_sender
+                            .send(AudioEvent::Diagnostics(audio_system.diagnostics()));
+                    }
                 }
             }
-            if let Ok(samples) = mixed_output.try_recv() {
-                audio_system.write_next_samples(samples.as_slice()).unwrap();
+
+            while let Ok(event) = audio_system.event_buffer.try_recv() {
+                match event {
+                    AudioEvent::OutputStalled => {
+                        metrics::AUDIO_UNDERRUNS.fetch_add(1, Ordering::Relaxed);
+                    }
+                    AudioEvent::OutputDisconnected => {
+                        audio_system.reset_output();
+                    }
+                    _ => {}
+                }
+
+                let _ = audio_event_sender.send(event);
             }
+
+            // Peers subscribe to the mic when they connect
+            audio_system.set_input_paused(audio_system_mic_sender.receiver_count() == 0);
+            audio_system.release_if_idle();
+
+            transfer_audio(&mut audio_system, &audio_system_mic_sender, &mixed_output);
         }
     });
 
-    let (mut rtc, mute_sender) = PhoneRTC::create(mixer_inputs, mic_sender);
+    let tone_mixer_input = mixer_inputs.clone();
+
+    let (mut rtc, mute_sender, rtc_command_sender, rtc_event_receiver) =
+        PhoneRTC::create(mixer_inputs, mic_sender);
+
+    let shutdown_command_sender = rtc_command_sender.clone();
+    let shutdown_audio_command_sender = audio_command_sender.clone();
+
+    // Shutdown goes outside in: leave signaling so no new peer or call shows up, fade the audio
+    // out and stop the streams while the mixer is still feeding them (stopping output first
+    // plays whatever was half-written as a burst of noise), and only then close the peer
+    // connections, whose decoders feed that mixer
+    tokio::spawn(async move {
+        shutdown_signal().await;
+
+        println!("Shutting down");
+
+        // The audio loop is about to stop beating on purpose
+        watchdog::disarm();
+
+        let _ = shutdown_command_sender.send(RTCCommand::LeaveSignaling);
+
+        let (audio_done_sender, audio_done_receiver) = mpsc::channel();
+
+        let _ = shutdown_audio_command_sender.send(AudioCommand::Shutdown(audio_done_sender));
+
+        let _ = tokio::task::spawn_blocking(move || {
+            audio_done_receiver.recv_timeout(AUDIO_SHUTDOWN_TIMEOUT)
+        })
+        .await;
+
+        let _ = shutdown_command_sender.send(RTCCommand::Shutdown);
+
+        // Signaling reads block, so the RTC loop may not get to the command in time
+        tokio::time::sleep(config.shutdown_grace_period).await;
+
+        std::process::exit(0);
+    });
 
     let webrtc_task = tokio::spawn(async move {
         rtc.run().await;
+
+        std::process::exit(0);
     });
 
     let (mut socket, outgoing_messages, incoming_messages) = PhoneSocket::create(phone_side);
@@ -74,7 +319,17 @@ async fn main() {
         socket.run();
     });
 
-    ui_entry(outgoing_messages, incoming_messages, mute_sender).await;
+    ui_entry(
+        outgoing_messages,
+        incoming_messages,
+        mute_sender,
+        audio_event_receiver,
+        audio_command_sender,
+        rtc_command_sender,
+        rtc_event_receiver,
+        tone_mixer_input,
+    )
+    .await;
 
     webrtc_task.abort();
     websocket_task.abort();
@@ -0,0 +1,217 @@
+use std::{
+    fmt::Write as _,
+    io::{Read, Write},
+    net::TcpListener,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        OnceLock,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+pub static CALLS: AtomicU64 = AtomicU64::new(0);
+pub static RECONNECTS: AtomicU64 = AtomicU64::new(0);
+pub static AUDIO_UNDERRUNS: AtomicU64 = AtomicU64::new(0);
+pub static PACKETS_RECEIVED: AtomicU64 = AtomicU64::new(0);
+pub static PACKETS_LOST: AtomicU64 = AtomicU64::new(0);
+pub static MIXER_RESTARTS: AtomicU64 = AtomicU64::new(0);
+pub static MIC_FRAMES_DROPPED: AtomicU64 = AtomicU64::new(0);
+pub static OUTPUT_FRAMES_DROPPED: AtomicU64 = AtomicU64::new(0);
+
+pub static CALL_DURATION: Histogram = Histogram::new();
+
+static START: OnceLock<Instant> = OnceLock::new();
+
+const CALL_DURATION_BUCKETS: [u64; 7] = [10, 30, 60, 300, 900, 1800, 3600];
+
+// Connections are served one at a time, so a client that connects and then goes quiet only
+// holds the rest up for this long
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(2);
+
+pub struct Histogram {
+    buckets: [AtomicU64; CALL_DURATION_BUCKETS.len()],
+    count: AtomicU64,
+    sum_millis: AtomicU64,
+}
+
+impl Histogram {
+    const fn new() -> Histogram {
+        Histogram {
+            buckets: [const { AtomicU64::new(0) }; CALL_DURATION_BUCKETS.len()],
+            count: AtomicU64::new(0),
+            sum_millis: AtomicU64::new(0),
+        }
+    }
+
+    pub fn observe(&self, duration: Duration) {
+        for (bucket, bound) in self.buckets.iter().zip(CALL_DURATION_BUCKETS) {
+            if duration.as_secs_f64() <= bound as f64 {
+                bucket.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_millis
+            .fetch_add(duration.as_millis() as u64, Ordering::Relaxed);
+    }
+}
+
+pub fn mark_start() {
+    START.get_or_init(Instant::now);
+}
+
+pub fn uptime() -> Duration {
+    START.get().map_or(Duration::ZERO, Instant::elapsed)
+}
+
+pub fn serve(port: u16) {
+    mark_start();
+
+    let Ok(listener) = TcpListener::bind(("0.0.0.0", port)) else {
+        println!("Failed to bind metrics port {}!", port);
+
+        return;
+    };
+
+    println!("Serving metrics on port {}", port);
+
+    spawn_server(listener);
+}
+
+fn spawn_server(listener: TcpListener) {
+    thread::spawn(move || {
+        for mut stream in listener.incoming().flatten() {
+            let _ = stream.set_read_timeout(Some(CLIENT_TIMEOUT));
+            let _ = stream.set_write_timeout(Some(CLIENT_TIMEOUT));
+
+            let mut request = [0u8; 1024];
+            let _ = stream.read(&mut request);
+
+            let body = render();
+
+            let _ = write!(
+                stream,
+                "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            );
+        }
+    });
+}
+
+fn render() -> String {
+    let mut body = String::new();
+
+    let mut counter = |name: &str, help: &str, value: u64| {
+        let _ = writeln!(body, "# HELP {name} {help}");
+        let _ = writeln!(body, "# TYPE {name} counter");
+        let _ = writeln!(body, "{name} {value}");
+    };
+
+    counter(
+        "phonebell_calls_total",
+        "Calls that reached a connected peer.",
+        CALLS.load(Ordering::Relaxed),
+    );
+    counter(
+        "phonebell_reconnects_total",
+        "Websocket reconnects after a dropped connection.",
+        RECONNECTS.load(Ordering::Relaxed),
+    );
+    counter(
+        "phonebell_audio_underruns_total",
+        "Sustained audio output underruns.",
+        AUDIO_UNDERRUNS.load(Ordering::Relaxed),
+    );
+    counter(
+        "phonebell_rtp_packets_received_total",
+        "RTP packets received from peers.",
+        PACKETS_RECEIVED.load(Ordering::Relaxed),
+    );
+    counter(
+        "phonebell_rtp_packets_lost_total",
+        "RTP packets missing from received sequence numbers.",
+        PACKETS_LOST.load(Ordering::Relaxed),
+    );
+    counter(
+        "phonebell_mixer_restarts_total",
+        "Audio mixer restarts after a panic.",
+        MIXER_RESTARTS.load(Ordering::Relaxed),
+    );
+    counter(
+        "phonebell_mic_frames_dropped_total",
+        "Mic frames a peer's encoder fell too far behind to send.",
+        MIC_FRAMES_DROPPED.load(Ordering::Relaxed),
+    );
+    counter(
+        "phonebell_output_frames_dropped_total",
+        "Mixed audio dropped while there was no output stream.",
+        OUTPUT_FRAMES_DROPPED.load(Ordering::Relaxed),
+    );
+
+    let _ = writeln!(
+        body,
+        "# HELP phonebell_call_duration_seconds Length of completed calls."
+    );
+    let _ = writeln!(body, "# TYPE phonebell_call_duration_seconds histogram");
+
+    for (bucket, bound) in CALL_DURATION.buckets.iter().zip(CALL_DURATION_BUCKETS) {
+        let _ = writeln!(
+            body,
+            "phonebell_call_duration_seconds_bucket{{le=\"{}\"}} {}",
+            bound,
+            bucket.load(Ordering::Relaxed)
+        );
+    }
+
+    let count = CALL_DURATION.count.load(Ordering::Relaxed);
+
+    let _ = writeln!(
+        body,
+        "phonebell_call_duration_seconds_bucket{{le=\"+Inf\"}} {}",
+        count
+    );
+    let _ = writeln!(
+        body,
+        "phonebell_call_duration_seconds_sum {}",
+        CALL_DURATION.sum_millis.load(Ordering::Relaxed) as f64 / 1000.0
+    );
+    let _ = writeln!(body, "phonebell_call_duration_seconds_count {}", count);
+
+    let uptime = uptime().as_secs_f64();
+
+    let _ = writeln!(body, "# HELP phonebell_uptime_seconds Time since startup.");
+    let _ = writeln!(body, "# TYPE phonebell_uptime_seconds gauge");
+    let _ = writeln!(body, "phonebell_uptime_seconds {}", uptime);
+
+    body
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpStream;
+
+    use super::*;
+
+    #[test]
+    fn silent_client_does_not_stall_the_endpoint() {
+        let listener = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+        let address = listener.local_addr().unwrap();
+
+        spawn_server(listener);
+
+        // Connects and never sends a request
+        let _silent = TcpStream::connect(address).unwrap();
+
+        let mut client = TcpStream::connect(address).unwrap();
+        client.set_read_timeout(Some(CLIENT_TIMEOUT * 3)).unwrap();
+        client.write_all(b"GET /metrics HTTP/1.1\r\n\r\n").unwrap();
+
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        assert!(response.contains("phonebell_calls_total"));
+    }
+}
@@ -0,0 +1,257 @@
+use std::{
+    collections::HashMap,
+    sync::{atomic::Ordering, mpsc},
+    time::Instant,
+};
+
+use tokio::sync::watch;
+use uuid::Uuid;
+
+use crate::{config, metrics};
+
+use super::rtc::RTCEvent;
+
+// Every call gets an id in the UI when it starts (off-hook, auto-answer or a peer connecting),
+// which goes to the server with Dial and to the RTC so logs from each part can be matched up
+pub fn call_tag(call_id: Option<Uuid>) -> String {
+    match call_id {
+        Some(call_id) => format!("[call {}]", call_id),
+        None => String::from("[no call]"),
+    }
+}
+
+// Only the active peer is audible while call waiting is enabled. A second peer that connects
+// during a call waits (call-waiting tone) until a flash swaps it in, after which the original
+// peer is held. Further flashes swap between the two.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CallState {
+    Idle,
+    Active(Uuid),
+    Waiting { active: Uuid, waiting: Uuid },
+    Holding { active: Uuid, held: Uuid },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HoldState {
+    None,
+    // Held by call waiting, silent both ways
+    Waiting,
+    // Put on hold by us, hears hold music while we hear nothing
+    Local,
+}
+
+impl CallState {
+    // Without call waiting another peer just joins the call, so the state stays Active
+    fn connected(self, peer: Uuid, call_waiting: bool) -> CallState {
+        match self {
+            CallState::Idle => CallState::Active(peer),
+            CallState::Active(active) if call_waiting && active != peer => CallState::Waiting {
+                active,
+                waiting: peer,
+            },
+            state => state,
+        }
+    }
+
+    fn left(self, peer: Uuid) -> CallState {
+        match self {
+            CallState::Active(active) if active == peer => CallState::Idle,
+            CallState::Waiting { active, waiting } if active == peer => CallState::Active(waiting),
+            CallState::Waiting { active, waiting } if waiting == peer => CallState::Active(active),
+            CallState::Holding { active, held } if active == peer => CallState::Active(held),
+            CallState::Holding { active, held } if held == peer => CallState::Active(active),
+            state => state,
+        }
+    }
+
+    fn swapped(self) -> CallState {
+        match self {
+            CallState::Waiting { active, waiting } => CallState::Holding {
+                active: waiting,
+                held: active,
+            },
+            CallState::Holding { active, held } => CallState::Holding {
+                active: held,
+                held: active,
+            },
+            state => state,
+        }
+    }
+
+    pub fn is_audible(&self, peer: Uuid) -> bool {
+        match self {
+            CallState::Idle => true,
+            CallState::Active(active)
+            | CallState::Waiting { active, .. }
+            | CallState::Holding { active, .. } => *active == peer,
+        }
+    }
+}
+
+pub struct CallTracker {
+    state: CallState,
+    hold_senders: HashMap<Uuid, watch::Sender<HoldState>>,
+    event_sender: mpsc::Sender<RTCEvent>,
+    call_start: Option<Instant>,
+    on_hold: bool,
+}
+
+impl CallTracker {
+    pub fn create(event_sender: mpsc::Sender<RTCEvent>) -> CallTracker {
+        CallTracker {
+            state: CallState::Idle,
+            hold_senders: HashMap::new(),
+            event_sender,
+            call_start: None,
+            on_hold: false,
+        }
+    }
+
+    pub fn state(&self) -> CallState {
+        self.state
+    }
+
+    pub fn hold_state(&self, peer: Uuid) -> HoldState {
+        if config::get().call_waiting_enabled && !self.state.is_audible(peer) {
+            HoldState::Waiting
+        } else if self.on_hold {
+            HoldState::Local
+        } else {
+            HoldState::None
+        }
+    }
+
+    pub fn track_peer(&mut self, peer: Uuid, hold_sender: watch::Sender<HoldState>) {
+        let _ = hold_sender.send(self.hold_state(peer));
+
+        self.hold_senders.insert(peer, hold_sender);
+    }
+
+    pub fn peer_connected(&mut self, peer: Uuid) {
+        let _ = self.event_sender.send(RTCEvent::PeerConnected(peer));
+
+        self.set_state(
+            self.state
+                .connected(peer, config::get().call_waiting_enabled),
+        );
+    }
+
+    pub fn peer_left(&mut self, peer: Uuid) {
+        self.hold_senders.remove(&peer);
+
+        self.set_state(self.state.left(peer));
+    }
+
+    pub fn swap(&mut self) {
+        self.set_state(self.state.swapped());
+    }
+
+    pub fn toggle_hold(&mut self) {
+        if self.state == CallState::Idle {
+            return;
+        }
+
+        self.set_hold(!self.on_hold);
+    }
+
+    fn set_hold(&mut self, on_hold: bool) {
+        if on_hold == self.on_hold {
+            return;
+        }
+
+        println!("On hold: {}", on_hold);
+
+        self.on_hold = on_hold;
+
+        self.update_hold_senders();
+
+        let _ = self.event_sender.send(RTCEvent::Hold(on_hold));
+    }
+
+    fn update_hold_senders(&self) {
+        for (peer, hold_sender) in &self.hold_senders {
+            let _ = hold_sender.send(self.hold_state(*peer));
+        }
+    }
+
+    fn set_state(&mut self, state: CallState) {
+        if state == self.state {
+            return;
+        }
+
+        println!("Call state: {:?}", state);
+
+        if self.state == CallState::Idle {
+            metrics::CALLS.fetch_add(1, Ordering::Relaxed);
+
+            self.call_start = Some(Instant::now());
+        } else if state == CallState::Idle {
+            if let Some(call_start) = self.call_start.take() {
+                metrics::CALL_DURATION.observe(call_start.elapsed());
+            }
+        }
+
+        self.state = state;
+
+        self.update_hold_senders();
+
+        let _ = self.event_sender.send(RTCEvent::CallState(state));
+
+        if state == CallState::Idle {
+            self.set_hold(false);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn second_peer_waits_with_call_waiting() {
+        let (first, second) = (Uuid::new_v4(), Uuid::new_v4());
+
+        let state = CallState::Idle
+            .connected(first, true)
+            .connected(second, true);
+
+        assert_eq!(
+            state,
+            CallState::Waiting {
+                active: first,
+                waiting: second
+            }
+        );
+        assert_eq!(
+            state.swapped(),
+            CallState::Holding {
+                active: second,
+                held: first
+            }
+        );
+    }
+
+    #[test]
+    fn second_peer_joins_without_call_waiting() {
+        let (first, second) = (Uuid::new_v4(), Uuid::new_v4());
+
+        let state = CallState::Idle
+            .connected(first, false)
+            .connected(second, false);
+
+        assert_eq!(state, CallState::Active(first));
+    }
+
+    #[test]
+    fn waiting_peer_takes_over_when_the_active_one_leaves() {
+        let (first, second) = (Uuid::new_v4(), Uuid::new_v4());
+
+        let state = CallState::Idle
+            .connected(first, true)
+            .connected(second, true);
+
+        assert_eq!(state.left(first), CallState::Active(second));
+        assert_eq!(state.left(second), CallState::Active(first));
+        assert_eq!(state.left(first).left(second), CallState::Idle);
+    }
+}
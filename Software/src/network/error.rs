@@ -0,0 +1,39 @@
+use thiserror::Error;
+use websocket::{client::ParseError, WebSocketError};
+
+// Why opening the phone socket or signaling failed. Both retry constantly, so these are
+// compared by message to only log a failure when it changes
+#[derive(Debug, Error)]
+pub enum ConnectError {
+    #[error("invalid url {url}: {source}")]
+    InvalidUrl { url: String, source: ParseError },
+    #[error("connecting to {url} failed: {source}")]
+    Connect { url: String, source: std::io::Error },
+    #[error("TLS handshake with {url} failed: {source}")]
+    Handshake { url: String, source: WebSocketError },
+    #[error("encoding the {what} failed: {source}")]
+    Encode {
+        what: &'static str,
+        source: serde_json::Error,
+    },
+    #[error("sending the {what} failed: {source}")]
+    Send {
+        what: &'static str,
+        source: WebSocketError,
+    },
+}
+
+// A message from the server or a peer that couldn't be used
+#[derive(Debug, Error)]
+pub enum MessageError {
+    #[error("undecodable message {data:?}: {source}")]
+    Decode {
+        data: String,
+        source: serde_json::Error,
+    },
+    #[error("encoding {what} failed: {source}")]
+    Encode {
+        what: String,
+        source: serde_json::Error,
+    },
+}
@@ -1,27 +1,184 @@
+pub mod call;
+pub mod error;
+pub mod qos;
 pub mod rtc;
 pub mod socket;
 
+use std::time::{Duration, Instant};
+
 use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::{config, hardware::audio::AudioDiagnostics};
+
+use self::rtc::PeerDiagnostics;
 
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(tag = "type")]
 pub enum PhoneOutgoingMessage {
-    Dial { number: String },
-    Hook { state: bool },
+    Dial {
+        number: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        caller_id: Option<String>,
+        call_id: Uuid,
+    },
+    Hook {
+        state: bool,
+    },
+    Diagnostics {
+        snapshot: Diagnostics,
+    },
+    // Sent right after the API key on every connect when an extension is configured
+    Identify {
+        extension: String,
+    },
+}
+
+// What a Dial can carry: at least one digit and nothing but digits. Anything else, e.g. only
+// whitespace or a stray * or #, is junk the server shouldn't see
+pub fn is_dialable(number: &str) -> bool {
+    !number.is_empty() && number.chars().all(|digit| digit.is_ascii_digit())
+}
+
+// Sent back for PhoneIncomingMessage::Diagnostics. Holds no config, so no secrets. Parts that
+// didn't answer in time are left out
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Diagnostics {
+    pub uptime_seconds: u64,
+    pub hook_on: bool,
+    pub input_muted: bool,
+    pub output_muted: bool,
+    pub call_state: String,
+    pub call_id: Option<Uuid>,
+    pub signaling_connected: Option<bool>,
+    pub peers: Option<Vec<PeerDiagnostics>>,
+    pub audio: Option<AudioDiagnostics>,
+    pub packets_received: u64,
+    pub packets_lost: u64,
+    pub audio_underruns: u64,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(tag = "type")]
 pub enum PhoneIncomingMessage {
-    Ring { state: bool },
-    Mute { state: bool },
-    PlaySound { sound: Sound },
+    Ring {
+        state: bool,
+    },
+    Mute {
+        state: bool,
+        #[serde(default)]
+        direction: MuteDirection,
+    },
+    PlaySound {
+        sound: Sound,
+    },
+    RingTest,
+    // Answered with PhoneOutgoingMessage::Diagnostics
+    Diagnostics,
+    CallerId {
+        #[serde(default)]
+        id: Option<String>,
+    },
+    // Switches call audio to one of config.audio_profiles
+    AudioProfile {
+        name: String,
+    },
+    // A short line of text to show, e.g. "Building closes at 10pm". Empty clears it
+    Notice {
+        text: String,
+    },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default)]
+pub enum MuteDirection {
+    #[default]
+    Both,
+    Input,
+    Output,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum Sound {
     None,
     Dialtone,
+    // Sent once dialing is done, while the server is setting up the call, until ringback
+    Connecting,
     Ringback,
+    // The dialed phone is already in a call
+    Busy,
     Hangup,
+    // Two quick beeps, e.g. once the door has been opened. Plays once, the rest loop
+    Confirm,
+}
+
+impl Sound {
+    // One-shots queue behind each other and the loop resumes after them, loops replace the loop
+    pub fn is_one_shot(&self) -> bool {
+        matches!(self, Sound::Confirm)
+    }
+}
+
+// Caps the per-message lines a socket prints, a busy call otherwise floods the console. What
+// gets held back is counted and reported once the next second starts
+pub struct MessageLog {
+    name: &'static str,
+    window_start: Instant,
+    printed: u32,
+    held_back: u32,
+}
+
+impl MessageLog {
+    pub fn new(name: &'static str) -> Self {
+        Self {
+            name,
+            window_start: Instant::now(),
+            printed: 0,
+            held_back: 0,
+        }
+    }
+
+    pub fn allow(&mut self) -> bool {
+        let limit = config::get().message_log_rate;
+
+        if self.window_start.elapsed() >= Duration::from_secs(1) {
+            if self.held_back > 0 && limit > 0 {
+                println!("{}: {} messages not logged", self.name, self.held_back);
+            }
+
+            self.window_start = Instant::now();
+            self.printed = 0;
+            self.held_back = 0;
+        }
+
+        if self.printed < limit {
+            self.printed += 1;
+
+            true
+        } else {
+            self.held_back += 1;
+
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn junk_numbers_are_not_dialable() {
+        for number in [
+            "", " ", "\t\n", " 12", "12 ", "1 2", "*", "#", "*#", "12#", "１２",
+        ] {
+            assert!(!is_dialable(number), "{:?} was dialable", number);
+        }
+    }
+
+    #[test]
+    fn digit_only_numbers_are_dialable() {
+        for number in ["0", "7", "1234", "0000"] {
+            assert!(is_dialable(number), "{:?} wasn't dialable", number);
+        }
+    }
 }
@@ -0,0 +1,47 @@
+use std::{
+    net::{SocketAddr, UdpSocket},
+    sync::Arc,
+};
+
+use socket2::{Domain, Protocol, Socket, Type};
+use webrtc::ice::udp_mux::{UDPMuxDefault, UDPMuxParams};
+
+// An IPv4 UDP socket for ICE, marked with the DSCP before anything is sent on it
+pub fn marked_udp_socket(dscp: u8) -> Result<UdpSocket, String> {
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))
+        .map_err(|error| error.to_string())?;
+
+    socket
+        .set_tos(u32::from(dscp) << 2)
+        .map_err(|error| error.to_string())?;
+    socket
+        .bind(&SocketAddr::from(([0, 0, 0, 0], 0)).into())
+        .map_err(|error| error.to_string())?;
+    socket
+        .set_nonblocking(true)
+        .map_err(|error| error.to_string())?;
+
+    Ok(socket.into())
+}
+
+// Every peer connection's ICE goes through this one marked socket, so the call audio is marked
+// and nothing else the process sends is. Needs to be inside the tokio runtime
+pub fn marked_udp_mux(dscp: u8) -> Result<Arc<UDPMuxDefault>, String> {
+    let socket = tokio::net::UdpSocket::from_std(marked_udp_socket(dscp)?)
+        .map_err(|error| error.to_string())?;
+
+    Ok(UDPMuxDefault::new(UDPMuxParams::new(socket)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use socket2::SockRef;
+
+    #[test]
+    fn socket_is_marked_when_created() {
+        let socket = marked_udp_socket(46).unwrap();
+
+        assert_eq!(SockRef::from(&socket).tos().unwrap(), 46 << 2);
+    }
+}
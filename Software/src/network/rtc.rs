@@ -1,11 +1,14 @@
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet, VecDeque},
+    fs,
+    net::Ipv6Addr,
     sync::{
         atomic::{AtomicI64, Ordering},
         mpsc::{self},
-        Arc,
+        Arc, Mutex,
     },
     thread,
+    time::{Duration, Instant},
 };
 
 use bytes::Bytes;
@@ -15,17 +18,23 @@ use tokio::sync::{broadcast, watch};
 use uuid::Uuid;
 use webrtc::{
     api::{
-        interceptor_registry::register_default_interceptors,
+        interceptor_registry::{
+            configure_nack, configure_rtcp_reports, configure_twcc_receiver_only,
+        },
         media_engine::{MediaEngine, MIME_TYPE_OPUS},
+        setting_engine::SettingEngine,
         APIBuilder, API,
     },
+    ice::{candidate::CandidateType, network_type::NetworkType, udp_network::UDPNetwork},
     ice_transport::{
         ice_candidate::{RTCIceCandidate, RTCIceCandidateInit},
+        ice_candidate_type::RTCIceCandidateType,
         ice_server::RTCIceServer,
     },
     interceptor::registry::Registry,
     peer_connection::{
-        configuration::RTCConfiguration, peer_connection_state::RTCPeerConnectionState,
+        configuration::RTCConfiguration, offer_answer_options::RTCOfferOptions,
+        peer_connection_state::RTCPeerConnectionState,
         sdp::session_description::RTCSessionDescription, RTCPeerConnection,
     },
     rtp::{
@@ -34,26 +43,47 @@ use webrtc::{
         sequence::new_random_sequencer,
     },
     rtp_transceiver::rtp_codec::{RTCRtpCodecCapability, RTCRtpCodecParameters, RTPCodecType},
+    stats::StatsReportType,
     track::track_local::{
         track_local_static_rtp::TrackLocalStaticRTP, TrackLocal, TrackLocalWriter,
     },
 };
 
-use crate::{config::SAMPLE_RATE, hardware::audio::MixerMessage};
+use crate::{
+    config::{self, WebRtcInterceptor, SAMPLE_RATE},
+    hardware::{audio::MixerMessage, tone},
+    metrics,
+};
+
+use super::{
+    call::{call_tag, CallState, CallTracker, HoldState},
+    error::{ConnectError, MessageError},
+    qos, MessageLog,
+};
 
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(tag = "type")]
 pub enum SignalingMessage {
     Join {
         from: Uuid,
+        // Rejoining after a signaling reconnect with calls still up
+        #[serde(default)]
+        resume: bool,
+        #[serde(default = "legacy_protocol_versions")]
+        versions: Vec<u32>,
     },
     JoinAck {
         from: Uuid,
+        #[serde(default = "legacy_protocol_versions")]
+        versions: Vec<u32>,
     },
     ICEOffer {
         offer: RTCSessionDescription,
         from: Uuid,
         to: Uuid,
+        // An ICE restart for the connection we already have, not a new session
+        #[serde(default)]
+        restart: bool,
     },
     ICEAnswer {
         answer: RTCSessionDescription,
@@ -68,29 +98,254 @@ pub enum SignalingMessage {
     Leave {
         from: Uuid,
     },
+    // Answer confirmation, sent while the sender is off-hook with a connected call
+    Ready {
+        from: Uuid,
+        to: Uuid,
+    },
+    // Sent instead of a JoinAck or answer while off-hook in a call that can't take another peer
+    Busy {
+        from: Uuid,
+        to: Uuid,
+    },
+}
+
+#[derive(Debug)]
+pub enum RTCEvent {
+    CallState(CallState),
+    PeerConnected(Uuid),
+    Hold(bool),
+    AnswerConfirmed(Uuid),
+    // The peer turned us away with SignalingMessage::Busy
+    Busy(Uuid),
+    // A peer went Disconnected and might come back, false once it has or was dropped
+    Recovering(Uuid, bool),
+    // Every reconnect to a dropped peer failed
+    ReconnectFailed(Uuid),
+    // Which way the audio takes to a newly connected peer
+    NetworkPath(Uuid, NetworkPath),
+    Status {
+        signaling_connected: bool,
+        peers: usize,
+        // The slowest peer's, None until one has a nominated candidate pair
+        round_trip_time_ms: Option<f64>,
+    },
+    Diagnostics {
+        signaling_connected: bool,
+        peers: Vec<PeerDiagnostics>,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum NetworkPath {
+    // Host candidates on both ends, usually the same LAN
+    Direct,
+    // Through NAT with server or peer reflexive candidates
+    Reflexive,
+    // Through a TURN server
+    Relayed,
+}
+
+impl NetworkPath {
+    pub fn output_target_depth(self) -> Duration {
+        let config = config::get();
+
+        match self {
+            NetworkPath::Direct => config.direct_output_target_depth,
+            NetworkPath::Reflexive => config.output_target_depth,
+            NetworkPath::Relayed => config.relayed_output_target_depth,
+        }
+    }
+
+    // From the candidates of the nominated pair, None until there is one
+    pub async fn of(peer_connection: &RTCPeerConnection) -> Option<NetworkPath> {
+        let stats = peer_connection.get_stats().await;
+
+        let pair = stats.reports.values().find_map(|report| match report {
+            StatsReportType::CandidatePair(pair) if pair.nominated => Some(pair),
+            _ => None,
+        })?;
+
+        let candidate_type = |id: &str| {
+            stats.reports.get(id).and_then(|report| match report {
+                StatsReportType::LocalCandidate(candidate)
+                | StatsReportType::RemoteCandidate(candidate) => Some(candidate.candidate_type),
+                _ => None,
+            })
+        };
+
+        let types = [
+            candidate_type(&pair.local_candidate_id)?,
+            candidate_type(&pair.remote_candidate_id)?,
+        ];
+
+        Some(if types.contains(&CandidateType::Relay) {
+            NetworkPath::Relayed
+        } else if types.iter().all(|kind| *kind == CandidateType::Host) {
+            NetworkPath::Direct
+        } else {
+            NetworkPath::Reflexive
+        })
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PeerDiagnostics {
+    pub id: Uuid,
+    pub state: String,
+    // From the nominated ICE candidate pair, if there is one yet
+    pub round_trip_time_ms: Option<f64>,
+}
+
+// Input gates what we send to peers, output gates what we play from them
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MuteState {
+    pub input: bool,
+    pub output: bool,
+}
+
+impl MuteState {
+    pub const ALL: MuteState = MuteState {
+        input: true,
+        output: true,
+    };
+
+    // What the encoder and decoder go by, a held call is silent both ways whatever the mute
+    pub fn input_silenced(&self, hold: HoldState) -> bool {
+        self.input || hold != HoldState::None
+    }
+
+    pub fn output_silenced(&self, hold: HoldState) -> bool {
+        self.output || hold != HoldState::None
+    }
+}
+
+#[derive(Debug)]
+pub enum RTCCommand {
+    SwapCalls,
+    ToggleHold,
+    ReportStatus,
+    // Answered with RTCEvent::Diagnostics
+    ReportDiagnostics,
+    // Whether this phone is off-hook with a connected call
+    SetReady(bool),
+    // Same as SetReady, but always sent, so new peers can be turned away
+    SetBusy(bool),
+    // The handset itself, a dropped call is only reconnected while it's off-hook
+    SetOffHook(bool),
+    // Tones sent to every peer in place of the mic, even while it's muted
+    SendDtmf(Vec<f32>),
+    // First step of shutting down, so nothing new arrives while the audio winds down
+    LeaveSignaling,
+    SetCallId(Option<Uuid>),
+    Shutdown,
+}
+
+type SignalingSocket = websocket::client::sync::Client<
+    websocket::stream::sync::TlsStream<websocket::stream::sync::TcpStream>,
+>;
+
+// What one read from the signaling socket means for the run loop
+#[derive(Debug)]
+enum SignalingRead {
+    Text(String),
+    Ping(Vec<u8>),
+    // Closed by the server or broken, the socket is dropped so the loop reconnects
+    Lost(String),
+    Ignored,
+}
+
+impl SignalingRead {
+    fn from(received: Result<websocket::OwnedMessage, websocket::WebSocketError>) -> Self {
+        match received {
+            Ok(websocket::OwnedMessage::Text(data)) => SignalingRead::Text(data),
+            Ok(websocket::OwnedMessage::Ping(data)) => SignalingRead::Ping(data),
+            Ok(websocket::OwnedMessage::Close(_)) => {
+                SignalingRead::Lost(String::from("closed by the server"))
+            }
+            Ok(_) => SignalingRead::Ignored,
+            // Reads block, so an error means the connection is gone
+            Err(error) => SignalingRead::Lost(format!("read failed: {}", error)),
+        }
+    }
+}
+
+// The peer of a call that dropped while we were off-hook, remembered until it reconnects, we
+// hang up or the attempts run out
+struct Reconnect {
+    peer: Uuid,
+    attempts: u32,
+    next_attempt: Instant,
 }
 
 pub struct PhoneRTC {
-    signaling_socket: Option<
-        websocket::client::sync::Client<
-            websocket::stream::sync::TlsStream<websocket::stream::sync::TcpStream>,
-        >,
-    >,
+    signaling_socket: Option<SignalingSocket>,
+    // Connects are retried constantly, only log a failure when it changes
+    last_connect_error: Option<String>,
     webrtc_api: API,
-    mute_receiver: mpsc::Receiver<bool>,
+    mute_receiver: mpsc::Receiver<MuteState>,
+    command_receiver: mpsc::Receiver<RTCCommand>,
     peer_connections: HashMap<Uuid, RTCPeerConnection>,
+    calls: CallTracker,
+    event_sender: mpsc::Sender<RTCEvent>,
     mixer_out: mpsc::Sender<MixerMessage>,
     mic_in: broadcast::Sender<Vec<f32>>,
+    dtmf_in: broadcast::Sender<Vec<f32>>,
     id: Uuid,
-    muted: bool,
+    muted: MuteState,
+    ready: bool,
+    ready_peers: HashSet<Uuid>,
+    busy: bool,
+    off_hook: bool,
+    reconnect: Option<Reconnect>,
+    // Set on shutdown so the loop doesn't reconnect
+    left_signaling: bool,
+    call_id: Option<Uuid>,
+    // Negotiated from Join/JoinAck, nothing depends on it until there's a version 2
+    peer_versions: HashMap<Uuid, u32>,
+    // Peers we already restarted negotiation with after a bad answer, until they connect
+    renegotiated_peers: HashSet<Uuid>,
+    // When each peer went Disconnected, cleared if it comes back before the grace period is up
+    disconnected_peers: HashMap<Uuid, Instant>,
+    message_log: MessageLog,
+    // Packets received from each peer and when that last went up
+    media_progress: HashMap<Uuid, (u64, Instant)>,
+    last_media_check: Instant,
+    last_ice_restarts: HashMap<Uuid, Instant>,
 }
 
 impl PhoneRTC {
     pub fn create(
         mixer_out: mpsc::Sender<MixerMessage>,
         mic_in: broadcast::Sender<Vec<f32>>,
-    ) -> (PhoneRTC, mpsc::Sender<bool>) {
+    ) -> (
+        PhoneRTC,
+        mpsc::Sender<MuteState>,
+        mpsc::Sender<RTCCommand>,
+        mpsc::Receiver<RTCEvent>,
+    ) {
+        let (mut socket, mute_sender, command_sender, event_receiver) =
+            Self::new(mixer_out, mic_in, persistent_id());
+
+        socket.connect();
+
+        (socket, mute_sender, command_sender, event_receiver)
+    }
+
+    // Everything but the signaling connection, which create opens
+    fn new(
+        mixer_out: mpsc::Sender<MixerMessage>,
+        mic_in: broadcast::Sender<Vec<f32>>,
+        id: Uuid,
+    ) -> (
+        PhoneRTC,
+        mpsc::Sender<MuteState>,
+        mpsc::Sender<RTCCommand>,
+        mpsc::Receiver<RTCEvent>,
+    ) {
         let (mute_sender, mute_receiver) = mpsc::channel();
+        let (command_sender, command_receiver) = mpsc::channel();
+        let (event_sender, event_receiver) = mpsc::channel();
 
         let mut m = MediaEngine::default();
 
@@ -100,7 +355,7 @@ impl PhoneRTC {
                     mime_type: MIME_TYPE_OPUS.to_owned(),
                     ..Default::default()
                 },
-                payload_type: 120,
+                payload_type: OPUS_PAYLOAD_TYPE,
                 ..Default::default()
             },
             RTPCodecType::Audio,
@@ -109,60 +364,340 @@ impl PhoneRTC {
 
         let mut registry = Registry::new();
 
-        registry = register_default_interceptors(registry, &mut m).unwrap();
+        // The same as register_default_interceptors, minus whatever the config leaves out
+        for interceptor in &config::get().webrtc_interceptors {
+            registry = match interceptor {
+                WebRtcInterceptor::Nack => configure_nack(registry, &mut m),
+                WebRtcInterceptor::RtcpReports => configure_rtcp_reports(registry),
+                WebRtcInterceptor::Twcc => configure_twcc_receiver_only(registry, &mut m).unwrap(),
+            };
+        }
+
+        let mut setting_engine = SettingEngine::default();
+
+        // webrtc doesn't gather server reflexive candidates on a muxed socket, so with DSCP on
+        // only direct and relayed paths are left, and only the direct one is marked
+        if let Some(dscp) = config::get().audio_dscp.filter(|&dscp| dscp != 0) {
+            match qos::marked_udp_mux(dscp) {
+                Ok(udp_mux) => {
+                    println!("Marking call audio with DSCP {}", dscp);
+
+                    setting_engine.set_udp_network(UDPNetwork::Muxed(udp_mux));
+                    setting_engine.set_network_types(vec![NetworkType::Udp4]);
+                }
+                Err(error) => println!("Couldn't mark call audio with DSCP {}: {}", dscp, error),
+            }
+        }
 
         let webrtc_api = APIBuilder::new()
             .with_media_engine(m)
             .with_interceptor_registry(registry)
+            .with_setting_engine(setting_engine)
             .build();
 
-        let mut socket = PhoneRTC {
+        let socket = PhoneRTC {
             signaling_socket: None,
+            last_connect_error: None,
             webrtc_api,
             mute_receiver,
+            command_receiver,
             peer_connections: HashMap::new(),
+            calls: CallTracker::create(event_sender.clone()),
+            event_sender,
             mixer_out,
             mic_in,
-            id: Uuid::new_v4(),
-            muted: true,
+            dtmf_in: broadcast::channel(16).0,
+            id,
+            muted: MuteState::ALL,
+            ready: false,
+            busy: false,
+            off_hook: false,
+            reconnect: None,
+            left_signaling: false,
+            ready_peers: HashSet::new(),
+            call_id: None,
+            peer_versions: HashMap::new(),
+            renegotiated_peers: HashSet::new(),
+            disconnected_peers: HashMap::new(),
+            message_log: MessageLog::new("Signaling"),
+            media_progress: HashMap::new(),
+            last_media_check: Instant::now(),
+            last_ice_restarts: HashMap::new(),
         };
 
-        socket.connect();
-
-        (socket, mute_sender)
+        (socket, mute_sender, command_sender, event_receiver)
     }
 
     fn connect(&mut self) {
-        if self.signaling_socket.is_some() {
+        if self.signaling_socket.is_some() || self.left_signaling {
             return;
         }
 
-        let Ok(mut websocket_client_builder) =
-            websocket::ClientBuilder::new("wss://api.purduehackers.com/phonebell/signaling")
-        else {
-            return;
-        };
+        match self.open_signaling() {
+            Ok(websocket_client) => {
+                self.last_connect_error = None;
+                self.signaling_socket = Some(websocket_client);
+            }
+            Err(error) => self.connect_failed(error),
+        }
+    }
+
+    fn open_signaling(&self) -> Result<SignalingSocket, ConnectError> {
+        let url = "wss://api.purduehackers.com/phonebell/signaling";
+
+        let mut websocket_client = websocket::ClientBuilder::new(url)
+            .map_err(|source| ConnectError::InvalidUrl {
+                url: url.to_string(),
+                source,
+            })?
+            .connect_secure(Option::None)
+            .map_err(|source| ConnectError::Handshake {
+                url: url.to_string(),
+                source,
+            })?;
+
+        websocket_client
+            .send_message(&websocket::Message::text("gm!"))
+            .map_err(|source| ConnectError::Send {
+                what: "greeting",
+                source,
+            })?;
+
+        let join = self.join_message();
+
+        let message_string =
+            serde_json::to_string(&join).map_err(|source| ConnectError::Encode {
+                what: "Join",
+                source,
+            })?;
+
+        websocket_client
+            .send_message(&websocket::Message::text(message_string))
+            .map_err(|source| ConnectError::Send {
+                what: "Join",
+                source,
+            })?;
+
+        println!(
+            "Signaling connected to {} ({})",
+            url,
+            websocket_client
+                .peer_addr()
+                .map_or(String::from("unknown address"), |address| address
+                    .to_string())
+        );
+        println!("webrtc tx: {:?}", join);
+
+        Ok(websocket_client)
+    }
+
+    // Media doesn't go through signaling, so calls survive a reconnect
+    fn join_message(&self) -> SignalingMessage {
+        SignalingMessage::Join {
+            from: self.id,
+            resume: !self.peer_connections.is_empty(),
+            versions: PROTOCOL_VERSIONS.to_vec(),
+        }
+    }
+
+    fn connect_failed(&mut self, error: ConnectError) {
+        let error = error.to_string();
+
+        if self.last_connect_error.as_ref() != Some(&error) {
+            println!("Signaling connect failed: {}", error);
+
+            self.last_connect_error = Some(error);
+        }
+    }
+
+    // Ends a peer's recovery, however it turned out
+    fn forget_disconnect(&mut self, peer: Uuid) {
+        if self.disconnected_peers.remove(&peer).is_some() {
+            let _ = self.event_sender.send(RTCEvent::Recovering(peer, false));
+        }
+    }
+
+    // A Disconnected peer gets peer_disconnect_grace_period to come back before it's dropped,
+    // Failed drops it right away
+    async fn connection_changed(
+        &mut self,
+        connection_state: RTCPeerConnectionState,
+        from: Uuid,
+        channels: &SignalingChannels,
+    ) {
+        if connection_state == RTCPeerConnectionState::Connected {
+            if self.disconnected_peers.contains_key(&from) {
+                println!("{} Peer {} recovered", call_tag(self.call_id), from);
+
+                self.forget_disconnect(from);
+            }
+
+            if self
+                .reconnect
+                .take_if(|reconnect| reconnect.peer == from)
+                .is_some()
+            {
+                println!("{} Reconnected to {}", call_tag(self.call_id), from);
+
+                let _ = self.event_sender.send(RTCEvent::Recovering(from, false));
+            }
+
+            println!("{} Peer {} connected", call_tag(self.call_id), from);
+
+            self.renegotiated_peers.remove(&from);
+
+            self.calls.peer_connected(from);
+
+            let path = match self.peer_connections.get(&from) {
+                Some(peer_connection) => NetworkPath::of(peer_connection).await,
+                None => None,
+            };
+
+            if let Some(path) = path {
+                println!("{} Path to {}: {:?}", call_tag(self.call_id), from, path);
+
+                let _ = self.event_sender.send(RTCEvent::NetworkPath(from, path));
+            }
 
-        let Ok(mut websocket_client) = websocket_client_builder.connect_secure(Option::None) else {
+            if self.ready {
+                let _ = channels
+                    .signaling_message_sender
+                    .send(SignalingMessage::Ready {
+                        from: self.id,
+                        to: from,
+                    });
+            }
+        } else if connection_state == RTCPeerConnectionState::Disconnected {
+            if self.peer_connections.contains_key(&from)
+                && !self.disconnected_peers.contains_key(&from)
+            {
+                println!(
+                    "{} Peer {} disconnected, waiting for it to come back",
+                    call_tag(self.call_id),
+                    from
+                );
+
+                self.disconnected_peers.insert(from, Instant::now());
+
+                let _ = self.event_sender.send(RTCEvent::Recovering(from, true));
+            }
+        } else if connection_state == RTCPeerConnectionState::Failed {
+            self.drop_peer(from, "failed").await;
+        }
+    }
+
+    async fn drop_expired_peers(&mut self) {
+        let grace_period = config::get().peer_disconnect_grace_period;
+
+        let expired_peers: Vec<Uuid> = self
+            .disconnected_peers
+            .iter()
+            .filter(|(_, since)| since.elapsed() >= grace_period)
+            .map(|(id, _)| *id)
+            .collect();
+
+        for peer in expired_peers {
+            self.drop_peer(peer, "stayed disconnected").await;
+        }
+    }
+
+    async fn drop_peer(&mut self, from: Uuid, reason: &str) {
+        self.forget_disconnect(from);
+
+        if let Some(peer_connection) = self.peer_connections.remove(&from) {
+            println!("{} Peer {} {}", call_tag(self.call_id), from, reason);
+
+            let _ = peer_connection.close().await;
+
+            self.ready_peers.remove(&from);
+            self.calls.peer_left(from);
+
+            let retrying = self
+                .reconnect
+                .as_ref()
+                .is_some_and(|reconnect| reconnect.peer == from);
+
+            if self.off_hook && !retrying && config::get().reconnect_attempts > 0 {
+                println!(
+                    "{} Reconnecting to {} while off-hook",
+                    call_tag(self.call_id),
+                    from
+                );
+
+                self.reconnect = Some(Reconnect {
+                    peer: from,
+                    attempts: 0,
+                    next_attempt: Instant::now() + config::get().reconnect_backoff,
+                });
+
+                let _ = self.event_sender.send(RTCEvent::Recovering(from, true));
+            }
+        }
+    }
+
+    // Only the side that offers actually reconnects, the other one answers its offer as usual and
+    // just keeps count so both give up around the same time
+    async fn retry_reconnect(&mut self, channels: &SignalingChannels) {
+        let Some(reconnect) = &mut self.reconnect else {
             return;
         };
 
-        let Ok(_) = websocket_client.send_message(&websocket::Message::text("gm!")) else {
+        if Instant::now() < reconnect.next_attempt {
             return;
-        };
+        }
+
+        let peer = reconnect.peer;
+        let max_attempts = config::get().reconnect_attempts;
+
+        reconnect.attempts += 1;
+
+        if reconnect.attempts > max_attempts {
+            println!(
+                "{} Gave up reconnecting to {} after {} attempts",
+                call_tag(self.call_id),
+                peer,
+                max_attempts
+            );
+
+            self.reconnect = None;
+
+            let _ = self.event_sender.send(RTCEvent::Recovering(peer, false));
+
+            if let Some(peer_connection) = self.peer_connections.remove(&peer) {
+                let _ = peer_connection.close().await;
+
+                self.calls.peer_left(peer);
+            }
+
+            let _ = self.event_sender.send(RTCEvent::ReconnectFailed(peer));
 
-        let Ok(message_string) = serde_json::to_string(&SignalingMessage::Join { from: self.id })
-        else {
             return;
-        };
+        }
 
-        let Ok(_) = websocket_client.send_message(&websocket::Message::text(message_string)) else {
+        reconnect.next_attempt =
+            Instant::now() + config::get().reconnect_backoff * 2u32.pow(reconnect.attempts);
+
+        println!(
+            "{} Reconnecting to {} ({}/{})",
+            call_tag(self.call_id),
+            peer,
+            reconnect.attempts,
+            max_attempts
+        );
+
+        if !self.initiates(peer) {
             return;
-        };
+        }
 
-        println!("webrtc tx: {:?}", SignalingMessage::Join { from: self.id });
+        // Whatever the last attempt left behind never connected
+        if let Some(peer_connection) = self.peer_connections.remove(&peer) {
+            let _ = peer_connection.close().await;
 
-        self.signaling_socket = Some(websocket_client);
+            self.ready_peers.remove(&peer);
+            self.calls.peer_left(peer);
+        }
+
+        self.send_offer(peer, channels).await;
     }
 
     pub async fn run(&mut self) {
@@ -175,11 +710,22 @@ impl PhoneRTC {
             mpsc::channel::<SignalingMessage>();
         let (signaling_pong_sender, signaling_pong_receiver) = mpsc::channel::<Vec<u8>>();
 
-        let (mute_sender, mute_receiver) = watch::channel(true);
+        let (mute_sender, mute_receiver) = watch::channel(MuteState::ALL);
+
+        let signaling_channels = SignalingChannels {
+            signaling_message_sender: signaling_message_sender.clone(),
+            ice_candidate_sender: ice_candidate_channel_sender,
+            connection_change_sender: connection_change_channel_sender,
+            mute_receiver,
+        };
 
         loop {
             if self.signaling_socket.is_none() {
                 self.connect();
+
+                if self.signaling_socket.is_some() {
+                    metrics::RECONNECTS.fetch_add(1, Ordering::Relaxed);
+                }
             }
 
             if let Ok(mute) = self.mute_receiver.try_recv() {
@@ -188,438 +734,1197 @@ impl PhoneRTC {
                 let _ = mute_sender.send(mute);
             }
 
-            if let Ok((connection_state, from)) = connection_change_channel_receiver.try_recv() {
-                if connection_state == RTCPeerConnectionState::Disconnected
-                    || connection_state == RTCPeerConnectionState::Failed
-                {
-                    if let Some(peer_connection) = self.peer_connections.remove(&from) {
-                        let _ = peer_connection.close().await;
-                    }
-                }
-            }
-
-            if let Some(signaling_socket) = &mut self.signaling_socket {
-                let mut should_shutdown = false;
-
-                'message_iterate: {
-                    if let Ok(message) = (*signaling_socket).recv_message() {
-                        match message {
-                            websocket::OwnedMessage::Text(data) => {
-                                let Ok(message): Result<SignalingMessage, serde_json::Error> =
-                                    serde_json::from_str(&data)
-                                else {
-                                    break 'message_iterate;
-                                };
-
-                                println!("webrtc rx {:?}", message);
-
-                                match message {
-                                    SignalingMessage::Join { from } => {
-                                        if from != self.id {
-                                            println!("Join from: {} {}", from, self.id);
-
-                                            let signaling_message_sender_clone =
-                                                signaling_message_sender.clone();
-                                            let from_clone = self.id;
-
-                                            thread::spawn(move || {
-                                                let _ = signaling_message_sender_clone.send(
-                                                    SignalingMessage::JoinAck { from: from_clone },
-                                                );
-                                            });
-                                        }
-                                    }
-                                    SignalingMessage::JoinAck { from } => {
-                                        if from != self.id
-                                            && !self.peer_connections.contains_key(&from)
-                                        {
-                                            println!("JoinAck from: {} {}", from, self.id);
-
-                                            let config = RTCConfiguration {
-                                                ice_servers: vec![RTCIceServer {
-                                                    urls: vec![
-                                                        "stun:stun.l.google.com:19302".to_owned()
-                                                    ],
-                                                    ..Default::default()
-                                                }],
-                                                ..Default::default()
-                                            };
-
-                                            let Ok(new_peer_connection) =
-                                                self.webrtc_api.new_peer_connection(config).await
-                                            else {
-                                                break 'message_iterate;
-                                            };
-
-                                            let Ok(_) = new_peer_connection
-                                                .add_transceiver_from_kind(
-                                                    RTPCodecType::Audio,
-                                                    None,
-                                                )
-                                                .await
-                                            else {
-                                                break 'message_iterate;
-                                            };
-
-                                            if !setup_peer_connection_audio(
-                                                &self.mixer_out,
-                                                &self.mic_in,
-                                                &new_peer_connection,
-                                                &mute_receiver,
-                                            )
-                                            .await
-                                            {
-                                                break 'message_iterate;
-                                            }
-
-                                            let Ok(offer) =
-                                                &(new_peer_connection.create_offer(None).await)
-                                            else {
-                                                break 'message_iterate;
-                                            };
-
-                                            let Ok(_) = new_peer_connection
-                                                .set_local_description(offer.clone())
-                                                .await
-                                            else {
-                                                break 'message_iterate;
-                                            };
-
-                                            let new_connection_change_channel_sender =
-                                                connection_change_channel_sender.clone();
-
-                                            new_peer_connection.on_peer_connection_state_change(
-                                                Box::new(move |connection_state| {
-                                                    println!(
-                                                        "PeerConnection to {} changed to {}",
-                                                        from, connection_state
-                                                    );
-
-                                                    let _ = new_connection_change_channel_sender
-                                                        .send((connection_state, from));
-                                                    Box::pin(async {})
-                                                }),
-                                            );
-
-                                            self.peer_connections.insert(from, new_peer_connection);
-
-                                            let _ = signaling_message_sender.send(
-                                                SignalingMessage::ICEOffer {
-                                                    offer: offer.clone(),
-                                                    from: self.id,
-                                                    to: from,
-                                                },
-                                            );
-                                        }
-                                    }
-                                    SignalingMessage::ICEOffer { offer, from, to } => {
-                                        if from != self.id
-                                            && to == self.id
-                                            && !self.peer_connections.contains_key(&from)
-                                        {
-                                            println!("ICEOffer from: {}", from);
-
-                                            let config = RTCConfiguration {
-                                                ice_servers: vec![RTCIceServer {
-                                                    urls: vec![
-                                                        "stun:stun.l.google.com:19302".to_owned()
-                                                    ],
-                                                    ..Default::default()
-                                                }],
-                                                ..Default::default()
-                                            };
-
-                                            let Ok(new_peer_connection) =
-                                                self.webrtc_api.new_peer_connection(config).await
-                                            else {
-                                                break 'message_iterate;
-                                            };
-
-                                            let Ok(_) = new_peer_connection
-                                                .add_transceiver_from_kind(
-                                                    RTPCodecType::Audio,
-                                                    None,
-                                                )
-                                                .await
-                                            else {
-                                                break 'message_iterate;
-                                            };
-
-                                            if !setup_peer_connection_audio(
-                                                &self.mixer_out,
-                                                &self.mic_in,
-                                                &new_peer_connection,
-                                                &mute_receiver,
-                                            )
-                                            .await
-                                            {
-                                                break 'message_iterate;
-                                            }
-
-                                            let Ok(_) = new_peer_connection
-                                                .set_remote_description(offer)
-                                                .await
-                                            else {
-                                                break 'message_iterate;
-                                            };
-
-                                            let Ok(answer) =
-                                                &(new_peer_connection.create_answer(None).await)
-                                            else {
-                                                break 'message_iterate;
-                                            };
-
-                                            let Ok(_) = new_peer_connection
-                                                .set_local_description(answer.clone())
-                                                .await
-                                            else {
-                                                break 'message_iterate;
-                                            };
-
-                                            let new_ice_candidate_channel_sender =
-                                                ice_candidate_channel_sender.clone();
-
-                                            new_peer_connection.on_ice_candidate(Box::new(
-                                                move |candidate_option| {
-                                                    if let Some(candidate) = candidate_option {
-                                                        let _ = new_ice_candidate_channel_sender
-                                                            .send((candidate, from));
-                                                    }
-                                                    Box::pin(async {})
-                                                },
-                                            ));
-
-                                            let new_connection_change_channel_sender =
-                                                connection_change_channel_sender.clone();
-
-                                            new_peer_connection.on_peer_connection_state_change(
-                                                Box::new(move |connection_state| {
-                                                    println!(
-                                                        "PeerConnection to {} changed to {}",
-                                                        from, connection_state
-                                                    );
-
-                                                    let _ = new_connection_change_channel_sender
-                                                        .send((connection_state, from));
-                                                    Box::pin(async {})
-                                                }),
-                                            );
-
-                                            self.peer_connections.insert(from, new_peer_connection);
-
-                                            let _ = signaling_message_sender.send(
-                                                SignalingMessage::ICEAnswer {
-                                                    answer: answer.clone(),
-                                                    from: self.id,
-                                                    to: from,
-                                                },
-                                            );
-                                        }
-                                    }
-                                    SignalingMessage::ICEAnswer { answer, from, to } => {
-                                        if from != self.id && to == self.id {
-                                            if let Some(peer_connection) =
-                                                self.peer_connections.get(&from)
-                                            {
-                                                println!("ICEAnswer from: {}", from);
-
-                                                let Ok(_) = peer_connection
-                                                    .set_remote_description(answer)
-                                                    .await
-                                                else {
-                                                    break 'message_iterate;
-                                                };
-
-                                                let new_ice_candidate_channel_sender =
-                                                    ice_candidate_channel_sender.clone();
-
-                                                peer_connection.on_ice_candidate(Box::new(
-                                                    move |candidate_option| {
-                                                        if let Some(candidate) = candidate_option {
-                                                            let _ =
-                                                                new_ice_candidate_channel_sender
-                                                                    .send((candidate, from));
-                                                        }
-                                                        Box::pin(async {})
-                                                    },
-                                                ));
-                                            }
-                                        }
-                                    }
-                                    SignalingMessage::ICECandidate {
-                                        candidate,
-                                        from,
-                                        to,
-                                    } => {
-                                        if from != self.id && to == self.id {
-                                            if let Some(peer_connection) =
-                                                self.peer_connections.get(&from)
-                                            {
-                                                println!("ICEAnswer from: {}", from);
-
-                                                let Ok(_) = peer_connection
-                                                    .add_ice_candidate(candidate)
-                                                    .await
-                                                else {
-                                                    break 'message_iterate;
-                                                };
-                                            }
-                                        }
-                                    }
-                                    SignalingMessage::Leave { from } => {
-                                        if from != self.id {
-                                            println!("Leave from: {}", from);
-
-                                            if let Some(peer_connection) =
-                                                self.peer_connections.remove(&from)
-                                            {
-                                                let _ = peer_connection.close().await;
-                                            }
-                                        }
-                                    }
-                                }
-                            }
-                            websocket::OwnedMessage::Binary(_) => {}
-                            websocket::OwnedMessage::Close(_) => {
-                                let _ = signaling_socket.shutdown();
-                                should_shutdown = true;
-
-                                break 'message_iterate;
-                            }
-                            websocket::OwnedMessage::Ping(data) => {
-                                let _ = signaling_pong_sender.send(data);
+            while let Ok(command) = self.command_receiver.try_recv() {
+                match command {
+                    RTCCommand::SwapCalls => self.calls.swap(),
+                    RTCCommand::ToggleHold => self.calls.toggle_hold(),
+                    RTCCommand::ReportStatus => {
+                        let mut slowest_round_trip_time_ms: Option<f64> = None;
+
+                        for peer_connection in self.peer_connections.values() {
+                            if let Some(round_trip_time_ms) =
+                                round_trip_time_ms(peer_connection).await
+                            {
+                                slowest_round_trip_time_ms = Some(
+                                    slowest_round_trip_time_ms
+                                        .unwrap_or_default()
+                                        .max(round_trip_time_ms),
+                                );
                             }
-                            websocket::OwnedMessage::Pong(_) => {}
                         }
-                    }
-                }
 
-                if should_shutdown {
-                    self.signaling_socket = None;
-                } else {
-                    for (candidate, from) in ice_candidate_channel_receiver.try_iter() {
-                        if let Ok(candidate_init) = candidate.to_json() {
-                            let _ = signaling_message_sender.send(SignalingMessage::ICECandidate {
-                                candidate: candidate_init,
-                                from: self.id,
-                                to: from,
+                        let _ = self.event_sender.send(RTCEvent::Status {
+                            signaling_connected: self.signaling_socket.is_some(),
+                            peers: self.peer_connections.len(),
+                            round_trip_time_ms: slowest_round_trip_time_ms,
+                        });
+                    }
+                    RTCCommand::ReportDiagnostics => {
+                        let mut peers = Vec::new();
+
+                        for (id, peer_connection) in &self.peer_connections {
+                            peers.push(PeerDiagnostics {
+                                id: *id,
+                                state: peer_connection.connection_state().to_string(),
+                                round_trip_time_ms: round_trip_time_ms(peer_connection).await,
                             });
                         }
+
+                        let _ = self.event_sender.send(RTCEvent::Diagnostics {
+                            signaling_connected: self.signaling_socket.is_some(),
+                            peers,
+                        });
                     }
+                    RTCCommand::SetReady(ready) => {
+                        self.ready = ready;
 
-                    'sender_loop: for message in signaling_message_receiver.try_iter() {
-                        println!("webrtc pre tx {:?}", message);
+                        if ready {
+                            let peers: Vec<Uuid> = self.peer_connections.keys().copied().collect();
 
-                        let Ok(message_string) = serde_json::to_string(&message) else {
-                            continue 'sender_loop;
-                        };
+                            for peer in peers {
+                                let _ = signaling_message_sender.send(SignalingMessage::Ready {
+                                    from: self.id,
+                                    to: peer,
+                                });
 
-                        let _ = (*signaling_socket)
-                            .send_message(&websocket::Message::text(message_string));
+                                self.confirm_answer(peer);
+                            }
+                        }
+                    }
+                    RTCCommand::SetBusy(busy) => self.busy = busy,
+                    RTCCommand::SetOffHook(off_hook) => {
+                        self.off_hook = off_hook;
+
+                        if !off_hook {
+                            if let Some(reconnect) = self.reconnect.take() {
+                                println!(
+                                    "{} Hung up, no longer reconnecting to {}",
+                                    call_tag(self.call_id),
+                                    reconnect.peer
+                                );
+
+                                let _ = self
+                                    .event_sender
+                                    .send(RTCEvent::Recovering(reconnect.peer, false));
+                            }
+                        }
+                    }
+                    RTCCommand::SendDtmf(samples) => {
+                        let _ = self.dtmf_in.send(samples);
+                    }
+                    RTCCommand::SetCallId(call_id) => self.call_id = call_id,
+                    RTCCommand::LeaveSignaling => {
+                        self.left_signaling = true;
 
-                        println!("webrtc tx {:?}", message);
+                        self.leave_signaling();
                     }
+                    RTCCommand::Shutdown => {
+                        self.shutdown().await;
 
-                    for data in signaling_pong_receiver.try_iter() {
-                        let _ = (*signaling_socket).send_message(&websocket::Message::pong(data));
+                        return;
                     }
                 }
             }
-        }
-    }
-}
 
-static CHANNEL_INDEXER: AtomicI64 = AtomicI64::new(0);
+            if let Ok((connection_state, from)) = connection_change_channel_receiver.try_recv() {
+                self.connection_changed(connection_state, from, &signaling_channels)
+                    .await;
+            }
 
-async fn setup_peer_connection_audio(
-    mixer_out: &mpsc::Sender<MixerMessage>,
-    mic_in: &broadcast::Sender<Vec<f32>>,
-    new_peer_connection: &RTCPeerConnection,
-    mute_receiver: &watch::Receiver<bool>,
-) -> bool {
-    const SAMPLE_RATE_PER_MILLISECOND: f32 = (SAMPLE_RATE / 1000) as f32;
+            self.drop_expired_peers().await;
 
-    const FRAME_LENGTH_1200: usize = (SAMPLE_RATE_PER_MILLISECOND * 60.0) as usize;
+            self.retry_reconnect(&signaling_channels).await;
 
-    let output_track = Arc::new(TrackLocalStaticRTP::new(
-        RTCRtpCodecCapability {
-            mime_type: MIME_TYPE_OPUS.to_owned(),
-            clock_rate: SAMPLE_RATE,
-            channels: 1,
-            ..Default::default()
-        },
-        "track-audio".to_string(),
-        "webrtc-rs".to_owned(),
-    ));
+            if self.last_media_check.elapsed() >= MEDIA_STALL_CHECK_INTERVAL {
+                self.last_media_check = Instant::now();
 
-    let Ok(rtcp_sender) = new_peer_connection
-        .add_track(Arc::clone(&output_track) as Arc<dyn TrackLocal + Send + Sync>)
-        .await
-    else {
-        return false;
-    };
+                if let Some(stall_timeout) = config::get().ice_restart_after_stall {
+                    self.restart_stalled_peers(stall_timeout, &signaling_channels)
+                        .await;
+                }
+            }
 
-    let mut mic_receiver = mic_in.subscribe();
-    let mute_receiver_encoder = mute_receiver.clone();
+            let Some(signaling_socket) = &mut self.signaling_socket else {
+                continue;
+            };
 
-    tokio::spawn(async move {
-        let Ok(mut encoder) = Encoder::new(SAMPLE_RATE, Channels::Mono, opus::Application::Voip)
-        else {
-            return Err(());
-        };
+            match SignalingRead::from(signaling_socket.recv_message()) {
+                SignalingRead::Text(data) => {
+                    match serde_json::from_str::<SignalingMessage>(&data) {
+                        Ok(message) => {
+                            self.handle_signaling_message(message, &signaling_channels)
+                                .await;
+                        }
+                        Err(source) => {
+                            println!("Signaling: {}", MessageError::Decode { data, source });
+                        }
+                    }
+                }
+                SignalingRead::Lost(reason) => {
+                    println!("Signaling {}, reconnecting", reason);
 
-        let mut mute_receiver_encoder = mute_receiver_encoder.clone();
+                    let _ = signaling_socket.shutdown();
 
-        let audio_send_task = tokio::spawn(async move {
-            let payloader = OpusPayloader;
-            let sequencer = new_random_sequencer();
-            let mut packetizer = new_packetizer(
-                1276,
-                120,
-                69,
-                Box::new(payloader),
-                Box::new(sequencer),
-                SAMPLE_RATE,
-            );
+                    self.signaling_socket = None;
 
-            loop {
-                let Ok(next_audio_frames) = mic_receiver.recv().await else {
                     continue;
-                };
-
-                let mute = *mute_receiver_encoder.borrow_and_update();
+                }
+                SignalingRead::Ping(data) => {
+                    let _ = signaling_pong_sender.send(data);
+                }
+                SignalingRead::Ignored => {}
+            }
 
-                let next_audio_frames_processed = next_audio_frames
-                    .into_iter()
-                    .map(|sample| if mute { 0.0 } else { sample })
-                    .collect::<Vec<f32>>();
+            let Some(signaling_socket) = &mut self.signaling_socket else {
+                continue;
+            };
+
+            for (candidate, from) in ice_candidate_channel_receiver.try_iter() {
+                if let Ok(candidate_init) = candidate.to_json() {
+                    let _ = signaling_message_sender.send(SignalingMessage::ICECandidate {
+                        candidate: candidate_init,
+                        from: self.id,
+                        to: from,
+                    });
+                }
+            }
 
-                let encode_result = encoder.encode_vec_float(
-                    next_audio_frames_processed.as_slice(),
-                    next_audio_frames_processed.len(),
-                );
+            'sender_loop: for message in signaling_message_receiver.try_iter() {
+                let message_string = match serde_json::to_string(&message) {
+                    Ok(message_string) => message_string,
+                    Err(source) => {
+                        println!(
+                            "Signaling: {}",
+                            MessageError::Encode {
+                                what: format!("{:?}", message),
+                                source,
+                            }
+                        );
 
-                let Ok(next_audio_frames) = encode_result else {
-                    continue;
+                        continue 'sender_loop;
+                    }
                 };
 
-                let number_frames = next_audio_frames.len();
-
-                let Ok(rtp_packets) =
-                    packetizer.packetize(&Bytes::from(next_audio_frames), number_frames as u32)
-                else {
-                    continue;
-                };
+                let _ = (*signaling_socket).send_message(&websocket::Message::text(message_string));
 
-                for rtp_packet in rtp_packets {
-                    let _ = output_track.write_rtp(&rtp_packet).await;
+                if self.message_log.allow() {
+                    println!("webrtc tx {:?}", message);
                 }
             }
-        });
 
-        let mut rtcp_buf = vec![0u8; 1500];
+            for data in signaling_pong_receiver.try_iter() {
+                let _ = (*signaling_socket).send_message(&websocket::Message::pong(data));
+            }
+        }
+    }
 
-        while let Ok((_, _)) = rtcp_sender.read(&mut rtcp_buf).await {}
+    async fn shutdown(&mut self) {
+        self.leave_signaling();
+
+        for (peer, peer_connection) in self.peer_connections.drain() {
+            let _ = peer_connection.close().await;
+
+            self.calls.peer_left(peer);
+        }
+    }
+
+    // The socket is sync, so this works from Drop too
+    fn leave_signaling(&mut self) {
+        if let Some(mut signaling_socket) = self.signaling_socket.take() {
+            if let Ok(message_string) =
+                serde_json::to_string(&SignalingMessage::Leave { from: self.id })
+            {
+                let _ = signaling_socket.send_message(&websocket::Message::text(message_string));
+
+                println!("webrtc tx: {:?}", SignalingMessage::Leave { from: self.id });
+            }
+
+            let _ = signaling_socket.shutdown();
+        }
+    }
+
+    // Applies one signaling message to the peer map. Replies go out through the channels rather
+    // than the socket so the handshake can be driven without a live connection.
+    pub async fn handle_signaling_message(
+        &mut self,
+        message: SignalingMessage,
+        channels: &SignalingChannels,
+    ) {
+        if self.message_log.allow() {
+            println!("webrtc rx {:?}", message);
+        }
+
+        match message {
+            SignalingMessage::Join {
+                from,
+                resume,
+                versions,
+            } => {
+                if from == self.id || !self.negotiate_version(from, &versions) {
+                    return;
+                }
+
+                println!("Join from: {} {}", from, self.id);
+
+                if self.reject_if_busy(from, channels) {
+                    return;
+                }
+
+                let resumed = resume
+                    && self
+                        .peer_connections
+                        .get(&from)
+                        .is_some_and(|peer_connection| {
+                            peer_connection.connection_state() == RTCPeerConnectionState::Connected
+                        });
+
+                if resumed {
+                    println!("Keeping the call with {} across its reconnect", from);
+                } else if let Some(stale_peer_connection) = self.peer_connections.remove(&from) {
+                    println!("Replacing stale session for: {}", from);
+
+                    let _ = stale_peer_connection.close().await;
+
+                    self.ready_peers.remove(&from);
+                    self.forget_disconnect(from);
+                    self.calls.peer_left(from);
+                }
+
+                let signaling_message_sender_clone = channels.signaling_message_sender.clone();
+                let from_clone = self.id;
+
+                thread::spawn(move || {
+                    let _ = signaling_message_sender_clone.send(SignalingMessage::JoinAck {
+                        from: from_clone,
+                        versions: PROTOCOL_VERSIONS.to_vec(),
+                    });
+                });
+
+                if !resumed && self.initiates(from) {
+                    self.send_offer(from, channels).await;
+                }
+            }
+            SignalingMessage::JoinAck { from, versions } => {
+                if from == self.id
+                    || !self.negotiate_version(from, &versions)
+                    || !self.initiates(from)
+                    || self.peer_connections.contains_key(&from)
+                {
+                    return;
+                }
+
+                println!("JoinAck from: {} {}", from, self.id);
+
+                if self.reject_if_busy(from, channels) {
+                    return;
+                }
+
+                self.send_offer(from, channels).await;
+            }
+            SignalingMessage::ICEOffer {
+                offer,
+                from,
+                to,
+                restart,
+            } => {
+                if from == self.id || to != self.id {
+                    return;
+                }
+
+                // A peer we don't know is answered as a new session
+                if let Some(peer_connection) = self.peer_connections.get(&from).filter(|_| restart)
+                {
+                    println!("ICE restart from: {}", from);
+
+                    match answer_ice_restart(peer_connection, from, offer, channels).await {
+                        Ok(answer) => {
+                            let _ = channels.signaling_message_sender.send(
+                                SignalingMessage::ICEAnswer {
+                                    answer,
+                                    from: self.id,
+                                    to: from,
+                                },
+                            );
+                        }
+                        Err(error) => println!("ICE restart from {} failed: {}", from, error),
+                    }
+
+                    return;
+                }
+
+                println!("ICEOffer from: {}", from);
+
+                if self.reject_if_busy(from, channels) {
+                    return;
+                }
+
+                // Only the initiating side offers, so a second offer means it started over
+                if let Some(stale_peer_connection) = self.peer_connections.remove(&from) {
+                    println!("Replacing stale session for: {}", from);
+
+                    let _ = stale_peer_connection.close().await;
+
+                    self.ready_peers.remove(&from);
+                    self.forget_disconnect(from);
+                    self.calls.peer_left(from);
+                }
+
+                for attempt in 1..=NEGOTIATION_ATTEMPTS {
+                    match self.try_answer(from, offer.clone(), channels).await {
+                        Ok(()) => break,
+                        Err(error) => println!(
+                            "Answering {} failed ({}/{}): {}",
+                            from, attempt, NEGOTIATION_ATTEMPTS, error
+                        ),
+                    }
+                }
+            }
+            SignalingMessage::ICEAnswer { answer, from, to } => {
+                if from == self.id || to != self.id {
+                    return;
+                }
+
+                let Some(peer_connection) = self.peer_connections.get(&from) else {
+                    return;
+                };
+
+                println!("ICEAnswer from: {}", from);
+
+                if let Err(error) = peer_connection.set_remote_description(answer).await {
+                    println!("Applying the answer from {} failed: {}", from, error);
+
+                    // Don't leave a half negotiated connection blocking the next attempt
+                    if let Some(peer_connection) = self.peer_connections.remove(&from) {
+                        let _ = peer_connection.close().await;
+
+                        self.ready_peers.remove(&from);
+                        self.forget_disconnect(from);
+                        self.calls.peer_left(from);
+                    }
+
+                    // Start over once, a peer that keeps sending bad answers is left alone
+                    if self.renegotiated_peers.insert(from) {
+                        self.send_offer(from, channels).await;
+                    }
+
+                    return;
+                }
+
+                forward_ice_candidates(peer_connection, from, channels);
+            }
+            SignalingMessage::ICECandidate {
+                candidate,
+                from,
+                to,
+            } => {
+                if from == self.id || to != self.id {
+                    return;
+                }
+
+                let Some(peer_connection) = self.peer_connections.get(&from) else {
+                    return;
+                };
+
+                if self.message_log.allow() {
+                    println!("ICECandidate from: {}", from);
+                }
+
+                let _ = peer_connection.add_ice_candidate(candidate).await;
+            }
+            SignalingMessage::Leave { from } => {
+                if from == self.id {
+                    return;
+                }
+
+                println!("Leave from: {}", from);
+
+                if let Some(peer_connection) = self.peer_connections.remove(&from) {
+                    let _ = peer_connection.close().await;
+
+                    self.ready_peers.remove(&from);
+                    self.forget_disconnect(from);
+                    self.calls.peer_left(from);
+                }
+            }
+            SignalingMessage::Ready { from, to } => {
+                if from == self.id || to != self.id || !self.peer_connections.contains_key(&from) {
+                    return;
+                }
+
+                self.ready_peers.insert(from);
+                self.confirm_answer(from);
+            }
+            SignalingMessage::Busy { from, to } => {
+                if from == self.id || to != self.id {
+                    return;
+                }
+
+                println!("{} {} is busy", call_tag(self.call_id), from);
+
+                if let Some(peer_connection) = self.peer_connections.remove(&from) {
+                    let _ = peer_connection.close().await;
+
+                    self.ready_peers.remove(&from);
+                    self.forget_disconnect(from);
+                    self.calls.peer_left(from);
+                }
+
+                let _ = self.event_sender.send(RTCEvent::Busy(from));
+            }
+        }
+    }
+
+    // Picks the highest version both sides speak, peers without one in common are ignored
+    fn negotiate_version(&mut self, peer: Uuid, versions: &[u32]) -> bool {
+        let Some(version) = PROTOCOL_VERSIONS
+            .iter()
+            .copied()
+            .filter(|version| versions.contains(version))
+            .max()
+        else {
+            println!(
+                "No common protocol version with {} (theirs {:?}, ours {:?})",
+                peer, versions, PROTOCOL_VERSIONS
+            );
+
+            return false;
+        };
+
+        if self.peer_versions.insert(peer, version) != Some(version) {
+            println!("Using protocol version {} with {}", version, peer);
+        }
+
+        true
+    }
+
+    // A peer we aren't connected to is turned away while we're off-hook in a call, unless call
+    // waiting has room for it, and always once max_peers are connected. Either way it gets Busy
+    fn reject_if_busy(&self, peer: Uuid, channels: &SignalingChannels) -> bool {
+        if self.peer_connections.contains_key(&peer) {
+            return false;
+        }
+
+        let max_peers = config::get().max_peers;
+        let can_wait = config::get().call_waiting_enabled
+            && matches!(self.calls.state(), CallState::Active(_));
+
+        let reason = if self.peer_connections.len() >= max_peers {
+            format!("already connected to {} peers", max_peers)
+        } else if self.busy && !can_wait {
+            String::from("busy")
+        } else {
+            return false;
+        };
+
+        println!(
+            "{} Turning away {}, {}",
+            call_tag(self.call_id),
+            peer,
+            reason
+        );
+
+        let _ = channels
+            .signaling_message_sender
+            .send(SignalingMessage::Busy {
+                from: self.id,
+                to: peer,
+            });
+
+        true
+    }
+
+    // When both phones join at once each sees the other's Join and JoinAck. If both offered,
+    // each would ignore the other's offer since it already has a connection for that peer and
+    // the call never connects. Instead the lower id always offers and the higher one only answers
+    fn initiates(&self, peer: Uuid) -> bool {
+        self.id < peer
+    }
+
+    async fn send_offer(&mut self, to: Uuid, channels: &SignalingChannels) {
+        for attempt in 1..=NEGOTIATION_ATTEMPTS {
+            match self.try_offer(to, channels).await {
+                Ok(()) => return,
+                Err(error) => println!(
+                    "Offering to {} failed ({}/{}): {}",
+                    to, attempt, NEGOTIATION_ATTEMPTS, error
+                ),
+            }
+        }
+    }
+
+    // A peer whose packet count hasn't moved for stall_timeout gets an ICE restart, which finds a
+    // new path after e.g. roaming between networks. Only the side that offered restarts, so both
+    // noticing doesn't end in crossed offers, and at most once per ice_restart_min_interval
+    async fn restart_stalled_peers(
+        &mut self,
+        stall_timeout: Duration,
+        channels: &SignalingChannels,
+    ) {
+        self.media_progress
+            .retain(|peer, _| self.peer_connections.contains_key(peer));
+        self.last_ice_restarts
+            .retain(|peer, _| self.peer_connections.contains_key(peer));
+
+        let peers: Vec<Uuid> = self.peer_connections.keys().copied().collect();
+
+        for peer in peers {
+            let Some(peer_connection) = self.peer_connections.get(&peer) else {
+                continue;
+            };
+
+            if peer_connection.connection_state() != RTCPeerConnectionState::Connected
+                && !self.disconnected_peers.contains_key(&peer)
+            {
+                continue;
+            }
+
+            let packets_received = peer_connection
+                .get_stats()
+                .await
+                .reports
+                .values()
+                .map(|report| match report {
+                    StatsReportType::InboundRTP(inbound) => inbound.packets_received,
+                    _ => 0,
+                })
+                .sum::<u64>();
+
+            let progress = self
+                .media_progress
+                .entry(peer)
+                .or_insert((packets_received, Instant::now()));
+
+            if progress.0 != packets_received {
+                *progress = (packets_received, Instant::now());
+
+                continue;
+            }
+
+            let stalled_for = progress.1.elapsed();
+
+            if stalled_for < stall_timeout || !self.initiates(peer) {
+                continue;
+            }
+
+            if self
+                .last_ice_restarts
+                .get(&peer)
+                .is_some_and(|restarted_at| {
+                    restarted_at.elapsed() < config::get().ice_restart_min_interval
+                })
+            {
+                continue;
+            }
+
+            println!(
+                "{} No audio from {} for {:?}, restarting ICE",
+                call_tag(self.call_id),
+                peer,
+                stalled_for
+            );
+
+            self.last_ice_restarts.insert(peer, Instant::now());
+
+            if let Err(error) = self.restart_ice(peer, channels).await {
+                println!("ICE restart with {} failed: {}", peer, error);
+            }
+        }
+    }
+
+    // The answer comes back as a normal ICEAnswer for the connection we already have
+    async fn restart_ice(
+        &mut self,
+        peer: Uuid,
+        channels: &SignalingChannels,
+    ) -> Result<(), String> {
+        let Some(peer_connection) = self.peer_connections.get(&peer) else {
+            return Ok(());
+        };
+
+        let offer = peer_connection
+            .create_offer(Some(RTCOfferOptions {
+                ice_restart: true,
+                ..Default::default()
+            }))
+            .await
+            .map_err(|error| format!("create_offer: {}", error))?;
+
+        // Starts the gathering timeout over for the new candidates
+        forward_ice_candidates(peer_connection, peer, channels);
+
+        peer_connection
+            .set_local_description(offer.clone())
+            .await
+            .map_err(|error| format!("set_local_description: {}", error))?;
+
+        let _ = channels
+            .signaling_message_sender
+            .send(SignalingMessage::ICEOffer {
+                offer,
+                from: self.id,
+                to: peer,
+                restart: true,
+            });
+
+        Ok(())
+    }
+
+    // A failed step closes the new connection instead of leaving it half set up
+    async fn try_offer(&mut self, to: Uuid, channels: &SignalingChannels) -> Result<(), String> {
+        let Some((new_peer_connection, hold_sender)) = self.new_peer_connection(to, channels).await
+        else {
+            return Err(String::from("couldn't create a peer connection"));
+        };
+
+        let offer = match new_peer_connection.create_offer(None).await {
+            Ok(offer) => offer,
+            Err(error) => {
+                let _ = new_peer_connection.close().await;
+
+                return Err(format!("create_offer: {}", error));
+            }
+        };
+
+        if let Err(error) = new_peer_connection
+            .set_local_description(offer.clone())
+            .await
+        {
+            let _ = new_peer_connection.close().await;
+
+            return Err(format!("set_local_description: {}", error));
+        }
+
+        self.peer_connections.insert(to, new_peer_connection);
+        self.calls.track_peer(to, hold_sender);
+
+        let _ = channels
+            .signaling_message_sender
+            .send(SignalingMessage::ICEOffer {
+                offer,
+                from: self.id,
+                to,
+                restart: false,
+            });
+
+        Ok(())
+    }
+
+    async fn try_answer(
+        &mut self,
+        from: Uuid,
+        offer: RTCSessionDescription,
+        channels: &SignalingChannels,
+    ) -> Result<(), String> {
+        let Some((new_peer_connection, hold_sender)) =
+            self.new_peer_connection(from, channels).await
+        else {
+            return Err(String::from("couldn't create a peer connection"));
+        };
+
+        if let Err(error) = new_peer_connection.set_remote_description(offer).await {
+            let _ = new_peer_connection.close().await;
+
+            return Err(format!("set_remote_description: {}", error));
+        }
+
+        let answer = match new_peer_connection.create_answer(None).await {
+            Ok(answer) => answer,
+            Err(error) => {
+                let _ = new_peer_connection.close().await;
+
+                return Err(format!("create_answer: {}", error));
+            }
+        };
+
+        if let Err(error) = new_peer_connection
+            .set_local_description(answer.clone())
+            .await
+        {
+            let _ = new_peer_connection.close().await;
+
+            return Err(format!("set_local_description: {}", error));
+        }
+
+        forward_ice_candidates(&new_peer_connection, from, channels);
+
+        self.peer_connections.insert(from, new_peer_connection);
+        self.calls.track_peer(from, hold_sender);
+
+        let _ = channels
+            .signaling_message_sender
+            .send(SignalingMessage::ICEAnswer {
+                answer,
+                from: self.id,
+                to: from,
+            });
+
+        Ok(())
+    }
+
+    // Both ends are off-hook and connected
+    fn confirm_answer(&self, peer: Uuid) {
+        if self.ready && self.ready_peers.contains(&peer) {
+            let _ = self.event_sender.send(RTCEvent::AnswerConfirmed(peer));
+        }
+    }
+
+    pub fn peer_ids(&self) -> impl Iterator<Item = &Uuid> {
+        self.peer_connections.keys()
+    }
+
+    // Takes &mut self only so the future stays Send, the websocket client isn't Sync
+    async fn new_peer_connection(
+        &mut self,
+        from: Uuid,
+        channels: &SignalingChannels,
+    ) -> Option<(RTCPeerConnection, watch::Sender<HoldState>)> {
+        let config = RTCConfiguration {
+            ice_servers: vec![RTCIceServer {
+                urls: config::get().ice_servers.clone(),
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+
+        let Ok(new_peer_connection) = self.webrtc_api.new_peer_connection(config).await else {
+            return None;
+        };
+
+        let Ok(_) = new_peer_connection
+            .add_transceiver_from_kind(RTPCodecType::Audio, None)
+            .await
+        else {
+            return None;
+        };
+
+        let (hold_sender, hold_receiver) = watch::channel(self.calls.hold_state(from));
+
+        if !setup_peer_connection_audio(
+            &self.mixer_out,
+            &self.mic_in,
+            &self.dtmf_in,
+            &new_peer_connection,
+            &channels.mute_receiver,
+            &hold_receiver,
+        )
+        .await
+        {
+            return None;
+        }
+
+        let connection_change_sender = channels.connection_change_sender.clone();
+
+        new_peer_connection.on_peer_connection_state_change(Box::new(move |connection_state| {
+            println!("PeerConnection to {} changed to {}", from, connection_state);
+
+            let _ = connection_change_sender.send((connection_state, from));
+            Box::pin(async {})
+        }));
+
+        Some((new_peer_connection, hold_sender))
+    }
+}
+
+// Best effort for when the RTC is dropped without shutdown(). The Leave goes out synchronously,
+// but closing a peer connection is async, so that only happens if a runtime is still around to
+// run it; otherwise peers notice through ICE timing out
+impl Drop for PhoneRTC {
+    fn drop(&mut self) {
+        self.leave_signaling();
+
+        if self.peer_connections.is_empty() {
+            return;
+        }
+
+        let peer_connections: Vec<RTCPeerConnection> = self
+            .peer_connections
+            .drain()
+            .map(|(_, peer_connection)| peer_connection)
+            .collect();
+
+        if let Ok(runtime) = tokio::runtime::Handle::try_current() {
+            runtime.spawn(async move {
+                for peer_connection in peer_connections {
+                    let _ = peer_connection.close().await;
+                }
+            });
+        }
+    }
+}
+
+pub struct SignalingChannels {
+    pub signaling_message_sender: mpsc::Sender<SignalingMessage>,
+    pub ice_candidate_sender: mpsc::Sender<(RTCIceCandidate, Uuid)>,
+    pub connection_change_sender: mpsc::Sender<(RTCPeerConnectionState, Uuid)>,
+    pub mute_receiver: watch::Receiver<MuteState>,
+}
+
+// Starts the encoder fresh after a mute instead of predicting from a long run of silence, or
+// the first word comes out garbled. The peer's decoder copes with the restart
+struct UnmuteReset {
+    was_muted: bool,
+}
+
+impl UnmuteReset {
+    fn new() -> Self {
+        Self { was_muted: true }
+    }
+
+    // Returns whether the encoder was reset
+    fn update(&mut self, encoder: &mut Encoder, mute: bool) -> bool {
+        let unmuted = self.was_muted && !mute;
+
+        if unmuted {
+            let _ = encoder.reset_state();
+        }
+
+        self.was_muted = mute;
+
+        unmuted
+    }
+}
+
+// Capture never waits for us, so falling behind loses the oldest frames, counted in
+// MIC_FRAMES_DROPPED. None once capture is gone
+async fn next_mic_frame(mic_receiver: &mut broadcast::Receiver<Vec<f32>>) -> Option<Vec<f32>> {
+    loop {
+        match mic_receiver.recv().await {
+            Ok(frame) => return Some(frame),
+            Err(broadcast::error::RecvError::Lagged(dropped)) => {
+                metrics::MIC_FRAMES_DROPPED.fetch_add(dropped, Ordering::Relaxed);
+            }
+            Err(broadcast::error::RecvError::Closed) => return None,
+        }
+    }
+}
+
+// The last round trip measured on the nominated candidate pair, if there is one yet
+async fn round_trip_time_ms(peer_connection: &RTCPeerConnection) -> Option<f64> {
+    let stats = peer_connection.get_stats().await;
+
+    stats.reports.values().find_map(|report| match report {
+        StatsReportType::CandidatePair(pair) if pair.nominated => {
+            Some(pair.current_round_trip_time * 1000.0)
+        }
+        _ => None,
+    })
+}
+
+fn forward_ice_candidates(
+    peer_connection: &RTCPeerConnection,
+    to: Uuid,
+    channels: &SignalingChannels,
+) {
+    let ice_candidate_sender = channels.ice_candidate_sender.clone();
+    let gathering_start = Instant::now();
+
+    peer_connection.on_ice_candidate(Box::new(move |candidate_option| {
+        if let Some(candidate) = candidate_option {
+            if config::get()
+                .ice_gathering_timeout
+                .is_some_and(|timeout| gathering_start.elapsed() > timeout)
+            {
+                println!("Dropping late ICE candidate for: {}", to);
+            } else if !candidate_allowed(&candidate) {
+                println!("Filtered ICE candidate for {}: {}", to, candidate);
+            } else {
+                let _ = ice_candidate_sender.send((candidate, to));
+            }
+        }
+        Box::pin(async {})
+    }));
+}
+
+// Applies a restart offer to a connection in place, keeping its tracks and the call
+async fn answer_ice_restart(
+    peer_connection: &RTCPeerConnection,
+    from: Uuid,
+    offer: RTCSessionDescription,
+    channels: &SignalingChannels,
+) -> Result<RTCSessionDescription, String> {
+    peer_connection
+        .set_remote_description(offer)
+        .await
+        .map_err(|error| format!("set_remote_description: {}", error))?;
+
+    let answer = peer_connection
+        .create_answer(None)
+        .await
+        .map_err(|error| format!("create_answer: {}", error))?;
+
+    forward_ice_candidates(peer_connection, from, channels);
+
+    peer_connection
+        .set_local_description(answer.clone())
+        .await
+        .map_err(|error| format!("set_local_description: {}", error))?;
+
+    Ok(answer)
+}
+
+fn candidate_allowed(candidate: &RTCIceCandidate) -> bool {
+    let config = config::get();
+
+    if !config.ice_allow_host_candidates && candidate.typ == RTCIceCandidateType::Host {
+        return false;
+    }
+
+    if !config.ice_allow_mdns_candidates && candidate.address.ends_with(".local") {
+        return false;
+    }
+
+    if !config.ice_allow_ipv6_candidates && candidate.address.parse::<Ipv6Addr>().is_ok() {
+        return false;
+    }
+
+    true
+}
+
+fn persistent_id() -> Uuid {
+    if let Ok(contents) = fs::read_to_string(&config::get().id_path) {
+        if let Ok(id) = Uuid::parse_str(contents.trim()) {
+            return id;
+        }
+    }
+
+    let id = Uuid::new_v4();
+
+    if fs::write(&config::get().id_path, id.to_string()).is_err() {
+        println!("Failed to persist phone id!");
+    }
+
+    id
+}
+
+// Signaling protocol versions this phone speaks, peers settle on the highest common one so
+// phones can be upgraded one at a time
+const PROTOCOL_VERSIONS: &[u32] = &[1];
+
+// Phones from before versioning only sent Join/JoinAck without a list
+fn legacy_protocol_versions() -> Vec<u32> {
+    vec![1]
+}
+
+// Offer/answer setup is tried this many times before giving up on a peer
+const NEGOTIATION_ATTEMPTS: u32 = 2;
+
+// Only what we offer, the answer decides the payload type actually used on the wire
+const OPUS_PAYLOAD_TYPE: u8 = 120;
+
+// Every Opus packet has at least a TOC byte
+const MIN_OPUS_PAYLOAD_LENGTH: usize = 1;
+
+// Too short to be Opus. An empty payload would be decoded as packet loss, so these are skipped
+fn is_runt(payload: &[u8]) -> bool {
+    payload.len() < MIN_OPUS_PAYLOAD_LENGTH
+}
+
+// DTX sends a TOC byte or two every so often instead of silent frames
+const DTX_PAYLOAD_LENGTH: usize = 2;
+
+const COMFORT_NOISE_FRAME: Duration = Duration::from_millis(20);
+
+// How often received packet counts are compared to spot a stall
+const MEDIA_STALL_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+static CHANNEL_INDEXER: AtomicI64 = AtomicI64::new(0);
+
+// One peer's incoming audio sources by SSRC, each with a stable mixer channel. A track that
+// turns up again for an SSRC that's already playing, e.g. after a renegotiation, takes over its
+// channel and the older track goes quiet, so the same audio is never mixed in twice. A second
+// SSRC from the same peer gets a channel of its own. The channel is closed once the last track
+// on it has ended
+#[derive(Default)]
+struct TrackChannels {
+    // Channel, tracks still open on it, and the generation of the newest one
+    sources: HashMap<u32, (i64, usize, u64)>,
+    generations: u64,
+}
+
+impl TrackChannels {
+    // The channel for a new track, its generation, and whether the channel needs opening
+    fn acquire(&mut self, ssrc: u32) -> (i64, u64, bool) {
+        self.generations += 1;
+
+        let (channel_number, tracks, generation) = self
+            .sources
+            .entry(ssrc)
+            .or_insert_with(|| (CHANNEL_INDEXER.fetch_add(1, Ordering::SeqCst), 0, 0));
+
+        *tracks += 1;
+        *generation = self.generations;
+
+        (*channel_number, self.generations, *tracks == 1)
+    }
+
+    // Only the newest track for an SSRC feeds its channel
+    fn is_current(&self, ssrc: u32, generation: u64) -> bool {
+        self.sources
+            .get(&ssrc)
+            .is_some_and(|(_, _, current)| *current == generation)
+    }
+
+    // The channel to close, once no track is left on it
+    fn release(&mut self, ssrc: u32) -> Option<i64> {
+        let (channel_number, tracks, _) = self.sources.get_mut(&ssrc)?;
+
+        *tracks -= 1;
+
+        if *tracks > 0 {
+            return None;
+        }
+
+        let channel_number = *channel_number;
+
+        self.sources.remove(&ssrc);
+
+        Some(channel_number)
+    }
+}
+
+async fn setup_peer_connection_audio(
+    mixer_out: &mpsc::Sender<MixerMessage>,
+    mic_in: &broadcast::Sender<Vec<f32>>,
+    dtmf_in: &broadcast::Sender<Vec<f32>>,
+    new_peer_connection: &RTCPeerConnection,
+    mute_receiver: &watch::Receiver<MuteState>,
+    hold_receiver: &watch::Receiver<HoldState>,
+) -> bool {
+    const SAMPLE_RATE_PER_MILLISECOND: f32 = (SAMPLE_RATE / 1000) as f32;
+
+    const FRAME_LENGTH_1200: usize = (SAMPLE_RATE_PER_MILLISECOND * 60.0) as usize;
+    const COMFORT_NOISE_FRAME_LENGTH: usize =
+        (SAMPLE_RATE_PER_MILLISECOND * COMFORT_NOISE_FRAME.as_millis() as f32) as usize;
+
+    const _: () = {
+        assert!(FRAME_LENGTH_1200 == config::opus_frame_length(600));
+        assert!(
+            COMFORT_NOISE_FRAME_LENGTH
+                == config::opus_frame_length(COMFORT_NOISE_FRAME.as_millis() as usize * 10)
+        );
+    };
+
+    let output_track = Arc::new(TrackLocalStaticRTP::new(
+        RTCRtpCodecCapability {
+            mime_type: MIME_TYPE_OPUS.to_owned(),
+            clock_rate: SAMPLE_RATE,
+            channels: 1,
+            ..Default::default()
+        },
+        "track-audio".to_string(),
+        "webrtc-rs".to_owned(),
+    ));
+
+    let Ok(rtcp_sender) = new_peer_connection
+        .add_track(Arc::clone(&output_track) as Arc<dyn TrackLocal + Send + Sync>)
+        .await
+    else {
+        return false;
+    };
+
+    let mut mic_receiver = mic_in.subscribe();
+    let mut dtmf_receiver = dtmf_in.subscribe();
+    let mute_receiver_encoder = mute_receiver.clone();
+    let mut hold_receiver_encoder = hold_receiver.clone();
+
+    tokio::spawn(async move {
+        let Ok(mut encoder) = Encoder::new(SAMPLE_RATE, Channels::Mono, opus::Application::Voip)
+        else {
+            return Err(());
+        };
+
+        let mut mute_receiver_encoder = mute_receiver_encoder.clone();
+
+        let audio_send_task = tokio::spawn(async move {
+            let payloader = OpusPayloader;
+            let sequencer = new_random_sequencer();
+            // The track rewrites the payload type to the negotiated one for each binding
+            let mut packetizer = new_packetizer(
+                1276,
+                OPUS_PAYLOAD_TYPE,
+                69,
+                Box::new(payloader),
+                Box::new(sequencer),
+                SAMPLE_RATE,
+            );
+
+            let hold_music = tone::hold_music();
+            let mut hold_music_position = 0;
+            let mut unmute_reset = UnmuteReset::new();
+            let mut pending_dtmf = VecDeque::new();
+
+            loop {
+                let Some(next_audio_frames) = next_mic_frame(&mut mic_receiver).await else {
+                    break;
+                };
+
+                let hold = *hold_receiver_encoder.borrow_and_update();
+                let mute = mute_receiver_encoder
+                    .borrow_and_update()
+                    .input_silenced(hold);
+
+                unmute_reset.update(&mut encoder, mute);
+
+                let mut next_audio_frames_processed =
+                    if hold == HoldState::Local && config::get().hold_music_enabled {
+                        next_audio_frames
+                            .iter()
+                            .map(|_| {
+                                let sample = hold_music[hold_music_position];
+
+                                hold_music_position = (hold_music_position + 1) % hold_music.len();
+
+                                sample
+                            })
+                            .collect::<Vec<f32>>()
+                    } else {
+                        next_audio_frames
+                            .into_iter()
+                            .map(|sample| if mute { 0.0 } else { sample })
+                            .collect::<Vec<f32>>()
+                    };
+
+                while let Ok(samples) = dtmf_receiver.try_recv() {
+                    pending_dtmf.extend(samples);
+                }
+
+                // Replaces the mic, so the far end hears clean tones. Held peers don't get them
+                if hold == HoldState::None {
+                    for sample in next_audio_frames_processed.iter_mut() {
+                        let Some(tone) = pending_dtmf.pop_front() else {
+                            break;
+                        };
+
+                        *sample = tone;
+                    }
+                }
+
+                let encode_result = encoder.encode_vec_float(
+                    next_audio_frames_processed.as_slice(),
+                    next_audio_frames_processed.len(),
+                );
+
+                let Ok(next_audio_frames) = encode_result else {
+                    continue;
+                };
+
+                let number_frames = next_audio_frames.len();
+
+                let Ok(rtp_packets) =
+                    packetizer.packetize(&Bytes::from(next_audio_frames), number_frames as u32)
+                else {
+                    continue;
+                };
+
+                for rtp_packet in rtp_packets {
+                    let _ = output_track.write_rtp(&rtp_packet).await;
+                }
+            }
+        });
+
+        let mut rtcp_buf = vec![0u8; 1500];
+
+        while let Ok((_, _)) = rtcp_sender.read(&mut rtcp_buf).await {}
 
         audio_send_task.abort();
 
@@ -628,29 +1933,156 @@ async fn setup_peer_connection_audio(
 
     let mixer_sender = mixer_out.clone();
     let mute_receiver_decoder = mute_receiver.clone();
+    let hold_receiver_decoder = hold_receiver.clone();
+    let track_channels = Arc::new(Mutex::new(TrackChannels::default()));
 
     new_peer_connection.on_track(Box::new(move |remote_track, rtcp_receiver, _| {
-        let channel_number = CHANNEL_INDEXER.fetch_add(1, Ordering::SeqCst);
+        let codec = remote_track.codec();
+
+        if !codec
+            .capability
+            .mime_type
+            .eq_ignore_ascii_case(MIME_TYPE_OPUS)
+        {
+            println!("Ignoring {} track", codec.capability.mime_type);
+
+            return Box::pin(async {});
+        }
+
+        let payload_type = remote_track.payload_type();
+        let ssrc = remote_track.ssrc();
 
         let Ok(mut decoder) = Decoder::new(SAMPLE_RATE, Channels::Mono) else {
             return Box::pin(async {});
         };
 
-        let _ = mixer_sender.send(MixerMessage::Open(channel_number));
+        let (channel_number, generation, new_channel) =
+            track_channels.lock().unwrap().acquire(ssrc);
+
+        if new_channel {
+            println!(
+                "Receiving Opus with payload type {} from SSRC {} on channel {}",
+                payload_type, ssrc, channel_number
+            );
+
+            let _ = mixer_sender.send(MixerMessage::Open(channel_number));
+        } else {
+            println!(
+                "New track for SSRC {}, taking over channel {}",
+                ssrc, channel_number
+            );
+        }
 
+        let track_channels_loop = track_channels.clone();
+        let track_channels_termination = track_channels.clone();
         let mixer_sender_loop = mixer_sender.clone();
         let mixer_sender_termination = mixer_sender_loop.clone();
         let mut mute_receiver_decoder = mute_receiver_decoder.clone();
+        let mut hold_receiver_decoder = hold_receiver_decoder.clone();
 
         tokio::spawn(async move {
             let audio_receive_task = tokio::spawn(async move {
+                let mut last_sequence_number: Option<u16> = None;
+                let mut runt_packets: u64 = 0;
+
+                let comfort_noise_level = config::get().comfort_noise_level;
+                let mut comfort_noise = tone::ComfortNoise::new(comfort_noise_level);
+                let mut in_dtx = false;
+
                 loop {
-                    let Ok((rtp_packet, _)) = remote_track.read_rtp().await else {
+                    if !track_channels_loop
+                        .lock()
+                        .unwrap()
+                        .is_current(ssrc, generation)
+                    {
+                        println!("Track for SSRC {} was replaced, it stops here", ssrc);
+
+                        break;
+                    }
+
+                    let read_result = if in_dtx && comfort_noise_level > 0.0 {
+                        tokio::time::timeout(COMFORT_NOISE_FRAME, remote_track.read_rtp()).await
+                    } else {
+                        Ok(remote_track.read_rtp().await)
+                    };
+
+                    let hold = *hold_receiver_decoder.borrow_and_update();
+                    let mute = mute_receiver_decoder
+                        .borrow_and_update()
+                        .output_silenced(hold);
+
+                    // Nothing arrives between DTX packets, keep the noise going meanwhile
+                    let Ok(read_result) = read_result else {
+                        if !mute {
+                            let _ = mixer_sender_loop.send(MixerMessage::Samples(
+                                channel_number,
+                                0,
+                                comfort_noise.generate(COMFORT_NOISE_FRAME_LENGTH),
+                            ));
+                        }
+
+                        continue;
+                    };
+
+                    let Ok((rtp_packet, _)) = read_result else {
                         continue;
                     };
 
+                    // Another source's packets would scramble this decoder and the loss count
+                    if rtp_packet.header.payload_type != payload_type
+                        || rtp_packet.header.ssrc != ssrc
+                    {
+                        continue;
+                    }
+
+                    if is_runt(&rtp_packet.payload) {
+                        runt_packets += 1;
+
+                        if runt_packets.is_power_of_two() {
+                            println!("Skipped {} runt RTP packets", runt_packets);
+                        }
+
+                        continue;
+                    }
+
                     let sequence_number = rtp_packet.header.sequence_number;
 
+                    metrics::PACKETS_RECEIVED.fetch_add(1, Ordering::Relaxed);
+
+                    if let Some(last_sequence_number) = last_sequence_number {
+                        let gap = sequence_number.wrapping_sub(last_sequence_number);
+
+                        // Large gaps are reordered or duplicate packets, not loss
+                        if gap > 1 && gap < 1000 {
+                            metrics::PACKETS_LOST.fetch_add(gap as u64 - 1, Ordering::Relaxed);
+                        }
+                    }
+
+                    last_sequence_number = Some(sequence_number);
+
+                    in_dtx = rtp_packet.payload.len() <= DTX_PAYLOAD_LENGTH;
+
+                    if in_dtx {
+                        let length = decoder
+                            .get_nb_samples(&rtp_packet.payload)
+                            .unwrap_or(COMFORT_NOISE_FRAME_LENGTH)
+                            .min(FRAME_LENGTH_1200);
+
+                        let samples = if mute {
+                            vec![0.0; length]
+                        } else {
+                            comfort_noise.generate(length)
+                        };
+
+                        let _ = mixer_sender_loop.send(MixerMessage::Samples(
+                            channel_number,
+                            sequence_number,
+                            samples,
+                        ));
+
+                        continue;
+                    }
+
                     let mut audio_data: [f32; FRAME_LENGTH_1200] = [0.0; FRAME_LENGTH_1200];
 
                     let decode_result =
@@ -660,16 +2092,23 @@ async fn setup_peer_connection_audio(
                         continue;
                     };
 
-                    let mute = *mute_receiver_decoder.borrow_and_update();
+                    // Opus never decodes more than the buffer it's given, but don't trust that
+                    let decoded = &audio_data[..decode_length.min(FRAME_LENGTH_1200)];
+
+                    // One allocation sized to the decoded frame, rather than copying the whole
+                    // 60ms buffer first
+                    let samples = if mute {
+                        vec![0.0; decoded.len()]
+                    } else {
+                        comfort_noise.observe(decoded);
+
+                        decoded.to_vec()
+                    };
 
                     let _ = mixer_sender_loop.send(MixerMessage::Samples(
                         channel_number,
                         sequence_number,
-                        audio_data
-                            .to_vec()
-                            .drain(0..decode_length)
-                            .map(|sample| if mute { 0.0 } else { sample })
-                            .collect(),
+                        samples,
                     ));
                 }
             });
@@ -680,7 +2119,11 @@ async fn setup_peer_connection_audio(
 
             audio_receive_task.abort();
 
-            let _ = mixer_sender_termination.send(MixerMessage::Close(channel_number));
+            let closed_channel = track_channels_termination.lock().unwrap().release(ssrc);
+
+            if let Some(channel_number) = closed_channel {
+                let _ = mixer_sender_termination.send(MixerMessage::Close(channel_number));
+            }
         });
 
         Box::pin(async {})
@@ -688,3 +2131,662 @@ async fn setup_peer_connection_audio(
 
     true
 }
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    // 20ms, what capture sends by default
+    const FRAME_LENGTH: usize = SAMPLE_RATE as usize / 50;
+
+    #[test]
+    fn runt_packets_are_skipped() {
+        assert!(is_runt(&[]));
+        assert!(!is_runt(&[0x78]));
+        assert!(!is_runt(&[0x78, 0x01, 0x02]));
+    }
+
+    #[test]
+    fn mute_silences_each_direction_on_its_own() {
+        for input in [false, true] {
+            for output in [false, true] {
+                let muted = MuteState { input, output };
+
+                assert_eq!(muted.input_silenced(HoldState::None), input);
+                assert_eq!(muted.output_silenced(HoldState::None), output);
+            }
+        }
+    }
+
+    #[test]
+    fn held_calls_are_silent_both_ways_whatever_the_mute() {
+        for hold in [HoldState::Waiting, HoldState::Local] {
+            for muted in [
+                MuteState::ALL,
+                MuteState {
+                    input: false,
+                    output: false,
+                },
+            ] {
+                assert!(muted.input_silenced(hold));
+                assert!(muted.output_silenced(hold));
+            }
+        }
+    }
+
+    fn phone(
+        id: u128,
+    ) -> (
+        PhoneRTC,
+        SignalingChannels,
+        mpsc::Receiver<SignalingMessage>,
+    ) {
+        let (phone, channels, signaling_message_receiver, _) = phone_with_ice(id);
+
+        (phone, channels, signaling_message_receiver)
+    }
+
+    // The candidates and connection changes that run() would otherwise handle
+    type IceEvents = (
+        mpsc::Receiver<(RTCIceCandidate, Uuid)>,
+        mpsc::Receiver<(RTCPeerConnectionState, Uuid)>,
+    );
+
+    // Where a phone's call audio goes and comes from, and how it's muted
+    pub(crate) type PhoneAudio = (
+        mpsc::Sender<MixerMessage>,
+        broadcast::Sender<Vec<f32>>,
+        MuteState,
+    );
+
+    fn no_audio() -> PhoneAudio {
+        (mpsc::channel().0, broadcast::channel(16).0, MuteState::ALL)
+    }
+
+    fn phone_with_ice(
+        id: u128,
+    ) -> (
+        PhoneRTC,
+        SignalingChannels,
+        mpsc::Receiver<SignalingMessage>,
+        IceEvents,
+    ) {
+        phone_with_audio(id, no_audio())
+    }
+
+    fn phone_with_audio(
+        id: u128,
+        (mixer_out, mic_in, mute): PhoneAudio,
+    ) -> (
+        PhoneRTC,
+        SignalingChannels,
+        mpsc::Receiver<SignalingMessage>,
+        IceEvents,
+    ) {
+        let (phone, _, _, _) = PhoneRTC::new(mixer_out, mic_in, Uuid::from_u128(id));
+
+        let (signaling_message_sender, signaling_message_receiver) = mpsc::channel();
+        let (ice_candidate_sender, ice_candidate_receiver) = mpsc::channel();
+        let (connection_change_sender, connection_change_receiver) = mpsc::channel();
+        let (_, mute_receiver) = watch::channel(mute);
+
+        let channels = SignalingChannels {
+            signaling_message_sender,
+            ice_candidate_sender,
+            connection_change_sender,
+            mute_receiver,
+        };
+
+        (
+            phone,
+            channels,
+            signaling_message_receiver,
+            (ice_candidate_receiver, connection_change_receiver),
+        )
+    }
+
+    fn sent(receiver: &mpsc::Receiver<SignalingMessage>) -> SignalingMessage {
+        receiver
+            .recv_timeout(Duration::from_secs(5))
+            .expect("no signaling message was sent")
+    }
+
+    fn nothing_sent(receiver: &mpsc::Receiver<SignalingMessage>) -> bool {
+        receiver.recv_timeout(Duration::from_millis(100)).is_err()
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn handshake_tracks_peer_connections() {
+        let (mut a, a_channels, a_sent) = phone(1);
+        let (mut b, b_channels, b_sent) = phone(2);
+
+        // b doesn't initiate, so it only acknowledges
+        b.handle_signaling_message(
+            SignalingMessage::Join {
+                from: a.id,
+                resume: false,
+                versions: PROTOCOL_VERSIONS.to_vec(),
+            },
+            &b_channels,
+        )
+        .await;
+
+        let join_ack = sent(&b_sent);
+        assert!(matches!(join_ack, SignalingMessage::JoinAck { from, .. } if from == b.id));
+        assert!(nothing_sent(&b_sent));
+        assert!(b.peer_connections.is_empty());
+
+        a.handle_signaling_message(join_ack, &a_channels).await;
+
+        let offer = sent(&a_sent);
+        assert!(matches!(
+            offer,
+            SignalingMessage::ICEOffer { from, to, restart: false, .. } if from == a.id && to == b.id
+        ));
+        assert!(a.peer_connections.contains_key(&b.id));
+
+        b.handle_signaling_message(offer, &b_channels).await;
+
+        let answer = sent(&b_sent);
+        assert!(matches!(
+            answer,
+            SignalingMessage::ICEAnswer { from, to, .. } if from == b.id && to == a.id
+        ));
+        assert!(b.peer_connections.contains_key(&a.id));
+
+        a.handle_signaling_message(answer, &a_channels).await;
+
+        assert!(nothing_sent(&a_sent));
+        assert_eq!(a.peer_connections.len(), 1);
+
+        b.handle_signaling_message(
+            SignalingMessage::ICECandidate {
+                candidate: RTCIceCandidateInit {
+                    candidate: String::from(
+                        "candidate:1 1 udp 2130706431 127.0.0.1 50000 typ host",
+                    ),
+                    sdp_mid: Some(String::from("0")),
+                    sdp_mline_index: Some(0),
+                    username_fragment: None,
+                },
+                from: a.id,
+                to: b.id,
+            },
+            &b_channels,
+        )
+        .await;
+
+        assert!(nothing_sent(&b_sent));
+        assert!(b.peer_connections.contains_key(&a.id));
+
+        b.handle_signaling_message(SignalingMessage::Leave { from: a.id }, &b_channels)
+            .await;
+
+        assert!(nothing_sent(&b_sent));
+        assert!(b.peer_connections.is_empty());
+
+        a.handle_signaling_message(SignalingMessage::Leave { from: b.id }, &a_channels)
+            .await;
+
+        assert!(a.peer_connections.is_empty());
+    }
+
+    // Two phones in a call with each other over loopback, a having made the offer
+    pub(crate) async fn loopback_pair(
+        a_audio: PhoneAudio,
+        b_audio: PhoneAudio,
+    ) -> (PhoneRTC, PhoneRTC) {
+        let (mut a, a_channels, a_sent, (a_candidates, a_changes)) = phone_with_audio(1, a_audio);
+        let (mut b, b_channels, b_sent, (b_candidates, b_changes)) = phone_with_audio(2, b_audio);
+
+        a.handle_signaling_message(
+            SignalingMessage::JoinAck {
+                from: b.id,
+                versions: PROTOCOL_VERSIONS.to_vec(),
+            },
+            &a_channels,
+        )
+        .await;
+        b.handle_signaling_message(sent(&a_sent), &b_channels).await;
+        a.handle_signaling_message(sent(&b_sent), &a_channels).await;
+
+        // Trade candidates the way run() would until both ends connect
+        let deadline = Instant::now() + Duration::from_secs(10);
+        let (mut a_connected, mut b_connected) = (false, false);
+
+        while !(a_connected && b_connected) {
+            assert!(Instant::now() < deadline, "the call never connected");
+
+            for (candidate, to) in a_candidates.try_iter() {
+                let message = SignalingMessage::ICECandidate {
+                    candidate: candidate.to_json().unwrap(),
+                    from: a.id,
+                    to,
+                };
+
+                b.handle_signaling_message(message, &b_channels).await;
+            }
+
+            for (candidate, to) in b_candidates.try_iter() {
+                let message = SignalingMessage::ICECandidate {
+                    candidate: candidate.to_json().unwrap(),
+                    from: b.id,
+                    to,
+                };
+
+                a.handle_signaling_message(message, &a_channels).await;
+            }
+
+            a_connected |= a_changes
+                .try_iter()
+                .any(|(state, _)| state == RTCPeerConnectionState::Connected);
+            b_connected |= b_changes
+                .try_iter()
+                .any(|(state, _)| state == RTCPeerConnectionState::Connected);
+
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        (a, b)
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn loopback_call_shuts_down_cleanly() {
+        let (mut a, mut b) = loopback_pair(no_audio(), no_audio()).await;
+
+        // A moment of audio each way
+        for _ in 0..10 {
+            let _ = a.mic_in.send(vec![0.0; FRAME_LENGTH]);
+            let _ = b.mic_in.send(vec![0.0; FRAME_LENGTH]);
+
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+
+        // The same order as on exit, leaving signaling for good and then closing the calls
+        a.left_signaling = true;
+        a.leave_signaling();
+        a.connect();
+
+        assert!(a.signaling_socket.is_none());
+
+        a.shutdown().await;
+        b.shutdown().await;
+
+        assert!(a.peer_connections.is_empty());
+        assert!(b.peer_connections.is_empty());
+        assert_eq!(a.calls.state(), CallState::Idle);
+        assert_eq!(b.calls.state(), CallState::Idle);
+    }
+
+    #[test]
+    fn first_frames_after_unmute_decode_cleanly() {
+        let mut encoder =
+            Encoder::new(SAMPLE_RATE, Channels::Mono, opus::Application::Voip).unwrap();
+        let mut decoder = Decoder::new(SAMPLE_RATE, Channels::Mono).unwrap();
+        let mut unmute_reset = UnmuteReset::new();
+
+        let mut round_trip = |encoder: &mut Encoder, frame: &[f32]| {
+            let packet = encoder.encode_vec_float(frame, 4000).unwrap();
+
+            let mut decoded = [0.0; FRAME_LENGTH];
+            let length = decoder.decode_float(&packet, &mut decoded, false).unwrap();
+
+            assert_eq!(length, FRAME_LENGTH);
+
+            decoded
+        };
+
+        // Two seconds muted, the encoder only sees silence
+        for _ in 0..100 {
+            assert!(!unmute_reset.update(&mut encoder, true));
+
+            round_trip(&mut encoder, &[0.0; FRAME_LENGTH]);
+        }
+
+        assert!(unmute_reset.update(&mut encoder, false));
+        assert!(!unmute_reset.update(&mut encoder, false));
+
+        let speech = tone::sine(&[440.0], Duration::from_millis(100), 0.5);
+        let mut decoded = Vec::new();
+
+        for frame in speech.chunks(FRAME_LENGTH) {
+            decoded.extend(round_trip(&mut encoder, frame));
+        }
+
+        // Past the codec's lookahead the speech is all there
+        let settled = &decoded[FRAME_LENGTH..];
+        let rms = (settled.iter().map(|sample| sample * sample).sum::<f32>()
+            / settled.len() as f32)
+            .sqrt();
+
+        assert!(rms > 0.25, "rms {}", rms);
+    }
+
+    #[tokio::test]
+    async fn slow_mic_subscriber_drops_without_stalling_capture() {
+        let (mic_sender, mut fast_receiver) = broadcast::channel(4);
+        let mut slow_receiver = mic_sender.subscribe();
+
+        let dropped_before = metrics::MIC_FRAMES_DROPPED.load(Ordering::Relaxed);
+
+        // Capture goes on at full speed, and the peer keeping up sees every frame
+        for frame in 0..100 {
+            assert!(mic_sender.send(vec![frame as f32]).is_ok());
+
+            assert_eq!(
+                next_mic_frame(&mut fast_receiver).await,
+                Some(vec![frame as f32])
+            );
+        }
+
+        // The one that never read picks up at the oldest frame still around
+        assert_eq!(next_mic_frame(&mut slow_receiver).await, Some(vec![96.0]));
+        assert_eq!(
+            metrics::MIC_FRAMES_DROPPED.load(Ordering::Relaxed) - dropped_before,
+            96
+        );
+
+        drop(mic_sender);
+
+        for frame in 97..100 {
+            assert_eq!(
+                next_mic_frame(&mut slow_receiver).await,
+                Some(vec![frame as f32])
+            );
+        }
+
+        assert_eq!(next_mic_frame(&mut slow_receiver).await, None);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn peers_past_the_cap_are_turned_away() {
+        let (mut a, a_channels, a_sent) = phone(1);
+        let max_peers = config::get().max_peers;
+
+        let join = |from| SignalingMessage::Join {
+            from: Uuid::from_u128(from),
+            resume: false,
+            versions: PROTOCOL_VERSIONS.to_vec(),
+        };
+
+        for peer in 0..max_peers {
+            a.handle_signaling_message(join(peer as u128 + 2), &a_channels)
+                .await;
+        }
+
+        assert_eq!(a.peer_connections.len(), max_peers);
+
+        // Drop the acks and offers to the peers that fit
+        while a_sent.recv_timeout(Duration::from_millis(100)).is_ok() {}
+
+        // Whether it joins or offers, the next peer only gets Busy
+        let excess = Uuid::from_u128(100);
+
+        a.handle_signaling_message(join(100), &a_channels).await;
+
+        assert!(matches!(
+            sent(&a_sent),
+            SignalingMessage::Busy { from, to } if from == a.id && to == excess
+        ));
+        assert!(nothing_sent(&a_sent));
+
+        let excess = Uuid::from_u128(0);
+
+        a.handle_signaling_message(
+            SignalingMessage::ICEOffer {
+                offer: garbled("offer"),
+                from: excess,
+                to: a.id,
+                restart: false,
+            },
+            &a_channels,
+        )
+        .await;
+
+        assert!(matches!(
+            sent(&a_sent),
+            SignalingMessage::Busy { from, to } if from == a.id && to == excess
+        ));
+        assert!(nothing_sent(&a_sent));
+
+        assert_eq!(a.peer_connections.len(), max_peers);
+        assert!(!a.peer_connections.contains_key(&Uuid::from_u128(100)));
+        assert!(!a.peer_connections.contains_key(&excess));
+    }
+
+    #[test]
+    fn second_track_and_renegotiation_keep_channels_apart() {
+        let mut track_channels = TrackChannels::default();
+        let (first_ssrc, second_ssrc) = (1111, 2222);
+
+        let (first_channel, first_generation, opened) = track_channels.acquire(first_ssrc);
+        assert!(opened);
+
+        // A second source from the same peer mixes on a channel of its own
+        let (second_channel, second_generation, opened) = track_channels.acquire(second_ssrc);
+        assert!(opened);
+        assert_ne!(second_channel, first_channel);
+        assert!(track_channels.is_current(first_ssrc, first_generation));
+        assert!(track_channels.is_current(second_ssrc, second_generation));
+
+        // Renegotiating brings the first source back on its old channel, and the old track for
+        // it stops feeding the mix
+        let (renegotiated_channel, renegotiated_generation, opened) =
+            track_channels.acquire(first_ssrc);
+        assert!(!opened);
+        assert_eq!(renegotiated_channel, first_channel);
+        assert!(!track_channels.is_current(first_ssrc, first_generation));
+        assert!(track_channels.is_current(first_ssrc, renegotiated_generation));
+        assert!(track_channels.is_current(second_ssrc, second_generation));
+
+        // The channel stays open until the last track on it ends, and none are left behind
+        assert_eq!(track_channels.release(first_ssrc), None);
+        assert_eq!(track_channels.release(first_ssrc), Some(first_channel));
+        assert_eq!(track_channels.release(second_ssrc), Some(second_channel));
+        assert_eq!(track_channels.release(second_ssrc), None);
+        assert!(track_channels.sources.is_empty());
+    }
+
+    fn garbled(kind: &str) -> RTCSessionDescription {
+        serde_json::from_str(&format!(r#"{{"type":"{}","sdp":"not sdp"}}"#, kind)).unwrap()
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn failed_negotiation_leaves_no_peer_behind() {
+        let (mut a, a_channels, a_sent) = phone(1);
+        let (mut b, b_channels, b_sent) = phone(2);
+
+        // An offer that can't be applied isn't answered or kept
+        b.handle_signaling_message(
+            SignalingMessage::ICEOffer {
+                offer: garbled("offer"),
+                from: a.id,
+                to: b.id,
+                restart: false,
+            },
+            &b_channels,
+        )
+        .await;
+
+        assert!(nothing_sent(&b_sent));
+        assert!(b.peer_connections.is_empty());
+
+        a.handle_signaling_message(
+            SignalingMessage::JoinAck {
+                from: b.id,
+                versions: PROTOCOL_VERSIONS.to_vec(),
+            },
+            &a_channels,
+        )
+        .await;
+
+        assert!(matches!(sent(&a_sent), SignalingMessage::ICEOffer { .. }));
+
+        // A bad answer drops the half negotiated peer and starts over once
+        a.handle_signaling_message(
+            SignalingMessage::ICEAnswer {
+                answer: garbled("answer"),
+                from: b.id,
+                to: a.id,
+            },
+            &a_channels,
+        )
+        .await;
+
+        assert!(matches!(
+            sent(&a_sent),
+            SignalingMessage::ICEOffer { to, .. } if to == b.id
+        ));
+        assert!(a.peer_connections.contains_key(&b.id));
+
+        // Another one gives up on the peer
+        a.handle_signaling_message(
+            SignalingMessage::ICEAnswer {
+                answer: garbled("answer"),
+                from: b.id,
+                to: a.id,
+            },
+            &a_channels,
+        )
+        .await;
+
+        assert!(nothing_sent(&a_sent));
+        assert!(a.peer_connections.is_empty());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn disconnected_peer_is_kept_through_the_grace_period() {
+        let (mut a, a_channels, a_sent) = phone(1);
+        let b = Uuid::from_u128(2);
+
+        a.handle_signaling_message(
+            SignalingMessage::JoinAck {
+                from: b,
+                versions: PROTOCOL_VERSIONS.to_vec(),
+            },
+            &a_channels,
+        )
+        .await;
+
+        assert!(matches!(sent(&a_sent), SignalingMessage::ICEOffer { .. }));
+
+        a.connection_changed(RTCPeerConnectionState::Disconnected, b, &a_channels)
+            .await;
+        a.drop_expired_peers().await;
+
+        assert!(a.disconnected_peers.contains_key(&b));
+        assert!(a.peer_connections.contains_key(&b));
+
+        // Back within the window, so the call carries on
+        a.connection_changed(RTCPeerConnectionState::Connected, b, &a_channels)
+            .await;
+
+        assert!(a.disconnected_peers.is_empty());
+        assert!(a.peer_connections.contains_key(&b));
+
+        // Gone for the whole window is dropped
+        a.connection_changed(RTCPeerConnectionState::Disconnected, b, &a_channels)
+            .await;
+
+        let grace_period = config::get().peer_disconnect_grace_period;
+        a.disconnected_peers
+            .insert(b, Instant::now() - grace_period);
+
+        a.drop_expired_peers().await;
+
+        assert!(a.disconnected_peers.is_empty());
+        assert!(a.peer_connections.is_empty());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn stalled_media_restarts_ice_once() {
+        let (mut a, a_channels, a_sent) = phone(1);
+        let (mut b, b_channels, b_sent) = phone(2);
+
+        a.handle_signaling_message(
+            SignalingMessage::JoinAck {
+                from: b.id,
+                versions: PROTOCOL_VERSIONS.to_vec(),
+            },
+            &a_channels,
+        )
+        .await;
+
+        b.handle_signaling_message(sent(&a_sent), &b_channels).await;
+
+        a.handle_signaling_message(sent(&b_sent), &a_channels).await;
+
+        // A call that dropped out is one that should be getting media
+        a.connection_changed(RTCPeerConnectionState::Disconnected, b.id, &a_channels)
+            .await;
+
+        let stall_timeout = Duration::from_secs(5);
+
+        // The first look only starts the clock
+        a.restart_stalled_peers(stall_timeout, &a_channels).await;
+
+        assert!(nothing_sent(&a_sent));
+
+        a.media_progress
+            .insert(b.id, (0, Instant::now() - stall_timeout));
+
+        a.restart_stalled_peers(stall_timeout, &a_channels).await;
+
+        assert!(matches!(
+            sent(&a_sent),
+            SignalingMessage::ICEOffer { to, restart: true, .. } if to == b.id
+        ));
+
+        // Still stalled, but ice_restart_min_interval holds off another one
+        a.restart_stalled_peers(stall_timeout, &a_channels).await;
+
+        assert!(nothing_sent(&a_sent));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn signaling_close_then_rejoin() {
+        let (mut a, a_channels, a_sent) = phone(1);
+        let (mut b, b_channels, b_sent) = phone(2);
+
+        assert!(matches!(
+            a.join_message(),
+            SignalingMessage::Join { resume: false, .. }
+        ));
+
+        a.handle_signaling_message(
+            SignalingMessage::JoinAck {
+                from: b.id,
+                versions: PROTOCOL_VERSIONS.to_vec(),
+            },
+            &a_channels,
+        )
+        .await;
+
+        b.handle_signaling_message(sent(&a_sent), &b_channels).await;
+
+        a.handle_signaling_message(sent(&b_sent), &a_channels).await;
+
+        // A close or a broken read both drop the socket, and the loop reconnects
+        assert!(matches!(
+            SignalingRead::from(Ok(websocket::OwnedMessage::Close(None))),
+            SignalingRead::Lost(_)
+        ));
+        assert!(matches!(
+            SignalingRead::from(Err(websocket::WebSocketError::NoDataAvailable)),
+            SignalingRead::Lost(_)
+        ));
+
+        // The call is still up, so the rejoin says so
+        let join = a.join_message();
+        assert!(matches!(join, SignalingMessage::Join { resume: true, .. }));
+        assert!(a.peer_connections.contains_key(&b.id));
+
+        // And peers still answer it, so the phone can be found again
+        b.handle_signaling_message(join, &b_channels).await;
+
+        assert!(matches!(
+            sent(&b_sent),
+            SignalingMessage::JoinAck { from, .. } if from == b.id
+        ));
+    }
+}
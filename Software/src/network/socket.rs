@@ -1,17 +1,38 @@
-use std::sync::mpsc;
+use std::{
+    io,
+    net::ToSocketAddrs,
+    sync::{atomic::Ordering, mpsc},
+    thread,
+    time::{Duration, Instant},
+};
 
 use websocket::{
     client::sync::Client,
+    native_tls::TlsConnector,
     stream::sync::{TcpStream, TlsStream},
+    url::Url,
     ClientBuilder, Message, OwnedMessage,
 };
 
-use crate::PhoneSide;
+use crate::{
+    config, metrics,
+    watchdog::{self, Heartbeat},
+    PhoneSide,
+};
 
-use super::{PhoneIncomingMessage, PhoneOutgoingMessage};
+use super::{
+    error::{ConnectError, MessageError},
+    MessageLog, PhoneIncomingMessage, PhoneOutgoingMessage,
+};
 
 pub struct PhoneSocket {
     websocket_client: Option<Client<TlsStream<TcpStream>>>,
+    // Connects are retried constantly, only log a failure when it changes
+    last_connect_error: Option<String>,
+    // Keepalive, a connection that stays silent through a few pings is treated as dead
+    last_ping: Instant,
+    last_heard: Instant,
+    message_log: MessageLog,
     phone_side: PhoneSide,
     outgoing_receiver: mpsc::Receiver<PhoneOutgoingMessage>,
     incoming_sender: mpsc::Sender<PhoneIncomingMessage>,
@@ -30,6 +51,10 @@ impl PhoneSocket {
 
         let mut socket = PhoneSocket {
             websocket_client: None,
+            last_connect_error: None,
+            last_ping: Instant::now(),
+            message_log: MessageLog::new("Phone Socket"),
+            last_heard: Instant::now(),
             phone_side,
             outgoing_receiver,
             incoming_sender,
@@ -45,54 +70,146 @@ impl PhoneSocket {
             return;
         }
 
-        let Ok(mut websocket_client_builder) = ClientBuilder::new(&format!(
+        match self.open() {
+            Ok(websocket_client) => {
+                self.last_connect_error = None;
+                self.last_ping = Instant::now();
+                self.last_heard = Instant::now();
+                self.websocket_client = Some(websocket_client);
+            }
+            Err(error) => self.connect_failed(error),
+        }
+    }
+
+    fn open(&self) -> Result<Client<TlsStream<TcpStream>>, ConnectError> {
+        let url = format!(
             "wss://api.purduehackers.com/phonebell/{}",
             match self.phone_side {
                 PhoneSide::Inside => "inside",
                 PhoneSide::Outside => "outside",
             }
-        )) else {
-            return;
-        };
+        );
 
-        let Ok(mut websocket_client) = websocket_client_builder.connect_secure(Option::None) else {
-            return;
-        };
+        let mut client_builder =
+            ClientBuilder::new(&url).map_err(|source| ConnectError::InvalidUrl {
+                url: url.clone(),
+                source,
+            })?;
 
-        let Ok(_) =
-            websocket_client.send_message(&Message::text(std::env::var("PHONE_API_KEY").unwrap()))
-        else {
-            return;
-        };
+        // This loop is watched, so connecting can't block for as long as the network is down
+        let timeout = config::get().watchdog_stall_timeout / 4;
+
+        let tls_stream = connect_tls(&url, timeout).map_err(|source| ConnectError::Connect {
+            url: url.clone(),
+            source,
+        })?;
+
+        watchdog::beat(Heartbeat::Network);
+
+        let mut websocket_client =
+            client_builder
+                .connect_on(tls_stream)
+                .map_err(|source| ConnectError::Handshake {
+                    url: url.clone(),
+                    source,
+                })?;
+
+        websocket_client
+            .send_message(&Message::text(
+                config::get().api_key.clone().unwrap_or_default(),
+            ))
+            .map_err(|source| ConnectError::Send {
+                what: "API key",
+                source,
+            })?;
+
+        if let Some(extension) = config::get().extension.clone() {
+            let identify = PhoneOutgoingMessage::Identify { extension };
+
+            let message_string =
+                serde_json::to_string(&identify).map_err(|source| ConnectError::Encode {
+                    what: "Identify",
+                    source,
+                })?;
+
+            websocket_client
+                .send_message(&Message::text(message_string))
+                .map_err(|source| ConnectError::Send {
+                    what: "Identify",
+                    source,
+                })?;
+
+            println!("Phone Socket tx: {:?}", identify);
+        }
 
         let _ = websocket_client.set_nonblocking(true);
 
-        self.websocket_client = Some(websocket_client);
+        // The server closes the socket if it rejects the key
+        println!(
+            "Phone Socket connected to {} ({}), API key sent",
+            url,
+            websocket_client
+                .peer_addr()
+                .map_or(String::from("unknown address"), |address| address
+                    .to_string())
+        );
+
+        Ok(websocket_client)
+    }
+
+    fn connect_failed(&mut self, error: ConnectError) {
+        let error = error.to_string();
+
+        if self.last_connect_error.as_ref() != Some(&error) {
+            println!("Phone Socket connect failed: {}", error);
+
+            self.last_connect_error = Some(error);
+        }
     }
 
     pub fn run(&mut self) {
         loop {
+            watchdog::beat(Heartbeat::Network);
+
             if self.websocket_client.is_none() {
                 self.connect();
+
+                if self.websocket_client.is_some() {
+                    metrics::RECONNECTS.fetch_add(1, Ordering::Relaxed);
+                }
             }
 
             if let Some(websocket_client) = &mut self.websocket_client {
                 let mut should_shutdown = false;
 
                 'message_iterate: while let Ok(message) = (*websocket_client).recv_message() {
-                    println!("Phone Socket rx: {:?}", message);
+                    self.last_heard = Instant::now();
+
+                    if self.message_log.allow() {
+                        println!("Phone Socket rx: {:?}", message);
+                    }
+
                     match message {
                         OwnedMessage::Text(data) => {
-                            let Ok(message): Result<PhoneIncomingMessage, serde_json::Error> =
-                                serde_json::from_str(&data)
-                            else {
-                                continue;
-                            };
-
-                            let _ = self.incoming_sender.send(message);
+                            match serde_json::from_str::<PhoneIncomingMessage>(&data) {
+                                Ok(message) => {
+                                    let _ = self.incoming_sender.send(message);
+                                }
+                                Err(source) => println!(
+                                    "Phone Socket: {}",
+                                    MessageError::Decode { data, source }
+                                ),
+                            }
                         }
                         OwnedMessage::Binary(_) => {}
-                        OwnedMessage::Close(_) => {
+                        OwnedMessage::Close(close_data) => {
+                            println!(
+                                "Phone Socket closed by server: {}",
+                                close_data.map_or(String::from("no reason"), |close_data| {
+                                    format!("{} {}", close_data.status_code, close_data.reason)
+                                })
+                            );
+
                             let _ = websocket_client.shutdown();
                             should_shutdown = true;
 
@@ -105,14 +222,45 @@ impl PhoneSocket {
                     }
                 }
 
+                let keepalive_interval = config::get().keepalive_interval;
+
+                if !should_shutdown && self.last_heard.elapsed() > keepalive_interval * 3 {
+                    println!(
+                        "Phone Socket heard nothing for {:?}, reconnecting",
+                        self.last_heard.elapsed()
+                    );
+
+                    let _ = websocket_client.shutdown();
+                    should_shutdown = true;
+                }
+
+                if !should_shutdown && self.last_ping.elapsed() > keepalive_interval {
+                    self.last_ping = Instant::now();
+
+                    let _ = websocket_client.send_message(&Message::ping(Vec::new()));
+                }
+
                 if should_shutdown {
                     self.websocket_client = None;
                 } else {
                     while let Ok(message) = self.outgoing_receiver.try_recv() {
-                        println!("Phone Socket tx: {:?}", message);
+                        if self.message_log.allow() {
+                            println!("Phone Socket tx: {:?}", message);
+                        }
+
+                        let message_string = match serde_json::to_string(&message) {
+                            Ok(message_string) => message_string,
+                            Err(source) => {
+                                println!(
+                                    "Phone Socket: {}",
+                                    MessageError::Encode {
+                                        what: format!("{:?}", message),
+                                        source,
+                                    }
+                                );
 
-                        let Ok(message_string) = serde_json::to_string(&message) else {
-                            continue;
+                                continue;
+                            }
                         };
 
                         let _ = websocket_client.send_message(&Message::text(message_string));
@@ -122,3 +270,90 @@ impl PhoneSocket {
         }
     }
 }
+
+// connect_secure, except every step that can block gives up after timeout: the lookup, the TCP
+// connect, and each read or write of the TLS and websocket handshakes
+fn connect_tls(url: &str, timeout: Duration) -> io::Result<TlsStream<TcpStream>> {
+    let url = Url::parse(url).map_err(io::Error::other)?;
+
+    let host = url
+        .host_str()
+        .ok_or_else(|| io::Error::other("no host"))?
+        .to_string();
+    let port = url.port_or_known_default().unwrap_or(443);
+
+    // The lookup takes no timeout, one that runs over is left to finish on its own
+    let (address_sender, address_receiver) = mpsc::channel();
+    let lookup_host = host.clone();
+
+    thread::spawn(move || {
+        let addresses = (lookup_host.as_str(), port)
+            .to_socket_addrs()
+            .map(Iterator::collect::<Vec<_>>);
+
+        let _ = address_sender.send(addresses);
+    });
+
+    let deadline = Instant::now() + timeout;
+    let addresses = address_receiver.recv_timeout(timeout).map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::TimedOut,
+            format!("looking up {} timed out", host),
+        )
+    })??;
+
+    let mut last_error = io::Error::new(
+        io::ErrorKind::NotFound,
+        format!("{} has no addresses", host),
+    );
+
+    for address in addresses {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+
+        if remaining.is_zero() {
+            break;
+        }
+
+        let tcp_stream = match TcpStream::connect_timeout(&address, remaining) {
+            Ok(tcp_stream) => tcp_stream,
+            Err(error) => {
+                last_error = error;
+
+                continue;
+            }
+        };
+
+        tcp_stream.set_read_timeout(Some(timeout))?;
+        tcp_stream.set_write_timeout(Some(timeout))?;
+
+        return TlsConnector::new()
+            .map_err(io::Error::other)?
+            .connect(&host, tcp_stream)
+            .map_err(|error| io::Error::other(error.to_string()));
+    }
+
+    Err(last_error)
+}
+
+impl Drop for PhoneSocket {
+    fn drop(&mut self) {
+        if let Some(mut websocket_client) = self.websocket_client.take() {
+            let _ = websocket_client.send_message(&Message::close());
+            let _ = websocket_client.shutdown();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unroutable_server_gives_up_within_the_timeout() {
+        let timeout = Duration::from_millis(300);
+        let started = Instant::now();
+
+        assert!(connect_tls("wss://10.255.255.1/phonebell/inside", timeout).is_err());
+        assert!(started.elapsed() < timeout * 2);
+    }
+}
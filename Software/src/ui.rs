@@ -1,58 +1,1319 @@
+#[cfg(not(feature = "synth_tones"))]
+use std::io::Cursor;
 use std::{
-    io::Cursor,
-    sync::mpsc::{Receiver, Sender},
+    collections::HashSet,
+    sync::{
+        atomic::Ordering,
+        mpsc::{Receiver, Sender},
+    },
+    time::{Duration, Instant},
 };
 
 use crate::{
-    hardware::{self, PhoneHardware},
-    network::{PhoneIncomingMessage, PhoneOutgoingMessage, Sound},
+    clock::{self, SharedClock},
+    config::{self, Config, ShortCodeAction, SAMPLE_RATE},
+    hardware::{
+        self,
+        audio::{AudioCommand, AudioEvent, MixerMessage, TONE_CHANNEL},
+        tone, PhoneHardware,
+    },
+    metrics,
+    network::{
+        self,
+        call::{call_tag, CallState},
+        rtc::{MuteState, RTCCommand, RTCEvent},
+        Diagnostics, MuteDirection, PhoneIncomingMessage, PhoneOutgoingMessage, Sound,
+    },
+    watchdog::{self, Heartbeat},
 };
-use rodio::{Decoder, OutputStream, Sink, Source};
+use rodio::buffer::SamplesBuffer;
+#[cfg(not(feature = "synth_tones"))]
+use rodio::Decoder;
+use rodio::{
+    source::{ChannelVolume, SineWave, Zero},
+    OutputStream, OutputStreamHandle, Sink, Source,
+};
+use uuid::Uuid;
+
+// The sink PlaySound sounds go to. With local_sounds_enabled off it isn't connected to any
+// device, so whatever is appended is dropped without opening an output. Keep the stream alive
+// for as long as the sink is used
+pub fn local_sound_sink() -> (Option<(OutputStream, OutputStreamHandle)>, Sink) {
+    if !config::get().local_sounds_enabled {
+        println!("Local sounds are disabled");
+
+        return (None, Sink::new_idle().0);
+    }
+
+    let (stream, stream_handle) = OutputStream::try_default().unwrap();
+
+    let sink = Sink::try_new(&stream_handle).unwrap();
+
+    (Some((stream, stream_handle)), sink)
+}
+
+// Spreads a tone across the output channels set by tone_output_channels, the rest stay silent
+pub fn on_tone_channels<S>(source: S) -> ChannelVolume<S>
+where
+    S: Source<Item = f32>,
+{
+    let routed = &config::get().tone_output_channels;
+    let channel_count = routed.iter().max().map_or(1, |highest| highest + 1);
+
+    let volumes = (0..channel_count)
+        .map(|channel| if routed.contains(&channel) { 1.0 } else { 0.0 })
+        .collect();
+
+    ChannelVolume::new(source, volumes)
+}
+
+fn play_reorder(sink: &Sink) {
+    sink.clear();
+
+    // 30 seconds of the fast busy cadence, 250ms on and 250ms off
+    for _ in 0..60 {
+        sink.append(on_tone_channels(
+            SineWave::new(480.0)
+                .mix(SineWave::new(620.0))
+                .take_duration(Duration::from_millis(250))
+                .amplify(0.2),
+        ));
+        sink.append(on_tone_channels(
+            Zero::<f32>::new(1, SAMPLE_RATE).take_duration(Duration::from_millis(250)),
+        ));
+    }
+
+    sink.play();
+}
+
+// The bundled recordings, or with the synth_tones feature the standard North American tones
+// generated instead, for builds without the assets
+#[cfg(not(feature = "synth_tones"))]
+pub fn looped_sound(sound: &Sound) -> Option<Box<dyn Source<Item = f32> + Send>> {
+    let recording: &'static [u8] = match sound {
+        Sound::None => return None,
+        Sound::Dialtone => include_bytes!("../assets/dialtone.flac"),
+        Sound::Connecting => return connecting_tone(),
+        Sound::Busy => return Some(busy_tone()),
+        Sound::Ringback => include_bytes!("../assets/ringback.flac"),
+        Sound::Hangup => include_bytes!("../assets/hangup.flac"),
+        Sound::Confirm => return None,
+    };
+
+    let source = Decoder::new_looped(Cursor::new(recording)).unwrap();
+
+    Some(Box::new(source.convert_samples()))
+}
+
+#[cfg(feature = "synth_tones")]
+pub fn looped_sound(sound: &Sound) -> Option<Box<dyn Source<Item = f32> + Send>> {
+    // Each cadence is a whole number of cycles long so it repeats without clicks
+    let samples = match sound {
+        Sound::None => return None,
+        Sound::Dialtone => tone::sine(&[350.0, 440.0], Duration::from_secs(1), 0.2),
+        Sound::Connecting => return connecting_tone(),
+        Sound::Busy => return Some(busy_tone()),
+        Sound::Ringback => {
+            let mut samples = tone::sine(&[440.0, 480.0], Duration::from_secs(2), 0.2);
+            samples.extend(tone::silence(Duration::from_secs(4)));
+            samples
+        }
+        // Off-hook howler
+        Sound::Hangup => {
+            let mut samples = tone::sine(
+                &[1400.0, 2060.0, 2450.0, 2600.0],
+                Duration::from_millis(100),
+                0.2,
+            );
+            samples.extend(tone::silence(Duration::from_millis(100)));
+            samples
+        }
+        Sound::Confirm => return None,
+    };
+
+    Some(Box::new(
+        SamplesBuffer::new(1, SAMPLE_RATE, samples).repeat_infinite(),
+    ))
+}
+
+// Sounds that play once, None for the looped ones
+fn one_shot_sound(sound: &Sound) -> Option<SamplesBuffer<f32>> {
+    let samples = match sound {
+        Sound::Confirm => {
+            let mut samples = tone::sine(&[1200.0], Duration::from_millis(80), 0.2);
+            samples.extend(tone::silence(Duration::from_millis(60)));
+            samples.extend(tone::sine(&[1200.0], Duration::from_millis(80), 0.2));
+            samples
+        }
+        _ => return None,
+    };
+
+    Some(SamplesBuffer::new(1, SAMPLE_RATE, samples))
+}
+
+// What PlaySound does with the sink. A loop replaces the loop playing, but waits for queued
+// one-shots to finish. A one-shot stops the loop, plays after any one-shots still going, and then
+// the loop starts over
+struct SoundQueue {
+    looped: Option<Sound>,
+    one_shots_until: Instant,
+    resume_pending: bool,
+}
+
+impl SoundQueue {
+    fn new() -> Self {
+        Self {
+            looped: None,
+            one_shots_until: Instant::now(),
+            resume_pending: false,
+        }
+    }
+
+    fn play(&mut self, sink: &Sink, sound: Sound) {
+        let now = Instant::now();
+        let queueing = config::get().queue_one_shot_sounds;
+
+        if let Some(source) = one_shot_sound(&sound) {
+            let length = source.total_duration().unwrap_or_default();
+
+            if !queueing || self.one_shots_until <= now {
+                sink.clear();
+            }
+
+            self.one_shots_until = self.one_shots_until.max(now) + length;
+            self.resume_pending = queueing && self.looped.is_some();
+
+            sink.append(on_tone_channels(source));
+            sink.play();
+
+            return;
+        }
+
+        self.looped = Some(sound);
+
+        if queueing && self.one_shots_until > now {
+            self.resume_pending = true;
+        } else {
+            self.start_loop(sink);
+        }
+    }
+
+    // Picks the loop back up once the one-shots are done
+    fn update(&mut self, sink: &Sink) {
+        if self.resume_pending && self.one_shots_until <= Instant::now() {
+            self.start_loop(sink);
+        }
+    }
+
+    // For when something else takes over the sink
+    fn stop(&mut self) {
+        self.looped = None;
+        self.one_shots_until = Instant::now();
+        self.resume_pending = false;
+    }
+
+    fn start_loop(&mut self, sink: &Sink) {
+        self.resume_pending = false;
+
+        sink.clear();
+
+        match self.looped.as_ref().and_then(looped_sound) {
+            Some(source) => {
+                sink.append(on_tone_channels(source));
+                sink.play();
+            }
+            None => sink.pause(),
+        }
+    }
+}
+
+// A quiet short beep every two seconds, nothing when connecting_tone_enabled is off. There's
+// no recording for it, so it's always generated
+fn connecting_tone() -> Option<Box<dyn Source<Item = f32> + Send>> {
+    if !config::get().connecting_tone_enabled {
+        return None;
+    }
+
+    let mut samples = tone::sine(&[440.0], Duration::from_millis(100), 0.05);
+    samples.extend(tone::silence(Duration::from_millis(1900)));
+
+    Some(Box::new(
+        SamplesBuffer::new(1, SAMPLE_RATE, samples).repeat_infinite(),
+    ))
+}
+
+// 480 + 620Hz, half a second on and off. No recording for this either
+fn busy_tone() -> Box<dyn Source<Item = f32> + Send> {
+    let mut samples = tone::sine(&[480.0, 620.0], Duration::from_millis(500), 0.2);
+    samples.extend(tone::silence(Duration::from_millis(500)));
+
+    Box::new(SamplesBuffer::new(1, SAMPLE_RATE, samples).repeat_infinite())
+}
+
+// Round trips are pipped out in steps of this, up to MAX_ROUND_TRIP_PIPS
+const ROUND_TRIP_PIP_STEP_MS: f64 = 50.0;
+const MAX_ROUND_TRIP_PIPS: usize = 10;
+
+// One high beep if signaling is connected or a low one if not, then a beep per connected
+// peer, or a long low tone when there are none. Last, when there's a round trip time, a short
+// pip per started ROUND_TRIP_PIP_STEP_MS of it, so three pips is 100 to 150ms
+fn status_beeps(
+    signaling_connected: bool,
+    peers: usize,
+    round_trip_time_ms: Option<f64>,
+) -> Vec<f32> {
+    let beep = Duration::from_millis(150);
+    let gap = Duration::from_millis(150);
+
+    let mut samples = if signaling_connected {
+        tone::sine(&[1000.0], beep, 0.25)
+    } else {
+        tone::sine(&[400.0], beep, 0.25)
+    };
+
+    samples.extend(tone::silence(Duration::from_millis(600)));
+
+    if peers == 0 {
+        samples.extend(tone::sine(&[400.0], Duration::from_millis(600), 0.25));
+    }
+
+    for _ in 0..peers {
+        samples.extend(tone::sine(&[800.0], beep, 0.25));
+        samples.extend(tone::silence(gap));
+    }
+
+    if let Some(round_trip_time_ms) = round_trip_time_ms {
+        let pips = ((round_trip_time_ms / ROUND_TRIP_PIP_STEP_MS).floor() as usize + 1)
+            .min(MAX_ROUND_TRIP_PIPS);
+
+        samples.extend(tone::silence(Duration::from_millis(600)));
+
+        for _ in 0..pips {
+            samples.extend(tone::sine(&[1200.0], Duration::from_millis(60), 0.2));
+            samples.extend(tone::silence(Duration::from_millis(90)));
+        }
+    }
+
+    samples
+}
+
+// Queues a tone in the mixer and pushes back when the queued tones finish playing
+fn play_tone(mixer_sender: &Sender<MixerMessage>, samples: Vec<f32>, playing_until: &mut Instant) {
+    let length = Duration::from_secs_f32(samples.len() as f32 / SAMPLE_RATE as f32);
+
+    *playing_until = (*playing_until).max(Instant::now()) + length;
+
+    let _ = mixer_sender.send(MixerMessage::Samples(TONE_CHANNEL, 0, samples));
+}
+
+// Starts a call id if there isn't one yet
+fn call_id(current: &mut Option<Uuid>, rtc_command_sender: &Sender<RTCCommand>) -> Uuid {
+    *current.get_or_insert_with(|| {
+        let call_id = Uuid::new_v4();
+
+        println!("{} Started", call_tag(Some(call_id)));
+
+        let _ = rtc_command_sender.send(RTCCommand::SetCallId(Some(call_id)));
+
+        call_id
+    })
+}
+
+// Keeps the bell from being switched on faster than the solenoid can take. Turning it off is
+// always safe so that happens right away, a ring that comes too soon is held until it's allowed
+struct RingLimiter {
+    clock: SharedClock,
+    last_change: Option<Instant>,
+    pending: bool,
+    throttled: u64,
+}
+
+impl RingLimiter {
+    fn new(clock: SharedClock) -> Self {
+        Self {
+            clock,
+            last_change: None,
+            pending: false,
+            throttled: 0,
+        }
+    }
+
+    // Returns the state to drive the bell to now, if any
+    fn request(&mut self, state: bool) -> Option<bool> {
+        if !state {
+            self.pending = false;
+            self.last_change = Some(self.clock.now());
+
+            return Some(false);
+        }
+
+        if self.too_soon() {
+            self.throttled += 1;
+
+            if self.throttled.is_power_of_two() {
+                println!(
+                    "Ring commands too fast, throttled {} so far",
+                    self.throttled
+                );
+            }
+
+            self.pending = true;
+
+            return None;
+        }
+
+        self.last_change = Some(self.clock.now());
+
+        Some(true)
+    }
+
+    fn poll(&mut self) -> Option<bool> {
+        if !self.pending || self.too_soon() {
+            return None;
+        }
+
+        self.pending = false;
+        self.last_change = Some(self.clock.now());
+
+        Some(true)
+    }
+
+    fn too_soon(&self) -> bool {
+        self.last_change.is_some_and(|last_change| {
+            self.clock.elapsed_since(last_change) < config::get().ring_min_interval
+        })
+    }
+}
+
+// Only reports a hook change once the switch has stayed in the new state for hook_settle_time,
+// bounces shorter than that are ignored
+struct HookSettler {
+    clock: SharedClock,
+    settled: bool,
+    candidate_since: Option<Instant>,
+}
+
+impl HookSettler {
+    fn new(state: bool, clock: SharedClock) -> Self {
+        Self {
+            clock,
+            settled: state,
+            candidate_since: None,
+        }
+    }
+
+    // Returns the settled hook state
+    fn update(&mut self, raw: bool) -> bool {
+        if raw == self.settled {
+            self.candidate_since = None;
+
+            return self.settled;
+        }
+
+        let now = self.clock.now();
+        let since = *self.candidate_since.get_or_insert(now);
+
+        if now.saturating_duration_since(since) >= config::get().hook_settle_time {
+            self.settled = raw;
+            self.candidate_since = None;
+        }
+
+        self.settled
+    }
+}
+
+// What a settled hook change amounts to once bumps and flashes are accounted for
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum HookChange {
+    // The phone takes the new hook state
+    Report,
+    // Hold back the on-hook in case the handset comes back up in time
+    Wait,
+    // Back up in time with calls to swap or hold
+    Flash,
+    // Back up in time with nothing to flash for, so the call carries on
+    Bump,
+}
+
+// Holds back hanging up mid-call so a flash or a knocked handset doesn't end the call. The
+// phone stays off-hook as far as everything else is concerned until the hang-up is due
+struct HangUpTimer {
+    clock: SharedClock,
+    started: Option<(Instant, Duration)>,
+    on_hook: bool,
+}
+
+impl HangUpTimer {
+    fn new(clock: SharedClock) -> Self {
+        Self {
+            clock,
+            started: None,
+            on_hook: true,
+        }
+    }
+
+    // The hook state the rest of the phone goes by
+    fn on_hook(&self) -> bool {
+        self.on_hook
+    }
+
+    // A flash if it does anything in this call, otherwise the handset only has to stay down
+    // long enough that a bump doesn't hang up
+    fn change(
+        &mut self,
+        on_hook: bool,
+        call_state: CallState,
+        can_flash: bool,
+        config: &Config,
+    ) -> HookChange {
+        if !on_hook {
+            return match self.started.take() {
+                Some(_) if can_flash => HookChange::Flash,
+                Some(_) => HookChange::Bump,
+                None => self.report(on_hook),
+            };
+        }
+
+        let hang_up_after = if can_flash {
+            Some(config.flash_hook_max_duration)
+        } else {
+            (call_state != CallState::Idle && !config.hangup_confirm_time.is_zero())
+                .then_some(config.hangup_confirm_time)
+        };
+
+        match hang_up_after {
+            Some(hang_up_after) => {
+                self.started = Some((self.clock.now(), hang_up_after));
+
+                HookChange::Wait
+            }
+            None => self.report(on_hook),
+        }
+    }
+
+    fn report(&mut self, on_hook: bool) -> HookChange {
+        self.on_hook = on_hook;
+
+        HookChange::Report
+    }
+
+    // Returns whether the handset has been down long enough that the hang-up is due now, and
+    // goes on-hook if so
+    fn expired(&mut self) -> bool {
+        let expired = self
+            .started
+            .is_some_and(|(start, hang_up_after)| self.clock.elapsed_since(start) > hang_up_after);
+
+        if expired {
+            self.started = None;
+            self.on_hook = true;
+        }
+
+        expired
+    }
+}
+
+// Off-hook, or auto-answered, in a call. Goes by the hang-up timer's hook state, so a bump or
+// flash doesn't drop it
+fn is_connected(on_hook: bool, auto_answered: bool, call_state: CallState) -> bool {
+    (!on_hook || auto_answered) && call_state != CallState::Idle
+}
+
+// Where dialed digits go once local codes have had their turn
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum DigitRoute {
+    // To the server to place a call
+    Dial,
+    // To the far end as touch tones
+    Dtmf,
+    Ignore,
+}
+
+// Only idle digits go to the server with Dial, so a call is only ever placed from idle off-hook.
+// Once in a call they're touch tones, or dropped with in_call_dtmf off, never a second Dial
+fn route_digits(call_state: CallState, on_hook: bool, config: &Config) -> DigitRoute {
+    if call_state == CallState::Idle {
+        DigitRoute::Dial
+    } else if config.in_call_dtmf && !on_hook {
+        DigitRoute::Dtmf
+    } else {
+        DigitRoute::Ignore
+    }
+}
+
+// Takes the digits dialed since the last update. Hanging up wins over digits that finished in
+// the same update, and nothing dialed on-hook carries over to the next pickup
+fn take_dialed_number(hardware: &mut impl PhoneHardware, hook_state: bool) -> String {
+    let dialed = std::mem::take(hardware.dialed_number());
+
+    if hook_state && !dialed.is_empty() {
+        println!("Dropping digits dialed on-hook");
+
+        return String::new();
+    }
+
+    dialed
+}
+
+// How far a handset left off-hook has escalated, see off_hook_reorder_after
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum OffHookStage {
+    Dialtone,
+    Reorder,
+    Howler,
+}
+
+// Everything with a say in muting, combined in one place so the sources can't fight
+struct MuteSources {
+    // On-hook and not auto-answered
+    hung_up: bool,
+    // Off-hook, or auto-answered, with a peer connected
+    connected: bool,
+    server_input: Option<bool>,
+    server_output: Option<bool>,
+    ptt_released: bool,
+    awaiting_confirmation: bool,
+    tone_playing: bool,
+}
+
+impl MuteSources {
+    // Highest precedence first:
+    // 1. Waiting on answer confirmation mutes both ways, a tone or released push to talk the mic
+    // 2. Hung up with idle_muted set mutes both ways, the server can't open it
+    // 3. The server's Mute for the current call
+    // 4. idle_muted, lifted by auto_unmute_on_connect while connected
+    fn resolve(&self, config: &Config) -> MuteState {
+        let privacy_input = self.awaiting_confirmation || self.tone_playing || self.ptt_released;
+        let privacy_output = self.awaiting_confirmation;
+
+        let forced = self.hung_up && config.idle_muted;
+
+        let policy = config.idle_muted && !(config.auto_unmute_on_connect && self.connected);
+
+        MuteState {
+            input: privacy_input || forced || self.server_input.unwrap_or(policy),
+            output: privacy_output || forced || self.server_output.unwrap_or(policy),
+        }
+    }
+}
+
+// How long a diagnostics request waits on the RTC and audio threads before sending what it has
+const DIAGNOSTICS_TIMEOUT: Duration = Duration::from_secs(2);
+
+// What the server opens the door for
+const DOOR_OPEN_NUMBER: &str = "0";
+
+// Longer notices are cut short, they have to fit under the caller id
+const MAX_NOTICE_LENGTH: usize = 140;
+
+// Flattens a notice to one line of printable text: control characters and line breaks become
+// spaces, runs of whitespace collapse, and anything past MAX_NOTICE_LENGTH is cut with an ellipsis
+fn sanitize_notice(text: &str) -> String {
+    let printable: String = text
+        .chars()
+        .map(|character| {
+            if character.is_control() {
+                ' '
+            } else {
+                character
+            }
+        })
+        .collect();
+
+    let notice = printable.split_whitespace().collect::<Vec<_>>().join(" ");
+
+    if notice.chars().count() <= MAX_NOTICE_LENGTH {
+        return notice;
+    }
+
+    let shortened: String = notice.chars().take(MAX_NOTICE_LENGTH - 1).collect();
+
+    format!("{}…", shortened.trim_end())
+}
+
+#[derive(Clone, Copy)]
+enum LocalCode {
+    ReportStatus,
+    AnnounceExtension,
+    Replay,
+    SwitchAudioDevices,
+    Short(ShortCodeAction),
+}
+
+// The diagnostic and short codes work before a call, the others during one. With in_call_dtmf
+// on, a one digit code would keep that digit from ever going out as a touch tone, so it's left
+// out during calls
+fn local_codes(call_state: CallState, config: &Config) -> Vec<(&str, LocalCode)> {
+    let codes = if call_state == CallState::Idle {
+        let mut codes = vec![
+            (
+                config.diagnostic_dial_code.as_str(),
+                LocalCode::ReportStatus,
+            ),
+            (
+                config.announce_dial_code.as_str(),
+                LocalCode::AnnounceExtension,
+            ),
+        ];
+
+        codes.extend(
+            config
+                .short_codes
+                .iter()
+                .map(|(code, action)| (code.as_str(), LocalCode::Short(*action))),
+        );
+
+        codes
+    } else {
+        vec![
+            (config.replay_dial_code.as_str(), LocalCode::Replay),
+            (
+                config.switch_audio_devices_dial_code.as_str(),
+                LocalCode::SwitchAudioDevices,
+            ),
+        ]
+    };
+
+    codes
+        .into_iter()
+        .filter(|(code, _)| !code.is_empty())
+        .filter(|(code, _)| call_state == CallState::Idle || !config.in_call_dtmf || code.len() > 1)
+        .collect()
+}
+
+#[allow(clippy::too_many_arguments)]
+pub async fn ui_entry(
+    network_sender: Sender<PhoneOutgoingMessage>,
+    network_reciever: Receiver<PhoneIncomingMessage>,
+    mute_sender: Sender<MuteState>,
+    audio_event_receiver: Receiver<AudioEvent>,
+    audio_command_sender: Sender<AudioCommand>,
+    rtc_command_sender: Sender<RTCCommand>,
+    rtc_event_receiver: Receiver<RTCEvent>,
+    mixer_sender: Sender<MixerMessage>,
+) {
+    let config = config::get();
+
+    #[cfg(not(feature = "real"))]
+    let (mut hardware, ui) = {
+        let mut hardware = hardware::emulated::Hardware::create();
+        let ui = hardware.take_gui();
+        (hardware, ui)
+    };
+    #[cfg(all(feature = "real", not(feature = "mirror")))]
+    let mut hardware = hardware::console::PhysicalOrConsole::create();
+    #[cfg(feature = "mirror")]
+    let (mut hardware, ui) = {
+        let mut hardware = hardware::mirrored::Hardware::create();
+        let ui = hardware.take_gui();
+        (hardware, ui)
+    };
+
+    let (_stream, sink) = local_sound_sink();
+    let mut sounds = SoundQueue::new();
+
+    hardware.ring(false);
+    hardware.enable_dialing(config.intercom_number.is_none());
+
+    // The settled hook state, and the one the phone goes by, which only follows it on-hook
+    // once the hang-up timer says so
+    let mut settled_hook_state = true;
+    let mut last_hook_state = true;
+    let clock = clock::system();
+    let mut hook_settler = HookSettler::new(true, clock.clone());
+    // Off-hook was reported for an intercom auto-answer while the handset is still down
+    let mut auto_answered = false;
+
+    let caller_id = config
+        .caller_id
+        .clone()
+        .or_else(|| config.extension.clone());
+
+    // Set by the server for the current call, cleared on hang-up or when the call ends
+    let mut server_input_muted: Option<bool> = None;
+    let mut server_output_muted: Option<bool> = None;
+    let mut last_ptt_state = false;
+    let mut last_muted: Option<MuteState> = None;
+    let mut last_transmitting = false;
+    let mut last_audio_idle = false;
+    // When the tones queued in the mixer are done, for mute_during_tones
+    let mut mixer_tone_until = Instant::now();
+    // Collects the RTC and audio halves of a diagnostics request, see DIAGNOSTICS_TIMEOUT
+    let mut pending_diagnostics: Option<(Instant, Diagnostics)> = None;
+
+    // Answer confirmation, see config.answer_confirmation
+    let mut ready_since: Option<Instant> = None;
+    let mut answer_confirmed = false;
+
+    let mut call_state = CallState::Idle;
+    let mut current_call_id: Option<Uuid> = None;
+    let mut call_waiting_tone_at: Option<Instant> = None;
+    // When the handset went down mid-call and how long it has to stay there to hang up
+    let mut hang_up_timer = HangUpTimer::new(clock.clone());
+
+    let mut on_hold = false;
+    let mut hold_reminder_at: Option<Instant> = None;
+    let mut recovering_peers = HashSet::new();
+    let mut reconnecting_tone_at: Option<Instant> = None;
+
+    let mut dial_setup_start: Option<Instant> = None;
+    let mut ring_test_start: Option<Instant> = None;
+    let mut ring_limiter = RingLimiter::new(clock);
+    // Digits held back while they could still be the start of a local code, rearmed on hook
+    // and call state changes
+    let mut local_code_digits = Some(String::new());
+    // When the held digits started waiting on the next one, see short_code_timeout
+    let mut local_code_waiting_since: Option<Instant> = None;
+    // Digits sent since the last hook change, capped at max_dialed_digits
+    let mut dialed_digits = 0;
+    // Set while ringback or the connecting tone plays, both stop once the peer answers
+    let mut ringback_playing = false;
+    // Off-hook with no call since then, restarted by every digit
+    let mut off_hook_idle_since: Option<Instant> = None;
+    let mut off_hook_stage = OffHookStage::Dialtone;
+
+    #[allow(unused_variables)]
+    let ui_process_join_handle = tokio::spawn(async move {
+        loop {
+            watchdog::beat(Heartbeat::Ui);
+
+            hardware.update();
+
+            let hook_state = hook_settler.update(hardware.get_hook_state());
+
+            let dialed = take_dialed_number(&mut hardware, hook_state);
+
+            let local_code_timed_out = local_code_waiting_since
+                .is_some_and(|deadline| deadline.elapsed() > config.short_code_timeout);
+
+            if !dialed.is_empty() || local_code_timed_out {
+                off_hook_idle_since = None;
+
+                if off_hook_stage != OffHookStage::Dialtone {
+                    off_hook_stage = OffHookStage::Dialtone;
+
+                    sounds.stop();
+                    sink.clear();
+                    sink.pause();
+                }
+
+                // Only digits mean anything to the server
+                let mut number: String = dialed
+                    .chars()
+                    .filter(|digit| digit.is_ascii_digit())
+                    .collect();
+
+                // e.g. a spurious pulse that decoded to nothing, or a lone * or #
+                if !dialed.is_empty() && number.is_empty() {
+                    println!("Skipping {:?} dialed, it has no digits", dialed);
+
+                    if call_state == CallState::Idle && !last_hook_state {
+                        sounds.stop();
+                        play_reorder(&sink);
+                    }
+                }
+
+                let local_codes = local_codes(call_state, config);
+
+                local_code_waiting_since = None;
+
+                if let Some(held_digits) = &mut local_code_digits {
+                    held_digits.push_str(&number);
+                    number = String::new();
+
+                    let exact_match = local_codes
+                        .iter()
+                        .find(|(code, _)| code == held_digits)
+                        .map(|(_, local_code)| *local_code);
+                    let longer_match = local_codes.iter().any(|(code, _)| {
+                        code.len() > held_digits.len() && code.starts_with(held_digits.as_str())
+                    });
+
+                    match exact_match {
+                        // Could still become a longer code, give the next digit a moment
+                        Some(_) if longer_match && !local_code_timed_out => {
+                            local_code_waiting_since = Some(Instant::now());
+                        }
+                        Some(local_code) => {
+                            let code = std::mem::take(held_digits);
+
+                            match local_code {
+                                LocalCode::ReportStatus => {
+                                    let _ = rtc_command_sender.send(RTCCommand::ReportStatus);
+                                }
+                                LocalCode::AnnounceExtension => match &config.extension {
+                                    Some(extension) => {
+                                        println!("Announcing extension {}", extension);
+
+                                        sounds.stop();
+                                        sink.clear();
+                                        sink.pause();
+
+                                        play_tone(
+                                            &mixer_sender,
+                                            tone::dtmf(extension),
+                                            &mut mixer_tone_until,
+                                        );
+                                    }
+                                    None => {
+                                        println!("No extension set, nothing to announce");
+
+                                        sounds.stop();
+                                        play_reorder(&sink);
+                                    }
+                                },
+                                LocalCode::Replay => {
+                                    let _ = mixer_sender.send(MixerMessage::Replay);
+                                }
+                                LocalCode::SwitchAudioDevices => {
+                                    let _ = audio_command_sender.send(AudioCommand::SwitchDevices);
+                                }
+                                LocalCode::Short(action) => {
+                                    println!("Short code {} dialed: {:?}", code, action);
+
+                                    number = match action {
+                                        ShortCodeAction::DoorOpen => String::from(DOOR_OPEN_NUMBER),
+                                        ShortCodeAction::Intercom => {
+                                            config.intercom_number.clone().unwrap_or_default()
+                                        }
+                                        ShortCodeAction::DialThrough => code,
+                                        ShortCodeAction::Ignore => String::new(),
+                                    };
+                                }
+                            }
+                        }
+                        // Nothing matches, or nothing more came before the timeout
+                        None if !longer_match || local_code_timed_out => {
+                            number = local_code_digits.take().unwrap_or_default();
+                        }
+                        // The start of a code, wait for the rest
+                        None => {
+                            local_code_waiting_since = Some(Instant::now());
+                        }
+                    }
+                }
+
+                match route_digits(call_state, last_hook_state, config) {
+                    // Checked and sent with Dial below
+                    _ if number.is_empty() => {}
+                    DigitRoute::Dial => {}
+                    DigitRoute::Dtmf => {
+                        println!("{} Sending DTMF {}", call_tag(current_call_id), number);
+
+                        let samples = tone::dtmf(&number);
+
+                        // Heard locally too, like a real phone
+                        play_tone(&mixer_sender, samples.clone(), &mut mixer_tone_until);
+
+                        let _ = rtc_command_sender.send(RTCCommand::SendDtmf(samples));
+
+                        number.clear();
+                    }
+                    DigitRoute::Ignore => {
+                        println!(
+                            "{} Ignoring {} dialed during the call",
+                            call_tag(current_call_id),
+                            number
+                        );
+
+                        number.clear();
+                    }
+                }
+
+                let remaining_digits = config.max_dialed_digits.saturating_sub(dialed_digits);
+
+                if number.len() > remaining_digits {
+                    number.truncate(remaining_digits);
+
+                    // Only complain once, the rest of this hook state is ignored
+                    if remaining_digits > 0 || dialed_digits == config.max_dialed_digits {
+                        println!(
+                            "Dialed more than {} digits, dropping the rest",
+                            config.max_dialed_digits
+                        );
+
+                        dialed_digits = config.max_dialed_digits + 1;
+
+                        sounds.stop();
+                        play_reorder(&sink);
+                    }
+                }
+
+                if !number.is_empty() && !network::is_dialable(&number) {
+                    println!("Not dialing {:?}, it isn't a valid number", number);
+
+                    sounds.stop();
+                    play_reorder(&sink);
+
+                    number.clear();
+                }
+
+                if !number.is_empty() {
+                    dialed_digits += number.len();
+
+                    let _ = network_sender.send(PhoneOutgoingMessage::Dial {
+                        number,
+                        caller_id: caller_id.clone(),
+                        call_id: call_id(&mut current_call_id, &rtc_command_sender),
+                    });
+
+                    if call_state == CallState::Idle {
+                        dial_setup_start = Some(Instant::now());
+                    }
+                }
+            }
+
+            if hook_state != settled_hook_state {
+                settled_hook_state = hook_state;
+
+                let calls_on_hold = config.call_waiting_enabled
+                    && matches!(
+                        call_state,
+                        CallState::Waiting { .. } | CallState::Holding { .. }
+                    );
+
+                let can_hold = config.hold_enabled && matches!(call_state, CallState::Active(_));
+
+                // Only a Report changes the hook state everything else goes by
+                match hang_up_timer.change(
+                    hook_state,
+                    call_state,
+                    calls_on_hold || can_hold,
+                    config,
+                ) {
+                    HookChange::Report | HookChange::Wait => {}
+                    HookChange::Flash if calls_on_hold => {
+                        let _ = rtc_command_sender.send(RTCCommand::SwapCalls);
+                    }
+                    HookChange::Flash => {
+                        let _ = rtc_command_sender.send(RTCCommand::ToggleHold);
+                    }
+                    HookChange::Bump => {
+                        println!(
+                            "{} Handset was only bumped, keeping the call",
+                            call_tag(current_call_id)
+                        );
+                    }
+                }
+            }
+
+            if hang_up_timer.expired() {
+                println!(
+                    "{} Handset stayed down, hanging up",
+                    call_tag(current_call_id)
+                );
+            }
+
+            if hang_up_timer.on_hook() != last_hook_state {
+                last_hook_state = hang_up_timer.on_hook();
+
+                local_code_digits = Some(String::new());
+                local_code_waiting_since = None;
+                dialed_digits = 0;
+
+                let _ = rtc_command_sender.send(RTCCommand::SetOffHook(!last_hook_state));
+
+                off_hook_idle_since = None;
+
+                if off_hook_stage != OffHookStage::Dialtone {
+                    off_hook_stage = OffHookStage::Dialtone;
+
+                    sounds.stop();
+                    sink.clear();
+                    sink.pause();
+                }
+
+                if last_hook_state {
+                    dial_setup_start = None;
+                    server_input_muted = None;
+                    server_output_muted = None;
+
+                    hardware.show_caller_id(None);
+
+                    if config.flush_mic_on_hangup && call_state != CallState::Idle {
+                        let _ = audio_command_sender.send(AudioCommand::FlushInput);
+                    }
+                }
+
+                // Already reported off-hook when the call was auto-answered
+                if last_hook_state || !std::mem::take(&mut auto_answered) {
+                    let _ = network_sender.send(PhoneOutgoingMessage::Hook {
+                        state: last_hook_state,
+                    });
+                }
+
+                if let Some(intercom_number) = &config.intercom_number {
+                    if !last_hook_state && call_state == CallState::Idle {
+                        let _ = network_sender.send(PhoneOutgoingMessage::Dial {
+                            number: intercom_number.clone(),
+                            caller_id: caller_id.clone(),
+                            call_id: call_id(&mut current_call_id, &rtc_command_sender),
+                        });
+
+                        dial_setup_start = Some(Instant::now());
+                    }
+                }
+            }
+
+            if !last_hook_state && call_state == CallState::Idle && dial_setup_start.is_none() {
+                let idle_for = off_hook_idle_since
+                    .get_or_insert_with(Instant::now)
+                    .elapsed();
+
+                if off_hook_stage == OffHookStage::Dialtone
+                    && config
+                        .off_hook_reorder_after
+                        .is_some_and(|after| idle_for >= after)
+                {
+                    println!(
+                        "Off-hook for {:?} without a call, playing reorder",
+                        idle_for
+                    );
+
+                    off_hook_stage = OffHookStage::Reorder;
+
+                    sounds.stop();
+                    play_reorder(&sink);
+                }
+
+                if off_hook_stage != OffHookStage::Howler
+                    && config
+                        .off_hook_howler_after
+                        .is_some_and(|after| idle_for >= after)
+                {
+                    println!("Off-hook for {:?} without a call, howling", idle_for);
+
+                    off_hook_stage = OffHookStage::Howler;
+
+                    sounds.play(&sink, Sound::Hangup);
+                }
+            } else {
+                off_hook_idle_since = None;
+                off_hook_stage = OffHookStage::Dialtone;
+            }
+
+            if config.ptt_enabled {
+                last_ptt_state = hardware.get_ptt_state();
+            }
+
+            while let Ok(audio_event) = audio_event_receiver.try_recv() {
+                match audio_event {
+                    AudioEvent::StreamError(kind, error) => {
+                        println!("Audio stream error ({:?}): {}", kind, error);
+                    }
+                    AudioEvent::OutputStalled => {
+                        println!("Audio output stalled, no samples from the mixer");
+                    }
+                    AudioEvent::OutputDisconnected => {
+                        println!("Audio output lost its sample source, rebuilding stream");
+                    }
+                    AudioEvent::Diagnostics(audio) => {
+                        if let Some((_, diagnostics)) = &mut pending_diagnostics {
+                            diagnostics.audio = Some(audio);
+                        }
+                    }
+                }
+            }
+
+            while let Ok(rtc_event) = rtc_event_receiver.try_recv() {
+                match rtc_event {
+                    RTCEvent::CallState(state) => {
+                        call_state = state;
+                        local_code_digits = Some(String::new());
 
-pub async fn ui_entry(
-    network_sender: Sender<PhoneOutgoingMessage>,
-    network_reciever: Receiver<PhoneIncomingMessage>,
-    mute_sender: Sender<bool>,
-) {
-    #[cfg(not(feature = "real"))]
-    let (mut hardware, ui) = {
-        let mut hardware = hardware::emulated::Hardware::create();
-        let ui = hardware.take_gui();
-        (hardware, ui)
-    };
-    #[cfg(feature = "real")]
-    let mut hardware = hardware::physical::Hardware::create();
+                        if call_state != CallState::Idle {
+                            dial_setup_start = None;
+                        } else {
+                            server_input_muted = None;
+                            server_output_muted = None;
 
-    let (_stream, stream_handle) = OutputStream::try_default().unwrap();
+                            if std::mem::take(&mut auto_answered) {
+                                let _ =
+                                    network_sender.send(PhoneOutgoingMessage::Hook { state: true });
+                            }
+                        }
+                    }
+                    RTCEvent::PeerConnected(peer) => {
+                        // Don't rely on the server to stop ringback once they pick up
+                        if ringback_playing {
+                            println!(
+                                "{} Peer {} answered, stopping ringback",
+                                call_tag(current_call_id),
+                                peer
+                            );
 
-    let sink: Sink = Sink::try_new(&stream_handle).unwrap();
+                            ringback_playing = false;
 
-    hardware.ring(false);
-    hardware.enable_dialing(true);
+                            sounds.stop();
+                            sink.clear();
+                            sink.pause();
+                        }
+                    }
+                    RTCEvent::Recovering(peer, recovering) => {
+                        if recovering {
+                            recovering_peers.insert(peer);
+                        } else {
+                            recovering_peers.remove(&peer);
+                        }
+                    }
+                    RTCEvent::ReconnectFailed(peer) => {
+                        if !last_hook_state {
+                            println!("{} Couldn't get {} back", call_tag(current_call_id), peer);
 
-    let _ = mute_sender.send(true);
+                            sounds.stop();
+                            play_reorder(&sink);
+                        }
+                    }
+                    RTCEvent::NetworkPath(_, path) => {
+                        // Only the active call is heard, so follow whoever connected last
+                        let _ = audio_command_sender.send(AudioCommand::SetOutputTargetDepth(
+                            path.output_target_depth(),
+                        ));
+                    }
+                    RTCEvent::Busy(peer) => {
+                        // Only a call we're placing, not another phone turning away our join
+                        if dial_setup_start.take().is_some() && !last_hook_state {
+                            println!("{} {} is busy", call_tag(current_call_id), peer);
 
-    let mut last_hook_state = true;
+                            ringback_playing = false;
 
-    #[allow(unused_variables)]
-    let ui_process_join_handle = tokio::spawn(async move {
-        loop {
-            hardware.update();
+                            sounds.stop();
+                            sink.clear();
+                            sink.append(on_tone_channels(busy_tone()));
+                            sink.play();
+                        }
+                    }
+                    RTCEvent::Hold(state) => {
+                        on_hold = state;
+                    }
+                    RTCEvent::AnswerConfirmed(peer) => {
+                        if config.answer_confirmation && ready_since.is_some() && !answer_confirmed
+                        {
+                            println!("{} Answer confirmed by {}", call_tag(current_call_id), peer);
 
-            if !(*hardware.dialed_number()).is_empty() {
-                let _ = network_sender.send(PhoneOutgoingMessage::Dial {
-                    number: hardware.dialed_number().clone(),
-                });
+                            answer_confirmed = true;
 
-                *hardware.dialed_number() = String::from("");
+                            let mut chirp = tone::sine(&[1200.0], Duration::from_millis(60), 0.2);
+                            chirp.extend(tone::silence(Duration::from_millis(40)));
+                            chirp.extend(tone::sine(&[1600.0], Duration::from_millis(60), 0.2));
+
+                            play_tone(&mixer_sender, chirp, &mut mixer_tone_until);
+                        }
+                    }
+                    RTCEvent::Diagnostics {
+                        signaling_connected,
+                        peers,
+                    } => {
+                        if let Some((_, diagnostics)) = &mut pending_diagnostics {
+                            diagnostics.signaling_connected = Some(signaling_connected);
+                            diagnostics.peers = Some(peers);
+                        }
+                    }
+                    RTCEvent::Status {
+                        signaling_connected,
+                        peers,
+                        round_trip_time_ms,
+                    } => {
+                        println!(
+                            "Status: signaling connected {}, {} peers, round trip {:?}ms",
+                            signaling_connected, peers, round_trip_time_ms
+                        );
+
+                        sounds.stop();
+                        sink.clear();
+                        sink.pause();
+
+                        play_tone(
+                            &mixer_sender,
+                            status_beeps(signaling_connected, peers, round_trip_time_ms),
+                            &mut mixer_tone_until,
+                        );
+                    }
+                }
+            }
+
+            if ring_test_start.is_some_and(|start| start.elapsed() > config.ring_test_duration) {
+                ring_test_start = None;
+
+                if let Some(state) = ring_limiter.request(false) {
+                    hardware.ring(state);
+                }
+            }
+
+            if let Some(state) = ring_limiter.poll() {
+                hardware.ring(state);
+            }
+
+            if dial_setup_start.is_some_and(|start| start.elapsed() > config.dial_setup_timeout) {
+                println!("{} Setup timed out", call_tag(current_call_id));
+
+                dial_setup_start = None;
+                ringback_playing = false;
+
+                // Nobody is listening to an auto-answered call that never connected
+                if !std::mem::take(&mut auto_answered) {
+                    sounds.stop();
+                    play_reorder(&sink);
+                }
+
+                let _ = network_sender.send(PhoneOutgoingMessage::Hook { state: true });
+            }
+
+            if config.call_waiting_enabled && matches!(call_state, CallState::Waiting { .. }) {
+                if call_waiting_tone_at
+                    .is_none_or(|tone_at| tone_at.elapsed() >= config.call_waiting_tone_interval)
+                {
+                    call_waiting_tone_at = Some(Instant::now());
+
+                    play_tone(
+                        &mixer_sender,
+                        tone::sine(
+                            &[config.call_waiting_tone_frequency],
+                            config.call_waiting_tone_length,
+                            0.25,
+                        ),
+                        &mut mixer_tone_until,
+                    );
+                }
+            } else {
+                call_waiting_tone_at = None;
+            }
+
+            if on_hold {
+                if hold_reminder_at.is_none_or(|reminder_at| {
+                    reminder_at.elapsed() >= config.hold_reminder_interval
+                }) {
+                    hold_reminder_at = Some(Instant::now());
+
+                    play_tone(
+                        &mixer_sender,
+                        tone::sine(
+                            &[config.hold_reminder_tone_frequency],
+                            config.hold_reminder_tone_length,
+                            0.15,
+                        ),
+                        &mut mixer_tone_until,
+                    );
+                }
+            } else {
+                hold_reminder_at = None;
             }
 
-            if hardware.get_hook_state() != last_hook_state {
-                last_hook_state = hardware.get_hook_state();
+            if config.reconnecting_tone_enabled && !recovering_peers.is_empty() && !last_hook_state
+            {
+                if reconnecting_tone_at
+                    .is_none_or(|tone_at| tone_at.elapsed() >= config.reconnecting_tone_interval)
+                {
+                    reconnecting_tone_at = Some(Instant::now());
 
-                let _ = network_sender.send(PhoneOutgoingMessage::Hook {
-                    state: last_hook_state,
-                });
+                    play_tone(
+                        &mixer_sender,
+                        tone::sine(&[480.0], Duration::from_millis(80), 0.08),
+                        &mut mixer_tone_until,
+                    );
+                }
+            } else {
+                reconnecting_tone_at = None;
             }
 
             while let Ok(network_message) = network_reciever.try_recv() {
@@ -60,58 +1321,692 @@ pub async fn ui_entry(
 
                 match network_message {
                     PhoneIncomingMessage::Ring { state } => {
-                        hardware.ring(state);
+                        ring_test_start = None;
+
+                        if state && config.intercom_auto_answer && last_hook_state {
+                            auto_answered = true;
+                            dial_setup_start = Some(Instant::now());
+
+                            let _ =
+                                network_sender.send(PhoneOutgoingMessage::Hook { state: false });
+                        } else if let Some(state) = ring_limiter.request(state) {
+                            hardware.ring(state);
+                        }
                     }
-                    PhoneIncomingMessage::Mute { state } => {
-                        let _ = mute_sender.send(state);
+                    PhoneIncomingMessage::RingTest => {
+                        ring_test_start = Some(Instant::now());
+
+                        if let Some(state) = ring_limiter.request(true) {
+                            hardware.ring(state);
+                        }
                     }
-                    PhoneIncomingMessage::PlaySound { sound } => match sound {
-                        Sound::None => {
-                            sink.clear();
-                            sink.pause();
+                    PhoneIncomingMessage::Diagnostics => {
+                        // Filled in as the other threads answer, and sent once they have
+                        pending_diagnostics = Some((
+                            Instant::now(),
+                            Diagnostics {
+                                uptime_seconds: 0,
+                                hook_on: true,
+                                input_muted: true,
+                                output_muted: true,
+                                call_state: String::new(),
+                                call_id: None,
+                                signaling_connected: None,
+                                peers: None,
+                                audio: None,
+                                packets_received: 0,
+                                packets_lost: 0,
+                                audio_underruns: 0,
+                            },
+                        ));
+
+                        let _ = rtc_command_sender.send(RTCCommand::ReportDiagnostics);
+                        let _ = audio_command_sender.send(AudioCommand::ReportDiagnostics);
+                    }
+                    PhoneIncomingMessage::Mute { state, direction } => match direction {
+                        MuteDirection::Both => {
+                            server_input_muted = Some(state);
+                            server_output_muted = Some(state);
                         }
-                        Sound::Dialtone => {
-                            let source = Decoder::new_looped(Cursor::new(include_bytes!(
-                                "../assets/dialtone.flac"
-                            )))
-                            .unwrap();
+                        MuteDirection::Input => server_input_muted = Some(state),
+                        MuteDirection::Output => server_output_muted = Some(state),
+                    },
+                    PhoneIncomingMessage::CallerId { id } => {
+                        let id = id.filter(|id| !id.trim().is_empty());
 
-                            sink.clear();
-                            sink.append(source.convert_samples::<f32>());
-                            sink.play();
+                        println!(
+                            "{} Incoming call from: {}",
+                            call_tag(current_call_id),
+                            id.as_deref().unwrap_or("Unknown")
+                        );
+
+                        hardware.show_caller_id(Some(id.as_deref().unwrap_or("Unknown")));
+                    }
+                    PhoneIncomingMessage::AudioProfile { name } => {
+                        let Some(profile) = config.audio_profiles.get(&name) else {
+                            println!("Unknown audio profile {:?}, keeping the current one", name);
+
+                            continue;
+                        };
+
+                        println!("Switching to audio profile {:?}", name);
+
+                        let _ = mixer_sender.send(MixerMessage::SetProfile(profile.clone()));
+                    }
+                    PhoneIncomingMessage::Notice { text } => {
+                        let notice = sanitize_notice(&text);
+
+                        if notice.is_empty() {
+                            println!("Notice cleared");
+
+                            hardware.show_notice(None);
+                        } else {
+                            println!("Notice: {}", notice);
+
+                            hardware.show_notice(Some(&notice));
+
+                            if config.notice_tone_enabled && !last_hook_state {
+                                let mut samples =
+                                    tone::sine(&[880.0], Duration::from_millis(80), 0.15);
+                                samples.extend(tone::silence(Duration::from_millis(80)));
+                                samples.extend(tone::sine(
+                                    &[1320.0],
+                                    Duration::from_millis(80),
+                                    0.15,
+                                ));
+
+                                play_tone(&mixer_sender, samples, &mut mixer_tone_until);
+                            }
                         }
-                        Sound::Ringback => {
-                            let source = Decoder::new_looped(Cursor::new(include_bytes!(
-                                "../assets/ringback.flac"
-                            )))
-                            .unwrap();
+                    }
+                    PhoneIncomingMessage::PlaySound { sound } => {
+                        // Leaves ringback and call setup alone
+                        if sound.is_one_shot() {
+                            sounds.play(&sink, sound);
 
-                            sink.clear();
-                            sink.append(source.convert_samples::<f32>());
-                            sink.play();
+                            continue;
                         }
-                        Sound::Hangup => {
-                            let source = Decoder::new_looped(Cursor::new(include_bytes!(
-                                "../assets/hangup.flac"
-                            )))
-                            .unwrap();
 
-                            sink.clear();
-                            sink.append(source.convert_samples::<f32>());
-                            sink.play();
+                        let setup_sound = matches!(sound, Sound::Connecting | Sound::Ringback);
+
+                        if ringback_playing && !setup_sound {
+                            dial_setup_start = None;
                         }
-                    },
+
+                        ringback_playing = setup_sound;
+
+                        sounds.play(&sink, sound);
+                    }
+                }
+            }
+
+            sounds.update(&sink);
+
+            let connected = is_connected(last_hook_state, auto_answered, call_state);
+
+            if !last_hook_state || auto_answered || call_state != CallState::Idle {
+                call_id(&mut current_call_id, &rtc_command_sender);
+            } else if let Some(ended_call_id) = current_call_id.take() {
+                println!("{} Ended", call_tag(Some(ended_call_id)));
+
+                let _ = rtc_command_sender.send(RTCCommand::SetCallId(None));
+            }
+
+            if connected != ready_since.is_some() {
+                ready_since = connected.then(Instant::now);
+                answer_confirmed = false;
+
+                if config.answer_confirmation {
+                    let _ = rtc_command_sender.send(RTCCommand::SetReady(connected));
+                }
+
+                let _ = rtc_command_sender.send(RTCCommand::SetBusy(connected));
+
+                // Whatever the server said takes precedence, so only worth saying when it's quiet
+                if config.idle_muted
+                    && config.auto_unmute_on_connect
+                    && server_input_muted.is_none()
+                    && server_output_muted.is_none()
+                {
+                    println!(
+                        "{} {}",
+                        call_tag(current_call_id),
+                        if connected {
+                            "Picked up with a peer connected, unmuting"
+                        } else {
+                            "No longer connected, muting"
+                        }
+                    );
+                }
+            }
+
+            let awaiting_confirmation = config.answer_confirmation
+                && !answer_confirmed
+                && ready_since
+                    .is_some_and(|since| since.elapsed() < config.answer_confirmation_timeout);
+
+            // Released as soon as the tone ends, with no hang time, so speech right after a
+            // tone isn't clipped. The encoder restarts cleanly on unmute
+            let tone_playing = config.mute_during_tones
+                && ((config.local_sounds_enabled && !sink.empty() && !sink.is_paused())
+                    || mixer_tone_until > Instant::now());
+
+            let muted = MuteSources {
+                hung_up: last_hook_state && !auto_answered,
+                connected,
+                server_input: server_input_muted,
+                server_output: server_output_muted,
+                ptt_released: config.ptt_enabled && !last_ptt_state,
+                awaiting_confirmation,
+                tone_playing,
+            }
+            .resolve(config);
+
+            if last_muted != Some(muted) {
+                last_muted = Some(muted);
+
+                let _ = mute_sender.send(muted);
+            }
+
+            let transmitting = connected && !muted.input && !on_hold;
+
+            if transmitting != last_transmitting {
+                last_transmitting = transmitting;
+
+                hardware.show_transmitting(transmitting);
+            }
+
+            let audio_idle =
+                last_hook_state && !auto_answered && call_state == CallState::Idle && !connected;
+
+            if audio_idle != last_audio_idle {
+                last_audio_idle = audio_idle;
+
+                let _ = audio_command_sender.send(AudioCommand::SetIdle(audio_idle));
+            }
+
+            let diagnostics_ready =
+                pending_diagnostics
+                    .as_ref()
+                    .is_some_and(|(requested_at, diagnostics)| {
+                        (diagnostics.peers.is_some() && diagnostics.audio.is_some())
+                            || requested_at.elapsed() > DIAGNOSTICS_TIMEOUT
+                    });
+
+            if diagnostics_ready {
+                if let Some((_, mut diagnostics)) = pending_diagnostics.take() {
+                    diagnostics.uptime_seconds = metrics::uptime().as_secs();
+                    diagnostics.hook_on = last_hook_state;
+                    diagnostics.input_muted = muted.input;
+                    diagnostics.output_muted = muted.output;
+                    diagnostics.call_state = format!("{:?}", call_state);
+                    diagnostics.call_id = current_call_id;
+                    diagnostics.packets_received =
+                        metrics::PACKETS_RECEIVED.load(Ordering::Relaxed);
+                    diagnostics.packets_lost = metrics::PACKETS_LOST.load(Ordering::Relaxed);
+                    diagnostics.audio_underruns = metrics::AUDIO_UNDERRUNS.load(Ordering::Relaxed);
+
+                    println!("Diagnostics: {:?}", diagnostics);
+
+                    let _ = network_sender.send(PhoneOutgoingMessage::Diagnostics {
+                        snapshot: diagnostics,
+                    });
                 }
             }
         }
     });
 
-    #[cfg(feature = "real")]
+    #[cfg(all(feature = "real", not(feature = "mirror")))]
     {
         ui_process_join_handle.await;
     }
-    #[cfg(not(feature = "real"))]
+    #[cfg(any(not(feature = "real"), feature = "mirror"))]
     {
         ui.go();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+
+    #[derive(Default)]
+    struct MockHardware {
+        hook_state: bool,
+        dialed_number: String,
+    }
+
+    impl PhoneHardware for MockHardware {
+        fn create() -> Self {
+            Self::default()
+        }
+
+        fn update(&mut self) {}
+
+        fn ring(&mut self, _enabled: bool) {}
+
+        fn enable_dialing(&mut self, _enabled: bool) {}
+
+        fn dialed_number(&mut self) -> &mut String {
+            &mut self.dialed_number
+        }
+
+        fn get_hook_state(&self) -> bool {
+            self.hook_state
+        }
+
+        fn get_ptt_state(&self) -> bool {
+            false
+        }
+
+        fn show_caller_id(&mut self, _caller_id: Option<&str>) {}
+
+        fn show_notice(&mut self, _notice: Option<&str>) {}
+
+        fn show_transmitting(&mut self, _transmitting: bool) {}
+    }
+
+    fn idle() -> MuteSources {
+        MuteSources {
+            hung_up: false,
+            connected: false,
+            server_input: None,
+            server_output: None,
+            ptt_released: false,
+            awaiting_confirmation: false,
+            tone_playing: false,
+        }
+    }
+
+    const UNMUTED: MuteState = MuteState {
+        input: false,
+        output: false,
+    };
+
+    #[test]
+    fn connecting_off_hook_unmutes() {
+        let config = Config {
+            idle_muted: true,
+            auto_unmute_on_connect: true,
+            ..Config::default()
+        };
+
+        let off_hook = idle();
+        let connected = MuteSources {
+            connected: true,
+            ..idle()
+        };
+
+        assert_eq!(off_hook.resolve(&config), MuteState::ALL);
+        assert_eq!(connected.resolve(&config), UNMUTED);
+
+        // Never while on-hook, even with a peer connected
+        let hung_up = MuteSources {
+            hung_up: true,
+            ..connected
+        };
+
+        assert_eq!(hung_up.resolve(&config), MuteState::ALL);
+
+        // The server still has the last word
+        let server_muted = MuteSources {
+            connected: true,
+            server_input: Some(true),
+            ..idle()
+        };
+
+        assert_eq!(
+            server_muted.resolve(&config),
+            MuteState {
+                input: true,
+                output: false,
+            }
+        );
+    }
+
+    #[test]
+    fn connecting_stays_muted_without_auto_unmute() {
+        let config = Config {
+            idle_muted: true,
+            auto_unmute_on_connect: false,
+            ..Config::default()
+        };
+
+        let connected = MuteSources {
+            connected: true,
+            ..idle()
+        };
+
+        assert_eq!(connected.resolve(&config), MuteState::ALL);
+
+        let server_unmuted = MuteSources {
+            server_input: Some(false),
+            server_output: Some(false),
+            ..connected
+        };
+
+        assert_eq!(server_unmuted.resolve(&config), UNMUTED);
+    }
+
+    #[test]
+    fn bouncy_pickup_settles_into_one_change() {
+        let clock = ManualClock::new();
+        let mut hook_settler = HookSettler::new(true, clock.clone());
+
+        // Lifted with a few bounces back to on-hook, then held off-hook
+        let raw_states = [
+            false, true, false, false, true, false, true, false, false, false, false, false, false,
+            false, false, false,
+        ];
+
+        let mut last_state = true;
+        let mut changes = 0;
+
+        for raw in raw_states {
+            let state = hook_settler.update(raw);
+
+            if state != last_state {
+                last_state = state;
+                changes += 1;
+            }
+
+            clock.advance(Duration::from_millis(10));
+        }
+
+        assert_eq!(changes, 1);
+        assert!(!last_state);
+    }
+
+    #[test]
+    fn hang_up_wins_over_digits_in_the_same_update() {
+        let clock = ManualClock::new();
+        let mut hook_settler = HookSettler::new(false, clock.clone());
+        let mut hardware = MockHardware::create();
+
+        hardware.dialed_number.push('5');
+
+        let hook_state = hook_settler.update(hardware.get_hook_state());
+
+        assert_eq!(take_dialed_number(&mut hardware, hook_state), "5");
+
+        // The handset goes down, and a digit finishes just as the hang-up settles
+        hardware.hook_state = true;
+        assert!(!hook_settler.update(hardware.get_hook_state()));
+
+        clock.advance(config::get().hook_settle_time);
+        hardware.dialed_number.push('7');
+
+        let hook_state = hook_settler.update(hardware.get_hook_state());
+
+        assert!(hook_state);
+        assert_eq!(take_dialed_number(&mut hardware, hook_state), "");
+        assert!(hardware.dialed_number.is_empty());
+
+        // Nothing from on-hook is left for the next pickup
+        hardware.hook_state = false;
+        hook_settler.update(hardware.get_hook_state());
+        clock.advance(config::get().hook_settle_time);
+
+        let hook_state = hook_settler.update(hardware.get_hook_state());
+
+        assert!(!hook_state);
+        assert_eq!(take_dialed_number(&mut hardware, hook_state), "");
+    }
+
+    fn hang_up(config: &Config, down_for: Duration) -> (bool, HookChange) {
+        let clock = ManualClock::new();
+        let mut hang_up_timer = HangUpTimer::new(clock.clone());
+        let call_state = CallState::Active(Uuid::new_v4());
+
+        assert_eq!(
+            hang_up_timer.change(true, call_state, false, config),
+            HookChange::Wait
+        );
+
+        clock.advance(down_for);
+
+        let hung_up = hang_up_timer.expired();
+
+        (
+            hung_up,
+            hang_up_timer.change(false, call_state, false, config),
+        )
+    }
+
+    #[test]
+    fn handset_bumped_just_under_the_confirm_time_keeps_the_call() {
+        let config = Config {
+            hangup_confirm_time: Duration::from_millis(400),
+            ..Config::default()
+        };
+
+        assert_eq!(
+            hang_up(&config, Duration::from_millis(399)),
+            (false, HookChange::Bump)
+        );
+    }
+
+    #[test]
+    fn handset_down_just_over_the_confirm_time_hangs_up() {
+        let config = Config {
+            hangup_confirm_time: Duration::from_millis(400),
+            ..Config::default()
+        };
+
+        // Picking up again after that is a new off-hook, not a bump
+        assert_eq!(
+            hang_up(&config, Duration::from_millis(401)),
+            (true, HookChange::Report)
+        );
+    }
+
+    #[test]
+    fn bumped_hook_mid_call_changes_nothing() {
+        let config = Config {
+            hangup_confirm_time: Duration::from_millis(400),
+            ..Config::default()
+        };
+        let clock = ManualClock::new();
+        let mut hook_settler = HookSettler::new(false, clock.clone());
+        let mut hang_up_timer = HangUpTimer::new(clock.clone());
+        let mut hardware = MockHardware::create();
+        let call_state = CallState::Active(Uuid::new_v4());
+
+        assert_eq!(
+            hang_up_timer.change(false, call_state, false, &config),
+            HookChange::Report
+        );
+
+        let in_call = |hang_up_timer: &HangUpTimer| {
+            let on_hook = hang_up_timer.on_hook();
+
+            (
+                on_hook,
+                is_connected(on_hook, false, call_state),
+                MuteSources {
+                    hung_up: on_hook,
+                    connected: true,
+                    ..idle()
+                }
+                .resolve(&config),
+            )
+        };
+        let before = in_call(&hang_up_timer);
+
+        // Down long enough to settle, then back up before the confirm time
+        for (hook_state, held_for) in [(true, 200), (false, 100)] {
+            hardware.hook_state = hook_state;
+            hook_settler.update(hardware.get_hook_state());
+            clock.advance(config.hook_settle_time);
+
+            let settled = hook_settler.update(hardware.get_hook_state());
+
+            hang_up_timer.change(settled, call_state, false, &config);
+            clock.advance(Duration::from_millis(held_for));
+
+            assert!(!hang_up_timer.expired());
+            assert_eq!(in_call(&hang_up_timer), before);
+        }
+
+        // Held down past the confirm time it hangs up after all
+        hang_up_timer.change(true, call_state, false, &config);
+        clock.advance(Duration::from_millis(401));
+
+        assert!(hang_up_timer.expired());
+        assert!(hang_up_timer.on_hook());
+        assert!(!is_connected(hang_up_timer.on_hook(), false, call_state));
+    }
+
+    #[test]
+    fn hanging_up_idle_is_reported_right_away() {
+        let clock = ManualClock::new();
+        let mut hang_up_timer = HangUpTimer::new(clock);
+
+        assert_eq!(
+            hang_up_timer.change(true, CallState::Idle, false, &Config::default()),
+            HookChange::Report
+        );
+        assert!(!hang_up_timer.expired());
+    }
+
+    #[test]
+    fn digits_dialed_mid_call_never_dial() {
+        let (active, other) = (Uuid::new_v4(), Uuid::new_v4());
+        let in_call = [
+            CallState::Active(active),
+            CallState::Waiting {
+                active,
+                waiting: other,
+            },
+            CallState::Holding {
+                active,
+                held: other,
+            },
+        ];
+
+        for in_call_dtmf in [false, true] {
+            let config = Config {
+                in_call_dtmf,
+                ..Config::default()
+            };
+
+            for call_state in in_call {
+                assert_ne!(route_digits(call_state, false, &config), DigitRoute::Dial);
+                assert_ne!(route_digits(call_state, true, &config), DigitRoute::Dial);
+            }
+
+            assert_eq!(
+                route_digits(CallState::Idle, false, &config),
+                DigitRoute::Dial
+            );
+        }
+
+        let config = Config {
+            in_call_dtmf: true,
+            ..Config::default()
+        };
+
+        assert_eq!(
+            route_digits(CallState::Active(active), false, &config),
+            DigitRoute::Dtmf
+        );
+
+        // Auto-answered with the handset still down
+        assert_eq!(
+            route_digits(CallState::Active(active), true, &config),
+            DigitRoute::Ignore
+        );
+    }
+
+    #[test]
+    fn ring_too_soon_is_held_until_the_interval_passes() {
+        let clock = ManualClock::new();
+        let mut ring_limiter = RingLimiter::new(clock.clone());
+        let ring_min_interval = config::get().ring_min_interval;
+
+        assert_eq!(ring_limiter.request(true), Some(true));
+
+        // Off always goes through, on again right after has to wait
+        assert_eq!(ring_limiter.request(false), Some(false));
+        assert_eq!(ring_limiter.request(true), None);
+
+        clock.advance(ring_min_interval - Duration::from_millis(1));
+        assert_eq!(ring_limiter.poll(), None);
+
+        clock.advance(Duration::from_millis(1));
+        assert_eq!(ring_limiter.poll(), Some(true));
+        assert_eq!(ring_limiter.poll(), None);
+    }
+
+    #[test]
+    fn hook_change_settles_at_the_settle_time() {
+        let clock = ManualClock::new();
+        let mut hook_settler = HookSettler::new(true, clock.clone());
+        let hook_settle_time = config::get().hook_settle_time;
+
+        assert!(hook_settler.update(false));
+
+        clock.advance(hook_settle_time - Duration::from_millis(1));
+        assert!(hook_settler.update(false));
+
+        clock.advance(Duration::from_millis(1));
+        assert!(!hook_settler.update(false));
+    }
+
+    // Whether the digits would be held for a local code rather than sent on
+    fn held_for_local_code(digits: &str, call_state: CallState, config: &Config) -> bool {
+        local_codes(call_state, config)
+            .iter()
+            .any(|(code, _)| code.starts_with(digits))
+    }
+
+    #[test]
+    fn default_short_codes_dont_wait_for_more_digits() {
+        let config = Config::default();
+        let codes = local_codes(CallState::Idle, &config);
+
+        for short_code in config.short_codes.keys() {
+            assert!(!codes
+                .iter()
+                .any(|(code, _)| code.len() > short_code.len()
+                    && code.starts_with(short_code.as_str())));
+        }
+    }
+
+    #[test]
+    fn nine_dialed_mid_call_goes_out_as_dtmf() {
+        let config = Config::default();
+        let call_state = CallState::Active(Uuid::new_v4());
+
+        assert!(config.in_call_dtmf);
+        assert!(!held_for_local_code("9", call_state, &config));
+        assert_eq!(route_digits(call_state, false, &config), DigitRoute::Dtmf);
+    }
+
+    #[test]
+    fn one_digit_in_call_codes_only_work_without_dtmf() {
+        let call_state = CallState::Active(Uuid::new_v4());
+        let config = Config {
+            replay_dial_code: String::from("9"),
+            ..Config::default()
+        };
+
+        assert!(!held_for_local_code("9", call_state, &config));
+
+        let config = Config {
+            in_call_dtmf: false,
+            ..config
+        };
+
+        assert!(held_for_local_code("9", call_state, &config));
+
+        let config = Config {
+            in_call_dtmf: true,
+            replay_dial_code: String::from("99"),
+            ..config
+        };
+
+        assert!(held_for_local_code("9", call_state, &config));
+    }
+}
@@ -0,0 +1,98 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::Write,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    thread,
+    time::Duration,
+};
+
+use crate::metrics;
+
+// The loops a phone can't work without. Each one calls beat every time around; the RTC loop
+// isn't one of them since its signaling reads block for as long as nothing is happening
+#[derive(Debug, Clone, Copy)]
+pub enum Heartbeat {
+    Audio,
+    Network,
+    Ui,
+}
+
+const HEARTBEATS: [Heartbeat; 3] = [Heartbeat::Audio, Heartbeat::Network, Heartbeat::Ui];
+
+// Uptime in milliseconds at each loop's last beat
+static LAST_BEATS: [AtomicU64; HEARTBEATS.len()] = [const { AtomicU64::new(0) }; HEARTBEATS.len()];
+
+// Kept so shutdown can disarm it
+static DEVICE: Mutex<Option<File>> = Mutex::new(None);
+
+// How often the watchdog is fed while every loop is healthy, well inside any device's timeout
+const FEED_INTERVAL: Duration = Duration::from_secs(1);
+
+pub fn beat(heartbeat: Heartbeat) {
+    LAST_BEATS[heartbeat as usize].store(metrics::uptime().as_millis() as u64, Ordering::Relaxed);
+}
+
+// Feeds the watchdog device for as long as every loop has beaten within stall_timeout. Once one
+// hasn't, feeding stops and the device reboots the Pi when its own timeout runs out
+pub fn serve(device_path: &str, stall_timeout: Duration) {
+    metrics::mark_start();
+
+    let device = match OpenOptions::new().write(true).open(device_path) {
+        Ok(device) => device,
+        Err(error) => {
+            println!("Failed to open watchdog {}: {}", device_path, error);
+
+            return;
+        }
+    };
+
+    println!("Feeding watchdog {}", device_path);
+
+    *DEVICE.lock().unwrap() = Some(device);
+
+    thread::spawn(move || loop {
+        thread::sleep(FEED_INTERVAL);
+
+        let now = metrics::uptime();
+
+        let stalled = HEARTBEATS.into_iter().find(|heartbeat| {
+            let last_beat =
+                Duration::from_millis(LAST_BEATS[*heartbeat as usize].load(Ordering::Relaxed));
+
+            now.saturating_sub(last_beat) > stall_timeout
+        });
+
+        let mut device = DEVICE.lock().unwrap();
+
+        let Some(file) = device.as_mut() else {
+            // Disarmed
+            return;
+        };
+
+        if let Some(heartbeat) = stalled {
+            println!(
+                "{:?} loop hasn't run in over {:?}, no longer feeding the watchdog",
+                heartbeat, stall_timeout
+            );
+
+            // Dropped without the magic close, so the reboot still happens
+            *device = None;
+
+            return;
+        }
+
+        let _ = file.write_all(b"\0").and_then(|_| file.flush());
+    });
+}
+
+// Writing V before closing tells the driver this is on purpose, so a clean exit doesn't reboot
+pub fn disarm() {
+    if let Some(mut device) = DEVICE.lock().unwrap().take() {
+        let _ = device.write_all(b"V").and_then(|_| device.flush());
+
+        println!("Watchdog disarmed");
+    }
+}